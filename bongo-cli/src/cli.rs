@@ -1,12 +1,25 @@
 use std::path::PathBuf;
 
+#[cfg(feature = "backend-acoustid")]
+use bongo_core::fetch::acoustid;
+#[cfg(feature = "backend-spotify")]
+use bongo_core::fetch::spotify;
+use bongo_core::fetch::MetadataBackend;
+#[cfg(feature = "backend-musicbrainz")]
+use bongo_core::fetch::{http::UreqClient, musicbrainz};
+use bongo_core::{
+    sanitize::Profile,
+    sort::{Link, OnCollision},
+};
+
 #[derive(clap::Parser, Debug)]
 pub struct Cli {
     #[arg(short, long, default_value_t = tracing::Level::INFO)]
     ///the log level for the applications {trace, debug, info, warn, error}
     pub log_level: tracing::Level,
     #[arg(short, long)]
-    ///disable browser auth for spotify
+    ///disable browser auth for spotify. Spotify uses client credentials, which never open a
+    ///browser, so this only exists for scripts that already pass it
     pub no_browser: bool,
     ///music directory [default: ./ ]
     #[arg(short, long)]
@@ -27,18 +40,137 @@ pub enum Command {
         #[arg(short, long)]
         ///create a bongo db if it doesn't exist
         auto_init: bool,
+        #[arg(short, long)]
+        ///path template, e.g. '{albumartist|artist}/{year} - {album}/{disc:02}-{track:02} {title}.{ext}'
+        ///[default: sort.format in .bongo.toml, or '{artist|"UnknownArtist"}/{album|"Singles"}/{title|filename}.{ext}']
+        format: Option<String>,
+        #[arg(short, long)]
+        ///file systems the sorted paths must be valid on [default: sort.sanitize in .bongo.toml, or posix]
+        sanitize: Option<Profile>,
+        #[arg(long)]
+        ///maximum length of a file or directory name in bytes, at least 16 [default: sort.max_length in .bongo.toml, or 255]
+        max_length: Option<usize>,
+        #[arg(long)]
+        ///what to do when songs would be sorted to the same path [default: sort.on_collision in .bongo.toml, or abort]
+        on_collision: Option<OnCollision>,
+        #[arg(long)]
+        ///carry cover art, cue sheets, rip logs and lyrics along with the songs [default: sort.sidecars in .bongo.toml]
+        sidecars: bool,
+        #[arg(long, requires = "destination_directory", conflicts_with = "auto_init")]
+        ///link files into the destination directory instead of copying them, copying where linking fails.
+        ///Can't be combined with --auto-init, whose uuids would be written into the originals
+        link: Option<Link>,
+        #[arg(long, requires = "destination_directory")]
+        ///encode a file type when copying, e.g. 'flac=opus-128'. Presets are opus-96, opus-128, mp3-v0 and mp3-320.
+        ///Needs ffmpeg [default: sort.transcode in .bongo.toml]
+        transcode: Vec<String>,
+        #[arg(long)]
+        ///remove directories left empty by moving [default: sort.prune in .bongo.toml]
+        prune: bool,
+        #[arg(long)]
+        ///print what would be copied or moved without changing anything
+        dry_run: bool,
+        #[arg(long, requires = "dry_run")]
+        ///print the dry run as json
+        json: bool,
+        #[arg(long)]
+        ///with --dry-run, save the plan to this file. Without, apply a saved plan exactly
+        plan_file: Option<PathBuf>,
+        #[arg(long, conflicts_with_all = ["dry_run", "plan_file"])]
+        ///continue a sort that was interrupted
+        resume: bool,
+        #[arg(long, conflicts_with_all = ["dry_run", "plan_file", "resume"])]
+        ///discard a sort that was interrupted, leaving the files it already sorted
+        abandon: bool,
+    },
+    ///mirror the sorted music dir into another directory, e.g. a portable player.
+    ///Only new and changed songs are copied
+    Sync {
+        ///the mirror
+        destination: PathBuf,
+        #[arg(short, long)]
+        ///path template [default: sort.format in .bongo.toml, or '{artist|"UnknownArtist"}/{album|"Singles"}/{title|filename}.{ext}']
+        format: Option<String>,
+        #[arg(short, long)]
+        ///file systems the mirrored paths must be valid on [default: sort.sanitize in .bongo.toml, or posix]
+        sanitize: Option<Profile>,
+        #[arg(long)]
+        ///maximum length of a file or directory name in bytes, at least 16 [default: sort.max_length in .bongo.toml, or 255]
+        max_length: Option<usize>,
+        #[arg(long)]
+        ///link files into the mirror instead of copying them, copying where linking fails
+        link: Option<Link>,
+        #[arg(long)]
+        ///encode a file type when copying, e.g. 'flac=opus-128'. Presets are opus-96, opus-128, mp3-v0 and mp3-320.
+        ///Needs ffmpeg [default: sort.transcode in .bongo.toml]
+        transcode: Vec<String>,
+        #[arg(long)]
+        ///remove songs that are no longer in the music dir from the mirror
+        delete: bool,
+        #[arg(long)]
+        ///print what would change without changing anything
+        dry_run: bool,
+    },
+    ///reverse a sort
+    Undo {
+        ///operation id from `bongo history` [default: the most recent sort]
+        id: Option<u64>,
+    },
+    ///list recorded sorts
+    History,
+    ///check the bongo db against the files on disk.
+    ///Exits with 0 if nothing is wrong, 2 if problems were found and 1 if the check itself failed
+    Verify {
+        ///also decode every file in full, slow
+        #[arg(short, long)]
+        decode: bool,
+        ///print the report as json
+        #[arg(long)]
+        json: bool,
     },
     ///fetch metadata for files
     Fetch {
-        // #[arg(short, long)]
+        #[arg(short, long)]
         ///metadata source
-        backend: bool,
+        backend: Backend,
+        #[arg(long)]
+        ///override the backend's api url, e.g. for a local mirror
+        api_url: Option<String>,
+        #[arg(short, long)]
+        ///write the best match without asking
+        yes: bool,
+        #[arg(short, long, default_value_t = 0.0, requires = "yes")]
+        ///with --yes, skip songs whose best match is less confident than this (0 to 1)
+        min_confidence: f32,
+        #[arg(short, long)]
+        ///override the editor used to edit a match
+        editor: Option<String>,
+        #[arg(long)]
+        ///reuse backend responses cached in the bongo db for this many days
+        ///[default: cache.days or cache.backends in .bongo.toml, or 30]
+        cache_days: Option<u64>,
+        #[arg(long)]
+        ///always query the backend and don't cache its responses
+        no_cache: bool,
+    },
+    ///manage the cached metadata backend responses
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
     },
     ///update metadata for files
     Update {
         // #[arg(short, long)]
         // regen_uuid: bool,
     },
+    ///move uuids written by older versions of bongo out of the catalog number tag
+    MigrateUuids,
+    ///compute acoustic fingerprints for files and store them in the bongo db
+    Fingerprint {
+        ///recompute fingerprints that are already stored
+        #[arg(short, long)]
+        force: bool,
+    },
     ///create a bongo.db in the music dir
     Init {
         ///create even if a bongo.db already exists
@@ -55,10 +187,11 @@ pub enum Command {
         // ///music dir [default:current-dir]
         // sub_directory: Option<PathBuf>,
     },
-    ///edit the metadata of a song
+    ///edit the metadata of songs
     Edit {
-        ///path to the song to edit
-        song: PathBuf,
+        ///paths to the songs or directories to edit
+        #[arg(required = true)]
+        songs: Vec<PathBuf>,
         ///override the editor
         #[arg(short, long)]
         editor: Option<String>,
@@ -69,9 +202,59 @@ pub enum Command {
     },
 }
 
-// #[derive(Debug, Clone)]
-// #[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
-// pub enum Backend {
-//     #[cfg(feature = "backend-spotify")]
-//     Spotify,
-// }
+#[derive(clap::Subcommand, Debug, Clone)]
+pub enum CacheCommand {
+    ///remove cached responses
+    Clear {
+        ///only remove responses older than this many days
+        #[arg(short, long)]
+        older_than: Option<u64>,
+    },
+    ///show the number and age of cached responses
+    Stats {
+        ///responses older than this many days count as expired
+        ///[default: cache.days or cache.backends in .bongo.toml, or 30]
+        #[arg(long)]
+        cache_days: Option<u64>,
+    },
+}
+
+#[derive(clap::ValueEnum, Debug, Clone)]
+pub enum Backend {
+    #[cfg(feature = "backend-spotify")]
+    Spotify,
+    #[cfg(feature = "backend-musicbrainz")]
+    Musicbrainz,
+    #[cfg(feature = "backend-acoustid")]
+    Acoustid,
+}
+impl Backend {
+    pub fn open(&self, api_url: Option<String>) -> anyhow::Result<Box<dyn MetadataBackend>> {
+        Ok(match self {
+            #[cfg(feature = "backend-spotify")]
+            Self::Spotify => {
+                let spotify = spotify::Spotify::from_env()?;
+                match api_url {
+                    Some(url) => Box::new(spotify.with_urls(url, spotify::AUTH_URL.to_owned())),
+                    None => Box::new(spotify),
+                }
+            }
+            #[cfg(feature = "backend-musicbrainz")]
+            Self::Musicbrainz => {
+                let musicbrainz = musicbrainz::MusicBrainz::new(UreqClient::new());
+                Box::new(match api_url {
+                    Some(url) => musicbrainz.with_url(url),
+                    None => musicbrainz,
+                })
+            }
+            #[cfg(feature = "backend-acoustid")]
+            Self::Acoustid => {
+                let acoustid = acoustid::AcoustId::from_env()?;
+                Box::new(match api_url {
+                    Some(url) => acoustid.with_url(url),
+                    None => acoustid,
+                })
+            }
+        })
+    }
+}
@@ -0,0 +1,47 @@
+use std::path::Path;
+
+use anyhow::Result;
+
+///optional settings stored next to the bongo db
+pub const CONFIGNAME: &str = ".bongo.toml";
+
+#[derive(serde::Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub sort: SortConfig,
+    ///which files are songs
+    pub scan: bongo_core::scan::Scan,
+    ///how long fetched responses are reused
+    pub cache: bongo_core::cache::Ttl,
+}
+
+#[derive(serde::Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SortConfig {
+    ///sort path template, see `bongo_core::template::Template`
+    pub format: Option<String>,
+    ///file systems the sorted paths must be valid on
+    pub sanitize: Option<bongo_core::sanitize::Profile>,
+    ///maximum length of a file or directory name in bytes, at least 16
+    pub max_length: Option<usize>,
+    ///what to do when songs would be sorted to the same path
+    pub on_collision: Option<bongo_core::sort::OnCollision>,
+    ///carry cover art, cue sheets, rip logs and lyrics along with the songs
+    pub sidecars: bool,
+    ///remove directories left empty by moving
+    pub prune: bool,
+    ///source extensions and the preset they are encoded with when copying
+    pub transcode: bongo_core::transcode::Transcoding,
+}
+
+impl Config {
+    ///read the config in `root`, or the defaults if there is none
+    pub fn load(root: &Path) -> Result<Self> {
+        let path = root.join(CONFIGNAME);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        toml::from_str(&std::fs::read_to_string(&path)?)
+            .map_err(|e| anyhow::anyhow!("invalid config '{}'. {e}", path.to_string_lossy()))
+    }
+}
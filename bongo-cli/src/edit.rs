@@ -0,0 +1,396 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::Result;
+use bongo_core::{
+    db::Database,
+    fetch::Metadata,
+    scan::Scan,
+    song::{MusicDir, Song},
+};
+
+use crate::config::Config;
+
+const ERROR_PREFIX: &str = "# error: ";
+
+///name of the table whose values apply to every song in a batch edit
+const COMMON: &str = "common";
+
+type TagMap = HashMap<String, String>;
+
+///edit the tags of `paths` as toml in an external editor.
+///directories are searched for music files
+pub fn edit(paths: &[PathBuf], editor: Option<String>) -> Result<()> {
+    let editor = find_editor(editor)?;
+    let mut songs = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            songs.extend(MusicDir::find_songs(path, &scan(path)?)?);
+        } else {
+            songs.push(Song::parse(path.clone())?);
+        }
+    }
+    if songs.is_empty() {
+        anyhow::bail!("no songs to edit");
+    }
+    let originals = table_names(&songs)
+        .into_iter()
+        .zip(&songs)
+        .map(|(name, s)| Ok((name, s.to_map()?)))
+        .collect::<Result<Vec<_>>>()?;
+    let mut text = render(&originals)?;
+    let (_dir, tmp) = edit_file("bongo-edit")?;
+    loop {
+        let edited = strip_errors(&open_editor(&editor, &tmp, &text)?);
+        let maps = match parse(&edited, &originals) {
+            Ok(maps) => maps,
+            Err(e) => {
+                text = with_error(&e, &edited);
+                continue;
+            }
+        };
+        match apply(&mut songs, &maps) {
+            Ok(()) => break Ok(()),
+            Err(e) => text = with_error(&e, &edited),
+        }
+    }
+}
+
+///the scan settings of the music dir containing `dir`, the defaults outside of one
+fn scan(dir: &Path) -> Result<Scan> {
+    match Database::find_root(dir) {
+        Ok(root) => Ok(Config::load(&root)?.scan),
+        Err(_) => Ok(Scan::default()),
+    }
+}
+
+///name each song's table by its path relative to its music dir, or as given outside of one.
+///Songs from different music dirs can share a relative path, in which case every table uses the full path
+fn table_names(songs: &[Song]) -> Vec<String> {
+    let names = songs
+        .iter()
+        .map(|s| relative_name(&s.path))
+        .collect::<Vec<_>>();
+    if names.iter().collect::<HashSet<_>>().len() == names.len() {
+        names
+    } else {
+        songs
+            .iter()
+            .map(|s| s.path.to_string_lossy().into_owned())
+            .collect()
+    }
+}
+
+fn relative_name(path: &Path) -> String {
+    let relative = path
+        .parent()
+        .and_then(|dir| Database::find_root(dir).ok())
+        .and_then(|root| path.strip_prefix(root).ok().map(Path::to_path_buf));
+    relative
+        .as_deref()
+        .unwrap_or(path)
+        .to_string_lossy()
+        .into_owned()
+}
+
+///write `maps` to the songs at the same index.
+///Every map is checked first so a bad key doesn't leave the batch half written
+fn apply(songs: &mut [Song], maps: &[TagMap]) -> Result<()> {
+    for (song, map) in songs.iter().zip(maps) {
+        song.check_map(map)?;
+    }
+    for (song, map) in songs.iter_mut().zip(maps) {
+        let changed = song.apply_map(map)?;
+        if changed.is_empty() {
+            tracing::debug!("no changes to '{}'", song.path.to_string_lossy());
+        } else {
+            tracing::info!(
+                "updated {} in '{}'",
+                changed.join(", "),
+                song.path.to_string_lossy()
+            );
+        }
+    }
+    Ok(())
+}
+
+///a single song is rendered as a flat table.
+///multiple songs get one table each, with the values they share moved into `[common]`
+fn render(songs: &[(String, TagMap)]) -> Result<String> {
+    if let [(_, map)] = songs {
+        return Ok(toml::to_string_pretty(
+            &map.iter().collect::<BTreeMap<_, _>>(),
+        )?);
+    }
+    let common = common_items(songs);
+    let mut doc = toml::Table::new();
+    doc.insert(COMMON.to_owned(), to_table(common.iter()));
+    for (name, map) in songs {
+        let own = map.iter().filter(|(k, _)| !common.contains_key(*k));
+        doc.insert(name.clone(), to_table(own));
+    }
+    Ok(toml::to_string_pretty(&doc)?)
+}
+
+///items with the same value in every song
+fn common_items(songs: &[(String, TagMap)]) -> BTreeMap<String, String> {
+    let Some(((_, first), rest)) = songs.split_first() else {
+        return BTreeMap::new();
+    };
+    first
+        .iter()
+        .filter(|(k, v)| rest.iter().all(|(_, map)| map.get(*k) == Some(v)))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect()
+}
+
+fn to_table<'a>(items: impl Iterator<Item = (&'a String, &'a String)>) -> toml::Value {
+    let items = items.collect::<BTreeMap<_, _>>();
+    toml::Value::Table(
+        items
+            .into_iter()
+            .map(|(k, v)| (k.clone(), toml::Value::String(v.clone())))
+            .collect(),
+    )
+}
+
+///inverse of [`render`]. Returns the new tags in the same order as `songs`
+fn parse(text: &str, songs: &[(String, TagMap)]) -> Result<Vec<TagMap>> {
+    if songs.len() == 1 {
+        return Ok(vec![parse_map(toml::from_str(text)?)?]);
+    }
+    let mut doc = toml::from_str::<toml::Table>(text)?;
+    let common = match doc.remove(COMMON) {
+        Some(toml::Value::Table(common)) => parse_map(common)?,
+        Some(_) => anyhow::bail!("'{COMMON}' must be a table"),
+        None => TagMap::new(),
+    };
+    let mut maps = Vec::with_capacity(songs.len());
+    for (name, _) in songs {
+        let own = match doc.remove(name) {
+            Some(toml::Value::Table(own)) => parse_map(own)?,
+            Some(_) => anyhow::bail!("'{name}' must be a table"),
+            None => anyhow::bail!("missing table for '{name}'"),
+        };
+        let mut map = common.clone();
+        map.extend(own);
+        maps.push(map);
+    }
+    if let Some(unknown) = doc.keys().next() {
+        anyhow::bail!("'{unknown}' is not one of the edited songs");
+    }
+    Ok(maps)
+}
+
+///edit fetched metadata before it is written
+pub fn edit_metadata(metadata: &Metadata, editor: Option<String>) -> Result<Metadata> {
+    let editor = find_editor(editor)?;
+    let mut text = toml::to_string_pretty(metadata)?;
+    let (_dir, tmp) = edit_file("bongo-fetch")?;
+    loop {
+        let edited = strip_errors(&open_editor(&editor, &tmp, &text)?);
+        match toml::from_str(&edited) {
+            Ok(metadata) => break Ok(metadata),
+            Err(e) => text = with_error(&e.into(), &edited),
+        }
+    }
+}
+
+///a path for the edited toml inside a new directory only the current user can access,
+///so other users can't swap the file for a symlink. The directory is removed when dropped
+fn edit_file(name: &str) -> Result<(tempfile::TempDir, PathBuf)> {
+    let dir = tempfile::Builder::new()
+        .prefix(&format!("{name}-"))
+        .tempdir()?;
+    let file = dir.path().join(format!("{name}.toml"));
+    Ok((dir, file))
+}
+
+///`--editor`, then `$VISUAL`, then `$EDITOR`
+fn find_editor(editor: Option<String>) -> Result<String> {
+    editor
+        .or_else(|| std::env::var("VISUAL").ok())
+        .or_else(|| std::env::var("EDITOR").ok())
+        .filter(|e| !e.trim().is_empty())
+        .ok_or_else(|| anyhow::anyhow!("no editor found. Set $EDITOR or pass --editor"))
+}
+
+///write `text` to `file`, wait for the editor to exit and read the result
+fn open_editor(editor: &str, file: &Path, text: &str) -> Result<String> {
+    std::fs::write(file, text)?;
+    //run through the shell like git does, so editors with arguments such as `code --wait`
+    //or quoted paths with spaces work
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{editor} \"$@\""))
+        .arg(editor)
+        .arg(file)
+        .status()?;
+    if !status.success() {
+        anyhow::bail!("editor '{editor}' exited with {status}");
+    }
+    Ok(std::fs::read_to_string(file)?)
+}
+
+///convert a toml table into a tag map.  Numbers and booleans are accepted as text
+fn parse_map(table: toml::Table) -> Result<TagMap> {
+    table
+        .into_iter()
+        .map(|(key, value)| {
+            let value = value_to_string(&key, value)?;
+            Ok((key, value))
+        })
+        .collect()
+}
+
+fn value_to_string(key: &str, value: toml::Value) -> Result<String> {
+    Ok(match value {
+        toml::Value::String(s) => s,
+        toml::Value::Integer(i) => i.to_string(),
+        toml::Value::Float(f) => f.to_string(),
+        toml::Value::Boolean(b) => b.to_string(),
+        toml::Value::Datetime(d) => d.to_string(),
+        toml::Value::Array(_) | toml::Value::Table(_) => {
+            anyhow::bail!("'{key}' must be a single value")
+        }
+    })
+}
+
+///prepend `error` to `text` as toml comments
+fn with_error(error: &anyhow::Error, text: &str) -> String {
+    let mut commented = String::new();
+    for line in error.to_string().lines() {
+        commented.push_str(ERROR_PREFIX);
+        commented.push_str(line);
+        commented.push('\n');
+    }
+    commented.push_str(text);
+    commented
+}
+
+///remove the comments added by [`with_error`]
+fn strip_errors(text: &str) -> String {
+    text.lines()
+        .skip_while(|l| l.starts_with(ERROR_PREFIX))
+        .map(|l| format!("{l}\n"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{common_items, parse, render, strip_errors, with_error, TagMap};
+
+    fn map(items: &[(&str, &str)]) -> TagMap {
+        items
+            .iter()
+            .map(|(k, v)| ((*k).to_owned(), (*v).to_owned()))
+            .collect()
+    }
+
+    fn album() -> Vec<(String, TagMap)> {
+        vec![
+            (
+                "a/1.mp3".to_owned(),
+                map(&[("Album", "A"), ("Artist", "X"), ("Title", "One")]),
+            ),
+            (
+                "a/2.mp3".to_owned(),
+                map(&[("Album", "A"), ("Artist", "Y"), ("Title", "Two")]),
+            ),
+        ]
+    }
+
+    #[test]
+    fn common_items_are_shared_by_every_song() {
+        let common = common_items(&album());
+        assert_eq!(common.len(), 1);
+        assert_eq!(common["Album"], "A");
+        assert!(common_items(&[]).is_empty());
+    }
+
+    #[test]
+    fn single_song_is_a_flat_table() {
+        let songs = vec![(
+            "1.mp3".to_owned(),
+            map(&[("Title", "One"), ("Year", "2001")]),
+        )];
+        let text = render(&songs).unwrap();
+        assert_eq!(text, "Title = \"One\"\nYear = \"2001\"\n");
+        assert_eq!(parse(&text, &songs).unwrap(), vec![songs[0].1.clone()]);
+    }
+
+    #[test]
+    fn batch_round_trips() {
+        let songs = album();
+        let text = render(&songs).unwrap();
+        let doc = text.parse::<toml::Table>().unwrap();
+        assert_eq!(doc["common"]["Album"].as_str(), Some("A"));
+        assert!(doc["a/1.mp3"].get("Album").is_none());
+        let maps = parse(&text, &songs).unwrap();
+        assert_eq!(maps, songs.into_iter().map(|(_, m)| m).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn common_values_apply_unless_overridden() {
+        let text = r#"
+            [common]
+            Album = "B"
+            Genre = "Rock"
+            ["a/1.mp3"]
+            Title = "One"
+            Genre = "Jazz"
+            ["a/2.mp3"]
+            Title = "Two"
+            Year = 1999
+        "#;
+        let maps = parse(text, &album()).unwrap();
+        assert_eq!(
+            maps[0],
+            map(&[("Album", "B"), ("Genre", "Jazz"), ("Title", "One")])
+        );
+        assert_eq!(
+            maps[1],
+            map(&[
+                ("Album", "B"),
+                ("Genre", "Rock"),
+                ("Title", "Two"),
+                ("Year", "1999")
+            ])
+        );
+    }
+
+    #[test]
+    fn removing_a_common_value_removes_it_everywhere() {
+        let text = r#"
+            [common]
+            ["a/1.mp3"]
+            Title = "One"
+            ["a/2.mp3"]
+            Title = "Two"
+        "#;
+        let maps = parse(text, &album()).unwrap();
+        assert!(maps.iter().all(|m| !m.contains_key("Album")));
+    }
+
+    #[test]
+    fn invalid_batches_are_rejected() {
+        let songs = album();
+        let missing = "[common]\n[\"a/1.mp3\"]\n";
+        assert!(parse(missing, &songs).is_err());
+        let unknown = "[\"a/1.mp3\"]\n[\"a/2.mp3\"]\n[\"a/3.mp3\"]\n";
+        assert!(parse(unknown, &songs).is_err());
+        let nested = "[\"a/1.mp3\"]\nTitle = [\"x\"]\n[\"a/2.mp3\"]\n";
+        assert!(parse(nested, &songs).is_err());
+    }
+
+    #[test]
+    fn errors_are_stripped_before_parsing() {
+        let text = "Title = \"One\"\n";
+        let annotated = with_error(&anyhow::anyhow!("first\nsecond"), text);
+        assert!(annotated.starts_with("# error: first\n# error: second\n"));
+        assert_eq!(strip_errors(&annotated), text);
+    }
+}
@@ -7,13 +7,31 @@
 )]
 #![allow(clippy::module_name_repetitions)]
 
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    path::Path,
+    time::{Duration, SystemTime},
+};
 
 use anyhow::Result;
-use bongo_core::{song, db::SONGTABLE, rexports::redb::ReadableTable};
+use bongo_core::{
+    cache::Ttl,
+    db::SONGTABLE,
+    fetch::Review,
+    rexports::redb::ReadableTable,
+    sanitize::{Profile, Sanitizer, MIN_LEN},
+    song,
+    sort::{Mode, Plan, SortOptions},
+    template::Template,
+    transcode::Transcoding,
+    verify::Report,
+};
 use clap::Parser;
 
 mod cli;
+mod config;
+mod edit;
+mod review;
 fn setup_logger(level: tracing::Level) -> Result<(), tracing::subscriber::SetGlobalDefaultError> {
     tracing::subscriber::set_global_default(
         tracing_subscriber::FmtSubscriber::builder()
@@ -23,9 +41,76 @@ fn setup_logger(level: tracing::Level) -> Result<(), tracing::subscriber::SetGlo
             .finish(),
     )
 }
+const fn days(days: u64) -> Duration {
+    Duration::from_secs(days * 24 * 60 * 60)
+}
+fn age_in_days(timestamp: u64) -> u64 {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    now.saturating_sub(timestamp) / days(1).as_secs()
+}
+///the sort template and sanitizer from the command line, falling back to the config
+fn layout(
+    config: &config::SortConfig,
+    format: Option<String>,
+    sanitize: Option<Profile>,
+    max_length: Option<usize>,
+) -> Result<(Template, Sanitizer)> {
+    let template = match format.or_else(|| config.format.clone()) {
+        Some(format) => format.parse()?,
+        None => Template::default(),
+    };
+    let mut sanitizer = Sanitizer::default();
+    if let Some(profile) = sanitize.or(config.sanitize) {
+        sanitizer.profile = profile;
+    }
+    if let Some(max_length) = max_length.or(config.max_length) {
+        anyhow::ensure!(
+            max_length >= MIN_LEN,
+            "max length must be at least {MIN_LEN} bytes, got {max_length}"
+        );
+        sanitizer.max_len = max_length;
+    }
+    Ok((template, sanitizer))
+}
+///the transcode rules in the config, overridden by the ones on the command line
+fn transcoding(config: &config::SortConfig, rules: &[String]) -> Result<Transcoding> {
+    let mut transcoding = config.transcode.clone();
+    for rule in rules {
+        transcoding.add_rule(rule)?;
+    }
+    Ok(transcoding)
+}
+///`--cache-days` for every backend, or the ttls in the config of the music dir containing `dir`
+fn cache_ttl(dir: &Path, days: Option<u64>) -> Result<Ttl> {
+    if let Some(days) = days {
+        return Ok(Ttl::days(days));
+    }
+    let root = bongo_core::db::Database::find_root(dir)?;
+    Ok(config::Config::load(&root)?.cache)
+}
+///the exit code of `bongo verify`, 2 if it found problems.
+///A check that fails exits with 1, like every other command
+fn verify_exit_code(report: &Report) -> i32 {
+    if report.is_clean() {
+        0
+    } else {
+        2
+    }
+}
+///open the music dir containing `dir`, finding its songs as set in its config
+fn open(dir: &Path) -> Result<song::MusicDir> {
+    let root = bongo_core::db::Database::find_root(dir)?;
+    song::MusicDir::open(dir, config::Config::load(&root)?.scan)
+}
 fn main() -> anyhow::Result<()> {
     let args = cli::Cli::parse();
     setup_logger(args.log_level)?;
+    if args.no_browser {
+        tracing::debug!("--no-browser has no effect, spotify authenticates without a browser");
+    }
     let music_dir = if let Some(dir) = args.directory {
         dir
     } else {
@@ -36,56 +121,250 @@ fn main() -> anyhow::Result<()> {
         cli::Command::Sort {
             destination_directory,
             ignore_db,
-            auto_init
+            auto_init,
+            format,
+            sanitize,
+            max_length,
+            on_collision,
+            sidecars,
+            link,
+            transcode,
+            prune,
+            dry_run,
+            json,
+            plan_file,
+            resume,
+            abandon,
         } => {
             if ignore_db && auto_init {
                 anyhow::bail!("unable to both ignore and create a db");
             }
-            song::MusicDir::open(&music_dir)?.sort(destination_directory, ignore_db, auto_init)?;
-        },
-        cli::Command::Fetch { backend: _ } => todo!(),
-        cli::Command::Update{/* regen_uuid*/} => {
-            let mut music_dir= song::MusicDir::open(&music_dir)?;
-            music_dir.update(true)?;
-        
-        },
-        cli::Command::List { /*sub_directory: _*/ } => song::MusicDir::open(&music_dir)?.list(),
+            let mut music_dir = open(&music_dir)?;
+            if resume {
+                music_dir.resume_sort(ignore_db, auto_init)?;
+                return Ok(());
+            }
+            if abandon {
+                music_dir.abandon_sort(ignore_db)?;
+                return Ok(());
+            }
+            if let (Some(plan_file), false) = (&plan_file, dry_run) {
+                let plan: Plan = serde_json::from_str(&std::fs::read_to_string(plan_file)?)?;
+                music_dir.apply_plan(&plan, ignore_db, auto_init)?;
+                return Ok(());
+            }
+            let config = config::Config::load(music_dir.root())?;
+            let (template, sanitizer) = layout(&config.sort, format, sanitize, max_length)?;
+            let options = SortOptions {
+                template,
+                sanitizer,
+                on_collision: on_collision
+                    .or(config.sort.on_collision)
+                    .unwrap_or_default(),
+                sidecars: sidecars || config.sort.sidecars,
+                prune: prune || config.sort.prune,
+                link,
+                transcode: transcoding(&config.sort, &transcode)?,
+            };
+            if dry_run {
+                let plan = music_dir.plan_sort(destination_directory, &options)?;
+                if let Some(plan_file) = plan_file {
+                    std::fs::write(plan_file, serde_json::to_string_pretty(&plan)?)?;
+                }
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&plan)?);
+                } else {
+                    println!("{plan}");
+                }
+            } else {
+                music_dir.sort(destination_directory, ignore_db, auto_init, &options)?;
+            }
+        }
+        cli::Command::Sync {
+            destination,
+            format,
+            sanitize,
+            max_length,
+            link,
+            transcode,
+            delete,
+            dry_run,
+        } => {
+            let music_dir = open(&music_dir)?;
+            let config = config::Config::load(music_dir.root())?;
+            let (template, sanitizer) = layout(&config.sort, format, sanitize, max_length)?;
+            let options = SortOptions {
+                template,
+                sanitizer,
+                link,
+                transcode: transcoding(&config.sort, &transcode)?,
+                ..SortOptions::default()
+            };
+            let plan = music_dir.plan_sync(&destination, &options, delete)?;
+            if dry_run {
+                println!("{plan}");
+            } else {
+                music_dir.apply_sync(&plan, &options)?;
+            }
+        }
+        cli::Command::Undo { id } => {
+            let id = open(&music_dir)?.undo(id)?;
+            println!("undid operation {id}");
+        }
+        cli::Command::History => {
+            let db = bongo_core::db::Database::open(&music_dir)?;
+            for (id, entry) in db.history()? {
+                let action = match entry.mode {
+                    Mode::Copy => format!(
+                        "copied {} files to '{}'",
+                        entry.moves.len(),
+                        entry.destination.to_string_lossy()
+                    ),
+                    Mode::Move => format!("moved {} files", entry.moves.len()),
+                };
+                let undone = if entry.undone { " (undone)" } else { "" };
+                println!(
+                    "{id}\t{} days ago\t{action}{undone}",
+                    age_in_days(entry.timestamp)
+                );
+            }
+        }
+        cli::Command::Verify { decode, json } => {
+            let config = config::Config::load(&bongo_core::db::Database::find_root(&music_dir)?)?;
+            let report = song::MusicDir::verify(&music_dir, &config.scan, decode)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                println!("{report}");
+            }
+            let code = verify_exit_code(&report);
+            if code != 0 {
+                std::process::exit(code);
+            }
+        }
+        cli::Command::Fetch {
+            backend,
+            api_url,
+            yes,
+            min_confidence,
+            editor,
+            cache_days,
+            no_cache,
+        } => {
+            let backend = backend.open(api_url)?;
+            let ttl = if no_cache {
+                None
+            } else {
+                Some(cache_ttl(&music_dir, cache_days)?.of(backend.name()))
+            };
+            let mut music_dir = open(&music_dir)?;
+            if yes {
+                music_dir.fetch(backend.as_ref(), ttl, |_, _, candidates| {
+                    Ok(Review::best(candidates, min_confidence))
+                })?;
+            } else {
+                let reviewer = review::Reviewer::new(editor);
+                music_dir.fetch(backend.as_ref(), ttl, |song, current, candidates| {
+                    reviewer.review(song, current, candidates)
+                })?;
+            }
+        }
+        cli::Command::Cache { command } => {
+            let db = bongo_core::db::Database::open(&music_dir)?;
+            match command {
+                cli::CacheCommand::Clear { older_than } => {
+                    let removed = db.clear_cache(older_than.map(days))?;
+                    println!("removed {removed} cached responses");
+                }
+                cli::CacheCommand::Stats { cache_days } => {
+                    let stats = db.cache_stats(&cache_ttl(&music_dir, cache_days)?)?;
+                    println!("cached queries: {}", stats.queries);
+                    println!("cached songs: {}", stats.songs);
+                    println!("expired: {}", stats.expired);
+                    if let (Some(oldest), Some(newest)) = (stats.oldest, stats.newest) {
+                        println!("oldest: {} days ago", age_in_days(oldest));
+                        println!("newest: {} days ago", age_in_days(newest));
+                    }
+                }
+            }
+        }
+        cli::Command::Update {} => {
+            let mut music_dir = open(&music_dir)?;
+            for reissued in music_dir.update(true)? {
+                println!(
+                    "gave '{}' the new uuid {}, it shared {} with '{}'",
+                    reissued.path.to_string_lossy(),
+                    reissued.new_uuid,
+                    reissued.old_uuid,
+                    reissued.original.to_string_lossy()
+                );
+            }
+        }
+        cli::Command::MigrateUuids => {
+            let migrated = open(&music_dir)?.migrate_uuids()?;
+            println!("migrated {migrated} songs");
+        }
+        cli::Command::Fingerprint { force } => open(&music_dir)?.fingerprint_all(force)?,
+        cli::Command::List {} => open(&music_dir)?.list(),
         cli::Command::Init { force_reinit } => {
-            song::MusicDir::init(music_dir, force_reinit)?;
+            let scan = config::Config::load(&music_dir)?.scan;
+            song::MusicDir::init(music_dir, force_reinit, scan)?;
         }
-        cli::Command::Show { songs }=> {
+        cli::Command::Show { songs } => {
             let mut show_map = HashMap::with_capacity(songs.len());
-            for path in songs 
-            {
-                match song::Song::parse(path.clone()).map(|s| s.to_map()){
-                    Ok(Ok(map)) => {show_map.insert(path.to_string_lossy().into_owned(), map);},
+            for path in songs {
+                match song::Song::parse(path.clone()).map(|s| s.to_map()) {
+                    Ok(Ok(map)) => {
+                        show_map.insert(path.to_string_lossy().into_owned(), map);
+                    }
                     Ok(Err(e)) => tracing::error!("{e}"),
-                    Err(e) => tracing::error!("{e}"),                   
+                    Err(e) => tracing::error!("{e}"),
                 }
-                           
             }
             print!("{}", toml::to_string_pretty(&show_map)?);
-        },
+        }
         cli::Command::DumpDb => {
             let db = bongo_core::db::Database::open(&music_dir)?;
             let reader = db.0.begin_read()?;
             {
                 let song_tbl = reader.open_table(SONGTABLE)?;
-                for entry in song_tbl.iter()?{
+                for entry in song_tbl.iter()? {
                     let entry = entry?;
                     let (u, e) = entry;
                     (u.value(), e.value());
-
                 }
-                let x = song_tbl.iter()?
-                    .map(
-                        |x| 
-                            x.map_err(Into::into).map(|(u, e)| (u.value().0, e.value()))).collect::<Result<HashMap<_,_>>>()?;
+                let x = song_tbl
+                    .iter()?
+                    .map(|x| x.map_err(Into::into).map(|(u, e)| (u.value().0, e.value())))
+                    .collect::<Result<HashMap<_, _>>>()?;
                 println!("{}", toml::to_string_pretty(&x)?);
-
             }
         }
-        cli::Command::Edit { .. } => todo!(),
+        cli::Command::Edit { songs, editor } => edit::edit(&songs, editor)?,
     };
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use bongo_core::verify::{Problem, Report};
+
+    use super::verify_exit_code;
+
+    #[test]
+    fn verify_exits_with_2_on_problems() {
+        let mut report = Report {
+            checked: 1,
+            unhashed: 0,
+            problems: Vec::new(),
+        };
+        assert_eq!(verify_exit_code(&report), 0);
+        report.problems.push(Problem::Unreadable {
+            path: PathBuf::from("song.mp3"),
+            error: "unreadable".to_owned(),
+        });
+        assert_eq!(verify_exit_code(&report), 2);
+    }
+}
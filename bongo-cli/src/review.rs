@@ -0,0 +1,93 @@
+use std::io::{BufRead, IsTerminal, Write};
+
+use anyhow::Result;
+use bongo_core::{
+    fetch::{Candidate, Metadata, Review},
+    song::Song,
+};
+
+use crate::edit;
+
+///asks the user which fetched candidate to write
+pub struct Reviewer {
+    editor: Option<String>,
+    color: bool,
+}
+
+impl Reviewer {
+    pub fn new(editor: Option<String>) -> Self {
+        Self {
+            editor,
+            color: std::io::stdout().is_terminal(),
+        }
+    }
+    pub fn review(
+        &self,
+        song: &Song,
+        current: &Metadata,
+        candidates: &[Candidate],
+    ) -> Result<Review> {
+        let mut shown = 0;
+        loop {
+            self.print(song, current, candidates, shown);
+            print!(
+                "[a]ccept, [n]ext, [p]revious, [1-{}] pick, [e]dit, [s]kip, [q]uit: ",
+                candidates.len()
+            );
+            std::io::stdout().flush()?;
+            let mut line = String::new();
+            if std::io::stdin().lock().read_line(&mut line)? == 0 {
+                return Ok(Review::Quit);
+            }
+            match line.trim() {
+                "a" | "" => return Ok(Review::Accept(shown)),
+                "n" => shown = (shown + 1) % candidates.len(),
+                "p" => shown = (shown + candidates.len() - 1) % candidates.len(),
+                "e" => {
+                    let metadata = &candidates[shown].metadata;
+                    return Ok(Review::Edit(edit::edit_metadata(
+                        metadata,
+                        self.editor.clone(),
+                    )?));
+                }
+                "s" => return Ok(Review::Skip),
+                "q" => return Ok(Review::Quit),
+                choice => match choice.parse::<usize>() {
+                    Ok(n) if (1..=candidates.len()).contains(&n) => shown = n - 1,
+                    _ => println!("unknown choice '{choice}'"),
+                },
+            }
+        }
+    }
+    ///print the current tags next to candidate `shown`, marking the fields that would change
+    fn print(&self, song: &Song, current: &Metadata, candidates: &[Candidate], shown: usize) {
+        let candidate = &candidates[shown];
+        println!(
+            "\n'{}' candidate {}/{} ({:.0}% confidence)",
+            song.path.to_string_lossy(),
+            shown + 1,
+            candidates.len(),
+            candidate.confidence * 100.0
+        );
+        let current = current.fields();
+        let width = current
+            .iter()
+            .map(|(_, v)| v.as_deref().unwrap_or_default().chars().count())
+            .max()
+            .unwrap_or_default()
+            .max("current".len());
+        println!("  {:<12} {:<width$} | candidate", "", "current");
+        for ((name, old), (_, new)) in current.into_iter().zip(candidate.metadata.fields()) {
+            let old = old.unwrap_or_default();
+            //missing fields are left untouched when writing
+            let changed = new.as_ref().is_some_and(|new| *new != old);
+            let new = new.unwrap_or_else(|| old.clone());
+            let (marker, new) = match (changed, self.color) {
+                (true, true) => ("*", format!("\x1b[1;33m{new}\x1b[0m")),
+                (true, false) => ("*", new),
+                (false, _) => (" ", new),
+            };
+            println!("{marker} {name:<12} {old:<width$} | {new}");
+        }
+    }
+}
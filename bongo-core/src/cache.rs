@@ -0,0 +1,359 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime},
+};
+
+use redb::ReadableTable;
+
+use crate::{
+    db::{Database, SongUuid, QUERYCACHETABLE, SONGCACHETABLE},
+    fetch::{Candidate, MetadataBackend, Query},
+};
+
+///candidates returned by a backend, stored in the db
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct CachedResponse {
+    pub backend: String,
+    ///seconds since the unix epoch
+    pub fetched_at: u64,
+    pub candidates: Vec<Candidate>,
+}
+
+impl CachedResponse {
+    fn is_fresh(&self, backend: &str, ttl: Duration) -> bool {
+        self.backend == backend && !self.is_older_than(ttl)
+    }
+    fn is_older_than(&self, ttl: Duration) -> bool {
+        now().saturating_sub(self.fetched_at) >= ttl.as_secs()
+    }
+}
+
+///how many days cached responses stay fresh, optionally per backend
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Ttl {
+    pub days: u64,
+    ///days by backend name, e.g. `musicbrainz = 90`
+    pub backends: HashMap<String, u64>,
+}
+
+impl Default for Ttl {
+    fn default() -> Self {
+        Self::days(30)
+    }
+}
+
+impl Ttl {
+    ///the same ttl for every backend
+    #[must_use]
+    pub fn days(days: u64) -> Self {
+        Self {
+            days,
+            backends: HashMap::new(),
+        }
+    }
+    ///the ttl of the backend named `backend`
+    #[must_use]
+    pub fn of(&self, backend: &str) -> Duration {
+        let days = self.backends.get(backend).copied().unwrap_or(self.days);
+        Duration::from_secs(days.saturating_mul(24 * 60 * 60))
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CacheStats {
+    pub queries: usize,
+    pub songs: usize,
+    ///entries older than the ttl
+    pub expired: usize,
+    ///seconds since the unix epoch
+    pub oldest: Option<u64>,
+    pub newest: Option<u64>,
+}
+
+///seconds since the unix epoch
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+///key for [`QUERYCACHETABLE`], the backend name followed by the query
+fn query_key(backend: &str, query: &Query) -> Vec<u8> {
+    postcard::to_allocvec(&(backend, query)).expect("queries are always serializable")
+}
+
+impl Database {
+    ///search `backend`, answering from the cache when a fresh response for `song` or `query` is stored
+    pub fn cached_search(
+        &self,
+        backend: &dyn MetadataBackend,
+        query: &Query,
+        song: Option<&SongUuid>,
+        ttl: Duration,
+    ) -> anyhow::Result<Vec<Candidate>> {
+        let name = backend.name();
+        let key = query_key(name, query);
+        {
+            let reader = self.0.begin_read()?;
+            let cached = match (song, reader.open_table(SONGCACHETABLE)) {
+                (Some(uuid), Ok(table)) => table.get(uuid)?.map(|c| c.value()),
+                _ => None,
+            };
+            let cached = match cached {
+                Some(cached) if cached.is_fresh(name, ttl) => Some(cached),
+                _ => match reader.open_table(QUERYCACHETABLE) {
+                    Ok(table) => table.get(key.as_slice())?.map(|c| c.value()),
+                    Err(_) => None,
+                },
+            };
+            if let Some(cached) = cached.filter(|c| c.is_fresh(name, ttl)) {
+                tracing::debug!("using cached {name} response");
+                return Ok(cached.candidates);
+            }
+        }
+        let response = CachedResponse {
+            backend: name.to_owned(),
+            fetched_at: now(),
+            candidates: backend.search(query)?,
+        };
+        let writer = self.0.begin_write()?;
+        {
+            writer
+                .open_table(QUERYCACHETABLE)?
+                .insert(key.as_slice(), &response)?;
+            if let Some(uuid) = song {
+                writer.open_table(SONGCACHETABLE)?.insert(uuid, &response)?;
+            }
+        }
+        writer.commit()?;
+        Ok(response.candidates)
+    }
+    ///remove every cached response, or only the ones older than `ttl`.
+    ///Returns the number of removed entries
+    pub fn clear_cache(&self, ttl: Option<Duration>) -> anyhow::Result<usize> {
+        let expired =
+            |response: &CachedResponse| ttl.map_or(true, |ttl| response.is_older_than(ttl));
+        let writer = self.0.begin_write()?;
+        let removed = {
+            let mut queries = writer.open_table(QUERYCACHETABLE)?;
+            let mut query_keys = Vec::new();
+            for entry in queries.iter()? {
+                let (key, value) = entry?;
+                if expired(&value.value()) {
+                    query_keys.push(key.value().to_vec());
+                }
+            }
+            for key in &query_keys {
+                queries.remove(key.as_slice())?;
+            }
+            let mut songs = writer.open_table(SONGCACHETABLE)?;
+            let mut song_keys = Vec::new();
+            for entry in songs.iter()? {
+                let (key, value) = entry?;
+                if expired(&value.value()) {
+                    song_keys.push(key.value());
+                }
+            }
+            for key in &song_keys {
+                songs.remove(key)?;
+            }
+            query_keys.len() + song_keys.len()
+        };
+        writer.commit()?;
+        Ok(removed)
+    }
+    ///count the cached responses. Each is expired by the ttl of the backend that returned it
+    pub fn cache_stats(&self, ttl: &Ttl) -> anyhow::Result<CacheStats> {
+        let mut stats = CacheStats::default();
+        let reader = self.0.begin_read()?;
+        let mut count = |response: CachedResponse| {
+            if response.is_older_than(ttl.of(&response.backend)) {
+                stats.expired += 1;
+            }
+            stats.oldest = Some(
+                stats
+                    .oldest
+                    .map_or(response.fetched_at, |o| o.min(response.fetched_at)),
+            );
+            stats.newest = Some(
+                stats
+                    .newest
+                    .map_or(response.fetched_at, |n| n.max(response.fetched_at)),
+            );
+        };
+        let mut queries = 0;
+        if let Ok(table) = reader.open_table(QUERYCACHETABLE) {
+            for entry in table.iter()? {
+                count(entry?.1.value());
+                queries += 1;
+            }
+        }
+        let mut songs = 0;
+        if let Ok(table) = reader.open_table(SONGCACHETABLE) {
+            for entry in table.iter()? {
+                count(entry?.1.value());
+                songs += 1;
+            }
+        }
+        stats.queries = queries;
+        stats.songs = songs;
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, time::Duration};
+
+    use super::{now, query_key, CachedResponse, Ttl};
+    use crate::{
+        db::{Database, SongUuid, QUERYCACHETABLE, SONGCACHETABLE},
+        fetch::{Candidate, Error, Metadata, MetadataBackend, Query},
+    };
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    ///answers every search with the same candidate and counts the searches
+    struct Counting {
+        name: &'static str,
+        searches: Cell<usize>,
+    }
+
+    impl Counting {
+        fn new(name: &'static str) -> Self {
+            Self {
+                name,
+                searches: Cell::new(0),
+            }
+        }
+    }
+
+    impl MetadataBackend for Counting {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+        fn search(&self, _query: &Query) -> Result<Vec<Candidate>, Error> {
+            self.searches.set(self.searches.get() + 1);
+            Ok(vec![Candidate {
+                id: "id".to_owned(),
+                release_id: None,
+                confidence: 1.0,
+                metadata: Metadata::default(),
+            }])
+        }
+        fn release(&self, id: &str) -> Result<Vec<Candidate>, Error> {
+            Err(Error::NotFound(id.to_owned()))
+        }
+    }
+
+    fn query(title: &str) -> Query {
+        Query {
+            title: Some(title.to_owned()),
+            ..Query::default()
+        }
+    }
+
+    ///store a response from `backend` that is `age` seconds old, for `query` and for a new song
+    fn store(db: &Database, backend: &str, query: &Query, age: u64) {
+        let response = CachedResponse {
+            backend: backend.to_owned(),
+            fetched_at: now() - age,
+            candidates: Vec::new(),
+        };
+        let writer = db.0.begin_write().unwrap();
+        {
+            writer
+                .open_table(QUERYCACHETABLE)
+                .unwrap()
+                .insert(query_key(backend, query).as_slice(), &response)
+                .unwrap();
+            writer
+                .open_table(SONGCACHETABLE)
+                .unwrap()
+                .insert(&SongUuid(uuid::Uuid::new_v4()), &response)
+                .unwrap();
+        }
+        writer.commit().unwrap();
+    }
+
+    #[test]
+    fn responses_are_reused_until_they_expire() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::init(dir.path(), false).unwrap();
+        let backend = Counting::new("counting");
+        let ttl = Duration::from_secs(DAY);
+        let first = db.cached_search(&backend, &query("a"), None, ttl).unwrap();
+        let second = db.cached_search(&backend, &query("a"), None, ttl).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(backend.searches.get(), 1);
+        db.cached_search(&backend, &query("a"), None, Duration::ZERO)
+            .unwrap();
+        assert_eq!(backend.searches.get(), 2);
+    }
+
+    #[test]
+    fn songs_are_answered_by_uuid_even_when_their_query_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::init(dir.path(), false).unwrap();
+        let backend = Counting::new("counting");
+        let ttl = Duration::from_secs(DAY);
+        let song = SongUuid(uuid::Uuid::new_v4());
+        db.cached_search(&backend, &query("a"), Some(&song), ttl)
+            .unwrap();
+        db.cached_search(&backend, &query("retagged"), Some(&song), ttl)
+            .unwrap();
+        assert_eq!(backend.searches.get(), 1);
+    }
+
+    #[test]
+    fn responses_of_other_backends_are_not_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::init(dir.path(), false).unwrap();
+        let ttl = Duration::from_secs(DAY);
+        let song = SongUuid(uuid::Uuid::new_v4());
+        let first = Counting::new("first");
+        let second = Counting::new("second");
+        db.cached_search(&first, &query("a"), Some(&song), ttl)
+            .unwrap();
+        db.cached_search(&second, &query("a"), Some(&song), ttl)
+            .unwrap();
+        assert_eq!(first.searches.get(), 1);
+        assert_eq!(second.searches.get(), 1);
+    }
+
+    #[test]
+    fn clear_cache_removes_old_or_all_responses() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::init(dir.path(), false).unwrap();
+        store(&db, "spotify", &query("new"), 0);
+        store(&db, "spotify", &query("old"), 10 * DAY);
+        let removed = db.clear_cache(Some(Duration::from_secs(5 * DAY))).unwrap();
+        assert_eq!(removed, 2);
+        let stats = db.cache_stats(&Ttl::default()).unwrap();
+        assert_eq!((stats.queries, stats.songs), (1, 1));
+        assert_eq!(db.clear_cache(None).unwrap(), 2);
+        let stats = db.cache_stats(&Ttl::default()).unwrap();
+        assert_eq!((stats.queries, stats.songs), (0, 0));
+        assert_eq!(stats.oldest, None);
+    }
+
+    #[test]
+    fn stats_expire_each_backend_by_its_own_ttl() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::init(dir.path(), false).unwrap();
+        let empty = db.cache_stats(&Ttl::default()).unwrap();
+        assert_eq!((empty.queries, empty.songs, empty.expired), (0, 0, 0));
+        store(&db, "spotify", &query("a"), 10 * DAY);
+        store(&db, "musicbrainz", &query("a"), 20 * DAY);
+        let stats = db.cache_stats(&Ttl::default()).unwrap();
+        assert_eq!((stats.queries, stats.songs, stats.expired), (2, 2, 0));
+        assert!(stats.oldest < stats.newest);
+        let mut ttl = Ttl::days(30);
+        ttl.backends.insert("spotify".to_owned(), 5);
+        assert_eq!(db.cache_stats(&ttl).unwrap().expired, 2);
+        assert_eq!(db.cache_stats(&Ttl::days(15)).unwrap().expired, 2);
+        assert_eq!(db.cache_stats(&Ttl::days(1)).unwrap().expired, 4);
+    }
+}
@@ -1,9 +1,63 @@
+use crate::{
+    cache::CachedResponse, fingerprint::Fingerprint, hash, journal::JournalEntry,
+    progress::StepStatus, sort::Plan,
+};
 use redb::{TableDefinition, TypeName};
 use relative_path::RelativePath;
 use std::path::{Path, PathBuf};
 
 pub const DBNAME: &str = ".bongo.db";
 pub const SONGTABLE: TableDefinition<SongUuid, DbEntry> = TableDefinition::new("song_table");
+pub const FINGERPRINTTABLE: TableDefinition<SongUuid, Fingerprint> =
+    TableDefinition::new("fingerprint_table");
+pub const QUERYCACHETABLE: TableDefinition<&[u8], CachedResponse> =
+    TableDefinition::new("query_cache_table");
+pub const SONGCACHETABLE: TableDefinition<SongUuid, CachedResponse> =
+    TableDefinition::new("song_cache_table");
+///sorts by operation id, see [`crate::journal`]
+pub const JOURNALTABLE: TableDefinition<u64, JournalEntry> = TableDefinition::new("journal_table");
+///the sort in progress, see [`crate::progress`]
+pub const SORTPLANTABLE: TableDefinition<&str, Plan> = TableDefinition::new("sort_plan_table");
+pub const SORTSTEPTABLE: TableDefinition<u64, StepStatus> = TableDefinition::new("sort_step_table");
+
+///store a serde type in redb using postcard.
+///With a legacy type, values written before fields were added are read through `From<$legacy>`
+macro_rules! redb_value {
+    ($type:ty, $name:literal) => {
+        redb_value!(@impl $type, $name, data => postcard::from_bytes(data).unwrap());
+    };
+    ($type:ty, $name:literal, $legacy:ty) => {
+        redb_value!(@impl $type, $name, data => postcard::from_bytes(data)
+            .or_else(|_| postcard::from_bytes::<$legacy>(data).map(<$type>::from))
+            .unwrap()
+        );
+    };
+    (@impl $type:ty, $name:literal, $data:ident => $from:expr) => {
+        impl redb::RedbValue for $type {
+            type SelfType<'a> = Self;
+            type AsBytes<'a> = Vec<u8>;
+            fn fixed_width() -> Option<usize> {
+                None
+            }
+            fn from_bytes<'a>($data: &'a [u8]) -> Self::SelfType<'a>
+            where
+                Self: 'a,
+            {
+                $from
+            }
+            fn as_bytes<'a, 'b: 'a>(value: &'a Self::SelfType<'b>) -> Self::AsBytes<'a>
+            where
+                Self: 'a,
+                Self: 'b,
+            {
+                postcard::to_allocvec(value).unwrap()
+            }
+            fn type_name() -> TypeName {
+                TypeName::new($name)
+            }
+        }
+    };
+}
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -50,6 +104,12 @@ impl Database {
             .ok_or_else(|| Error::UnableToFindDb(dir.to_path_buf()))?;
         Ok(Self(redb::Database::open(&path)?, path))
     }
+    ///the directory containing the db that `dir` belongs to
+    pub fn find_root(dir: &Path) -> Result<PathBuf, Error> {
+        Self::find_db(dir.to_path_buf())
+            .and_then(|db| db.parent().map(Path::to_path_buf))
+            .ok_or_else(|| Error::UnableToFindDb(dir.to_path_buf()))
+    }
     fn find_db(mut current_dir: PathBuf) -> Option<PathBuf> {
         current_dir.push("");
         let mut db_path;
@@ -67,32 +127,43 @@ impl Database {
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct DbEntry {
     pub old_path: RelativePath,
+    ///blake3 of the audio without its tags, see [`crate::hash::audio_hash`]
+    pub audio_hash: Option<String>,
+    ///size in bytes when last hashed
+    pub size: u64,
+    ///modification time in seconds since the unix epoch when last hashed
+    pub modified: u64,
 }
-impl redb::RedbValue for DbEntry {
-    type SelfType<'a> = Self;
-    type AsBytes<'a> = Vec<u8>;
-    fn fixed_width() -> Option<usize> {
-        None
-    }
-    fn from_bytes<'a>(data: &'a [u8]) -> Self::SelfType<'a>
-    where
-        Self: 'a,
-    {
-        postcard::from_bytes(data).unwrap()
+impl DbEntry {
+    ///whether the song at `path` looks unchanged since it was hashed
+    pub fn is_current(&self, path: &Path) -> std::io::Result<bool> {
+        Ok(self.audio_hash.is_some() && hash::stamp(path)? == (self.size, self.modified))
     }
-    fn as_bytes<'a, 'b: 'a>(value: &'a Self::SelfType<'b>) -> Self::AsBytes<'a>
-    where
-        Self: 'a,
-        Self: 'b,
-    {
-        postcard::to_allocvec(value).unwrap()
-    }
-    fn type_name() -> TypeName {
-        TypeName::new("song_entry")
+}
+///[`DbEntry`] as stored before songs were hashed
+#[derive(serde::Deserialize)]
+struct LegacyDbEntry {
+    old_path: RelativePath,
+}
+impl From<LegacyDbEntry> for DbEntry {
+    fn from(legacy: LegacyDbEntry) -> Self {
+        Self {
+            old_path: legacy.old_path,
+            audio_hash: None,
+            size: 0,
+            modified: 0,
+        }
     }
 }
+redb_value!(DbEntry, "song_entry", LegacyDbEntry);
 #[derive(
-    derive_more::From, derive_more::Display, Debug, serde::Serialize, serde::Deserialize, PartialEq,
+    derive_more::From,
+    derive_more::Display,
+    Debug,
+    Clone,
+    serde::Serialize,
+    serde::Deserialize,
+    PartialEq,
 )]
 pub struct SongUuid(pub uuid::Uuid);
 impl redb::RedbKey for SongUuid {
@@ -100,26 +171,9 @@ impl redb::RedbKey for SongUuid {
         data1.cmp(data2)
     }
 }
-impl redb::RedbValue for SongUuid {
-    type SelfType<'a> = Self;
-    type AsBytes<'a> = Vec<u8>;
-    fn fixed_width() -> Option<usize> {
-        None
-    }
-    fn from_bytes<'a>(data: &'a [u8]) -> Self::SelfType<'a>
-    where
-        Self: 'a,
-    {
-        postcard::from_bytes(data).unwrap()
-    }
-    fn as_bytes<'a, 'b: 'a>(value: &'a Self::SelfType<'b>) -> Self::AsBytes<'a>
-    where
-        Self: 'a,
-        Self: 'b,
-    {
-        postcard::to_allocvec(value).unwrap()
-    }
-    fn type_name() -> redb::TypeName {
-        TypeName::new("song_uuid")
-    }
-}
+redb_value!(SongUuid, "song_uuid");
+redb_value!(Fingerprint, "fingerprint");
+redb_value!(CachedResponse, "cached_response");
+redb_value!(JournalEntry, "journal_entry");
+redb_value!(Plan, "sort_plan");
+redb_value!(StepStatus, "step_status");
@@ -2,4 +2,6 @@
 pub enum Error {
     #[error(transparent)]
     Db(#[from] crate::db::Error),
+    #[error(transparent)]
+    Fetch(#[from] crate::fetch::Error),
 }
@@ -0,0 +1,340 @@
+use super::{
+    http::{HttpClient, UreqClient},
+    Candidate, Error, Metadata, MetadataBackend, Query,
+};
+
+pub const API_URL: &str = "https://api.acoustid.org/v2";
+
+///identifies songs by their acoustic fingerprint using an acoustid compatible api
+pub struct AcoustId<C: HttpClient = UreqClient> {
+    client: C,
+    api_key: String,
+    api_url: String,
+}
+
+impl AcoustId {
+    ///read the application api key from `ACOUSTID_API_KEY`
+    /// # Errors
+    ///   [`Error::MissingCredentials`]
+    pub fn from_env() -> Result<Self, Error> {
+        let api_key = std::env::var("ACOUSTID_API_KEY")
+            .map_err(|_| Error::MissingCredentials("set ACOUSTID_API_KEY"))?;
+        Ok(Self::new(UreqClient::new(), api_key))
+    }
+}
+
+impl<C: HttpClient> AcoustId<C> {
+    pub fn new(client: C, api_key: String) -> Self {
+        Self {
+            client,
+            api_key,
+            api_url: API_URL.to_owned(),
+        }
+    }
+    ///override the api url, e.g. for a local acoustid server
+    #[must_use]
+    pub fn with_url(mut self, api_url: String) -> Self {
+        self.api_url = api_url;
+        self
+    }
+}
+
+impl<C: HttpClient> MetadataBackend for AcoustId<C> {
+    fn name(&self) -> &'static str {
+        "acoustid"
+    }
+    fn needs_fingerprint(&self) -> bool {
+        true
+    }
+    fn search(&self, query: &Query) -> Result<Vec<Candidate>, Error> {
+        let Some(fingerprint) = &query.fingerprint else {
+            return Ok(Vec::new());
+        };
+        let duration = fingerprint.duration.to_string();
+        let compressed = fingerprint.compress();
+        let body = self.client.get(
+            &format!("{}/lookup", self.api_url),
+            &[
+                ("client", self.api_key.as_str()),
+                ("meta", "recordings releases tracks"),
+                ("duration", duration.as_str()),
+                ("fingerprint", compressed.as_str()),
+            ],
+            &[],
+        )?;
+        let response: LookupResponse = serde_json::from_str(&body)?;
+        if let Some(error) = response.error {
+            return Err(Error::Backend(error.message));
+        }
+        let mut candidates = Vec::new();
+        for result in response.results {
+            for recording in result.recordings {
+                let artist = recording
+                    .artists
+                    .iter()
+                    .flat_map(|a| [a.name.as_str(), a.joinphrase.as_str()])
+                    .collect::<String>();
+                //recordings without releases are still worth a candidate
+                let releases = if recording.releases.is_empty() {
+                    vec![None]
+                } else {
+                    recording.releases.iter().map(Some).collect()
+                };
+                for release in releases {
+                    candidates.push(Candidate {
+                        id: recording.id.clone(),
+                        release_id: release.map(|r| r.id.clone()),
+                        confidence: result.score,
+                        metadata: Metadata {
+                            title: recording.title.clone(),
+                            artist: (!artist.is_empty()).then(|| artist.clone()),
+                            album: release.and_then(|r| r.title.clone()),
+                            track: release.and_then(Release::track),
+                            year: release.and_then(|r| r.date.as_ref()).and_then(|d| d.year),
+                            musicbrainz_recording_id: Some(recording.id.clone()),
+                            musicbrainz_release_id: release.map(|r| r.id.clone()),
+                            ..Default::default()
+                        },
+                    });
+                }
+            }
+        }
+        Ok(candidates)
+    }
+    fn release(&self, id: &str) -> Result<Vec<Candidate>, Error> {
+        //acoustid only identifies recordings
+        Err(Error::NotFound(id.to_owned()))
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct LookupResponse {
+    #[serde(default)]
+    results: Vec<LookupResult>,
+    error: Option<ApiError>,
+}
+
+#[derive(serde::Deserialize)]
+struct ApiError {
+    message: String,
+}
+
+#[derive(serde::Deserialize)]
+struct LookupResult {
+    score: f32,
+    #[serde(default)]
+    recordings: Vec<Recording>,
+}
+
+#[derive(serde::Deserialize)]
+struct Recording {
+    id: String,
+    title: Option<String>,
+    #[serde(default)]
+    artists: Vec<Artist>,
+    #[serde(default)]
+    releases: Vec<Release>,
+}
+
+#[derive(serde::Deserialize)]
+struct Artist {
+    name: String,
+    #[serde(default)]
+    joinphrase: String,
+}
+
+#[derive(serde::Deserialize)]
+struct Release {
+    id: String,
+    title: Option<String>,
+    date: Option<Date>,
+    #[serde(default)]
+    mediums: Vec<Medium>,
+}
+
+impl Release {
+    fn track(&self) -> Option<u32> {
+        self.mediums
+            .iter()
+            .flat_map(|m| &m.tracks)
+            .find_map(|t| t.position)
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct Date {
+    year: Option<u32>,
+}
+
+#[derive(serde::Deserialize)]
+struct Medium {
+    #[serde(default)]
+    tracks: Vec<Track>,
+}
+
+#[derive(serde::Deserialize)]
+struct Track {
+    position: Option<u32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::AcoustId;
+    use crate::{
+        fetch::{
+            http::{self, HttpClient},
+            Error, MetadataBackend, Query,
+        },
+        fingerprint::Fingerprint,
+    };
+
+    const LOOKUP: &str = r#"{"status": "ok", "results": [{
+        "id": "result1",
+        "score": 0.9,
+        "recordings": [
+            {
+                "id": "rec1",
+                "title": "Some Title",
+                "artists": [{"name": "Some Artist", "joinphrase": " & "}, {"name": "Other"}],
+                "releases": [
+                    {
+                        "id": "rel1",
+                        "title": "Some Album",
+                        "date": {"year": 2001, "month": 2},
+                        "mediums": [{"tracks": [{"position": 4}]}]
+                    },
+                    {"id": "rel2", "title": "Best Of"}
+                ]
+            },
+            {"id": "rec2"}
+        ]
+    }]}"#;
+
+    ///the url and parameters of every request
+    type Requests = RefCell<Vec<(String, Vec<(String, String)>)>>;
+
+    ///answers every request with `body` and records the requests
+    struct Stub {
+        body: &'static str,
+        requests: Requests,
+    }
+
+    impl Stub {
+        fn new(body: &'static str) -> Self {
+            Self {
+                body,
+                requests: RefCell::default(),
+            }
+        }
+    }
+
+    impl HttpClient for &Stub {
+        fn get(
+            &self,
+            url: &str,
+            query: &[(&str, &str)],
+            _headers: &[(&str, &str)],
+        ) -> Result<String, http::Error> {
+            let query = query
+                .iter()
+                .map(|(k, v)| ((*k).to_owned(), (*v).to_owned()))
+                .collect();
+            self.requests.borrow_mut().push((url.to_owned(), query));
+            Ok(self.body.to_owned())
+        }
+        fn post_form(
+            &self,
+            _url: &str,
+            _form: &[(&str, &str)],
+            _headers: &[(&str, &str)],
+        ) -> Result<String, http::Error> {
+            unreachable!("lookups are GET requests")
+        }
+    }
+
+    fn acoustid(stub: &Stub) -> AcoustId<&Stub> {
+        AcoustId::new(stub, "key".to_owned()).with_url("http://acoustid.test/v2".to_owned())
+    }
+
+    fn query() -> Query {
+        Query {
+            fingerprint: Some(Fingerprint {
+                duration: 215,
+                raw: vec![1, 7, 1 << 8],
+            }),
+            ..Query::default()
+        }
+    }
+
+    #[test]
+    fn songs_without_a_fingerprint_are_not_looked_up() {
+        let stub = Stub::new(LOOKUP);
+        assert!(acoustid(&stub)
+            .search(&Query::default())
+            .unwrap()
+            .is_empty());
+        assert!(stub.requests.borrow().is_empty());
+    }
+
+    #[test]
+    fn lookup_sends_the_compressed_fingerprint() {
+        let stub = Stub::new(r#"{"status": "ok", "results": []}"#);
+        let query = query();
+        assert!(acoustid(&stub).search(&query).unwrap().is_empty());
+        let requests = stub.requests.borrow();
+        let (url, params) = &requests[0];
+        assert_eq!(url, "http://acoustid.test/v2/lookup");
+        let compressed = query.fingerprint.unwrap().compress();
+        for param in [
+            ("client", "key"),
+            ("duration", "215"),
+            ("fingerprint", compressed.as_str()),
+        ] {
+            assert!(
+                params.contains(&(param.0.to_owned(), param.1.to_owned())),
+                "{param:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn lookup_lists_a_candidate_per_release() {
+        let stub = Stub::new(LOOKUP);
+        let candidates = acoustid(&stub).search(&query()).unwrap();
+        let ids = candidates
+            .iter()
+            .map(|c| (c.id.as_str(), c.release_id.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ids,
+            [
+                ("rec1", Some("rel1")),
+                ("rec1", Some("rel2")),
+                ("rec2", None)
+            ]
+        );
+        assert!(candidates
+            .iter()
+            .all(|c| (c.confidence - 0.9).abs() < f32::EPSILON));
+        let metadata = &candidates[0].metadata;
+        assert_eq!(metadata.title.as_deref(), Some("Some Title"));
+        assert_eq!(metadata.artist.as_deref(), Some("Some Artist & Other"));
+        assert_eq!(metadata.album.as_deref(), Some("Some Album"));
+        assert_eq!(metadata.track, Some(4));
+        assert_eq!(metadata.year, Some(2001));
+        assert_eq!(metadata.musicbrainz_recording_id.as_deref(), Some("rec1"));
+        assert_eq!(metadata.musicbrainz_release_id.as_deref(), Some("rel1"));
+        assert_eq!(candidates[1].metadata.track, None);
+        assert_eq!(candidates[2].metadata.artist, None);
+    }
+
+    #[test]
+    fn api_errors_are_returned() {
+        let stub =
+            Stub::new(r#"{"status": "error", "error": {"code": 4, "message": "invalid API key"}}"#);
+        let error = acoustid(&stub).search(&query()).unwrap_err();
+        assert!(matches!(error, Error::Backend(message) if message == "invalid API key"));
+    }
+}
@@ -0,0 +1,281 @@
+use super::{Candidate, Error, MetadataBackend, Query};
+
+///an in memory backend for tests. Searches score every candidate against the query
+#[derive(Debug, Default, Clone)]
+pub struct Fake {
+    pub candidates: Vec<Candidate>,
+}
+
+impl Fake {
+    #[must_use]
+    pub fn new(candidates: Vec<Candidate>) -> Self {
+        Self { candidates }
+    }
+}
+
+impl MetadataBackend for Fake {
+    fn name(&self) -> &'static str {
+        "fake"
+    }
+    fn search(&self, query: &Query) -> Result<Vec<Candidate>, Error> {
+        let mut found = self
+            .candidates
+            .iter()
+            .map(|c| c.clone().with_confidence(query.score(&c.metadata)))
+            .filter(|c| c.confidence > 0.0)
+            .collect::<Vec<_>>();
+        found.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+        Ok(found)
+    }
+    fn release(&self, id: &str) -> Result<Vec<Candidate>, Error> {
+        let tracks = self
+            .candidates
+            .iter()
+            .filter(|c| c.release_id.as_deref() == Some(id))
+            .map(|c| c.clone().with_confidence(1.0))
+            .collect::<Vec<_>>();
+        if tracks.is_empty() {
+            return Err(Error::NotFound(id.to_owned()));
+        }
+        Ok(tracks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::HashMap};
+
+    use super::Fake;
+    use crate::{
+        fetch::{add_release_tracks, Candidate, Error, Metadata, MetadataBackend, Query, Review},
+        scan::Scan,
+        song::{MusicDir, Song},
+        test_util,
+    };
+
+    fn candidate(id: &str, title: &str, artist: &str, album: &str) -> Candidate {
+        Candidate {
+            id: id.to_owned(),
+            release_id: Some(format!("{album} release")),
+            confidence: 0.0,
+            metadata: Metadata {
+                title: Some(title.to_owned()),
+                artist: Some(artist.to_owned()),
+                album: Some(album.to_owned()),
+                ..Metadata::default()
+            },
+        }
+    }
+
+    fn fake() -> Fake {
+        Fake::new(vec![
+            candidate("unrelated", "Other Song", "Other Band", "Other Album"),
+            candidate("close", "Some Title (Live)", "Some Artist", "Live Album"),
+            candidate("exact", "Some Title", "Some Artist", "Studio Album"),
+        ])
+    }
+
+    fn query() -> Query {
+        Query {
+            title: Some("Some Title".to_owned()),
+            artist: Some("Some Artist".to_owned()),
+            ..Query::default()
+        }
+    }
+
+    ///fails every search
+    struct Failing;
+
+    impl MetadataBackend for Failing {
+        fn name(&self) -> &'static str {
+            "failing"
+        }
+        fn search(&self, _query: &Query) -> Result<Vec<Candidate>, Error> {
+            Err(Error::Backend("503 service unavailable".to_owned()))
+        }
+        fn release(&self, id: &str) -> Result<Vec<Candidate>, Error> {
+            Err(Error::NotFound(id.to_owned()))
+        }
+    }
+
+    ///a [`Fake`] that records the releases looked up
+    #[derive(Default)]
+    struct Recording {
+        fake: Fake,
+        releases: RefCell<Vec<String>>,
+    }
+
+    impl MetadataBackend for Recording {
+        fn name(&self) -> &'static str {
+            "recording"
+        }
+        fn search(&self, query: &Query) -> Result<Vec<Candidate>, Error> {
+            self.fake.search(query)
+        }
+        fn release(&self, id: &str) -> Result<Vec<Candidate>, Error> {
+            self.releases.borrow_mut().push(id.to_owned());
+            self.fake.release(id)
+        }
+    }
+
+    #[test]
+    fn exact_match_scores_one() {
+        let metadata = &fake().candidates[2].metadata;
+        assert!((query().score(metadata) - 1.0).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn score_ignores_case_and_punctuation() {
+        let metadata = Metadata {
+            title: Some("some title!".to_owned()),
+            artist: Some("SOME ARTIST".to_owned()),
+            ..Metadata::default()
+        };
+        assert!((query().score(&metadata) - 1.0).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn untagged_songs_are_scored_by_filename() {
+        let query = Query {
+            filename: Some("Some Artist - Some Title".to_owned()),
+            ..Query::default()
+        };
+        let fake = fake();
+        let exact = query.score(&fake.candidates[2].metadata);
+        let unrelated = query.score(&fake.candidates[0].metadata);
+        assert!(exact > unrelated);
+        assert!(unrelated.abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn search_ranks_by_confidence_and_drops_non_matches() {
+        let found = fake().search(&query()).unwrap();
+        let ids = found.iter().map(|c| c.id.as_str()).collect::<Vec<_>>();
+        assert_eq!(ids, ["exact", "close"]);
+        assert!(found[0].confidence > found[1].confidence);
+    }
+
+    #[test]
+    fn release_returns_every_track() {
+        let tracks = fake().release("Studio Album release").unwrap();
+        assert_eq!(tracks.len(), 1);
+        assert!(fake().release("missing").is_err());
+    }
+
+    #[test]
+    fn fetch_writes_the_accepted_candidate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = test_util::tagged_song(dir.path(), "song.flac", "Some Title", "Some Artist");
+        let mut music_dir =
+            MusicDir::init(dir.path().to_path_buf(), false, Scan::default()).unwrap();
+        let mut reviewed = Vec::new();
+        music_dir
+            .fetch(&fake(), None, |_, current, candidates| {
+                assert_eq!(current.title.as_deref(), Some("Some Title"));
+                reviewed.extend(candidates.iter().map(|c| c.id.clone()));
+                Ok(Review::best(candidates, 0.9))
+            })
+            .unwrap();
+        assert_eq!(reviewed, ["exact", "close"]);
+        let song = Song::parse(path).unwrap();
+        assert_eq!(song.query().unwrap().album.as_deref(), Some("Studio Album"));
+    }
+
+    #[test]
+    fn fetch_leaves_songs_below_the_confidence_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = test_util::tagged_song(dir.path(), "song.mp3", "Unknown Song", "Some Artist");
+        let mut music_dir =
+            MusicDir::init(dir.path().to_path_buf(), false, Scan::default()).unwrap();
+        music_dir
+            .fetch(&fake(), None, |_, _, candidates| {
+                Ok(Review::best(candidates, 0.9))
+            })
+            .unwrap();
+        let song = Song::parse(path).unwrap();
+        assert_eq!(song.query().unwrap().album, None);
+    }
+
+    #[test]
+    fn fetch_skips_songs_whose_search_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        test_util::tagged_song(dir.path(), "song.flac", "Some Title", "Some Artist");
+        let mut music_dir =
+            MusicDir::init(dir.path().to_path_buf(), false, Scan::default()).unwrap();
+        music_dir
+            .fetch(&Failing, None, |_, _, _| panic!("nothing to review"))
+            .unwrap();
+    }
+
+    #[test]
+    fn release_tracks_join_the_candidates() {
+        let mut releases = HashMap::new();
+        releases.insert(
+            "Studio Album release".to_owned(),
+            vec![
+                candidate("exact", "Some Title", "Some Artist", "Studio Album"),
+                candidate("b-side", "B Side", "Some Artist", "Studio Album"),
+                candidate("skit", "Interlude", "Nobody", "Studio Album"),
+            ],
+        );
+        let query = Query {
+            title: Some("B Side".to_owned()),
+            artist: Some("Some Artist".to_owned()),
+            ..Query::default()
+        };
+        let mut candidates =
+            vec![candidate("b-side", "B Side", "Some Artist", "Studio Album").with_confidence(0.5)];
+        add_release_tracks(&mut candidates, &releases, &query);
+        let ids = candidates.iter().map(|c| c.id.as_str()).collect::<Vec<_>>();
+        assert_eq!(ids, ["b-side", "exact"]);
+        //the search result wins over the release track with the same id
+        assert!((candidates[0].confidence - 0.5).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn fetch_looks_up_the_release_of_accepted_songs() {
+        let dir = tempfile::tempdir().unwrap();
+        test_util::tagged_song(dir.path(), "song.flac", "Some Title", "Some Artist");
+        test_util::tagged_song(dir.path(), "other.mp3", "Some Title", "Some Artist");
+        let mut music_dir =
+            MusicDir::init(dir.path().to_path_buf(), false, Scan::default()).unwrap();
+        let backend = Recording {
+            fake: fake(),
+            ..Recording::default()
+        };
+        music_dir
+            .fetch(&backend, None, |_, _, candidates| {
+                Ok(Review::best(candidates, 0.9))
+            })
+            .unwrap();
+        assert_eq!(*backend.releases.borrow(), ["Studio Album release"]);
+    }
+
+    #[test]
+    fn fetch_ignores_failed_release_lookups() {
+        ///finds songs but fails every release lookup
+        struct NoReleases(Fake);
+        impl MetadataBackend for NoReleases {
+            fn name(&self) -> &'static str {
+                "no releases"
+            }
+            fn search(&self, query: &Query) -> Result<Vec<Candidate>, Error> {
+                self.0.search(query)
+            }
+            fn release(&self, id: &str) -> Result<Vec<Candidate>, Error> {
+                Err(Error::NotFound(id.to_owned()))
+            }
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let path = test_util::tagged_song(dir.path(), "song.flac", "Some Title", "Some Artist");
+        let mut music_dir =
+            MusicDir::init(dir.path().to_path_buf(), false, Scan::default()).unwrap();
+        music_dir
+            .fetch(&NoReleases(fake()), None, |_, _, candidates| {
+                Ok(Review::best(candidates, 0.9))
+            })
+            .unwrap();
+        let song = Song::parse(path).unwrap();
+        assert_eq!(song.query().unwrap().album.as_deref(), Some("Studio Album"));
+    }
+}
@@ -0,0 +1,87 @@
+///the http requests made by metadata backends.
+///implemented by [`UreqClient`], swap it out to test against a mock server
+pub trait HttpClient {
+    ///send a GET request and return the response body
+    /// # Errors
+    ///   [`Error`] if the request fails or the server returns an error status
+    fn get(
+        &self,
+        url: &str,
+        query: &[(&str, &str)],
+        headers: &[(&str, &str)],
+    ) -> Result<String, Error>;
+    ///send a POST request with a url encoded form and return the response body
+    /// # Errors
+    ///   [`Error`] if the request fails or the server returns an error status
+    fn post_form(
+        &self,
+        url: &str,
+        form: &[(&str, &str)],
+        headers: &[(&str, &str)],
+    ) -> Result<String, Error>;
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("request to '{url}' failed. {error}")]
+    Request {
+        url: String,
+        error: Box<ureq::Error>,
+    },
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+pub struct UreqClient(ureq::Agent);
+impl UreqClient {
+    #[must_use]
+    pub fn new() -> Self {
+        Self(
+            ureq::AgentBuilder::new()
+                .user_agent(concat!("bongo/", env!("CARGO_PKG_VERSION")))
+                .build(),
+        )
+    }
+}
+impl Default for UreqClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl HttpClient for UreqClient {
+    fn get(
+        &self,
+        url: &str,
+        query: &[(&str, &str)],
+        headers: &[(&str, &str)],
+    ) -> Result<String, Error> {
+        let mut request = self.0.get(url);
+        for (key, value) in query {
+            request = request.query(key, value);
+        }
+        for (key, value) in headers {
+            request = request.set(key, value);
+        }
+        let response = request.call().map_err(|e| Error::Request {
+            url: url.to_owned(),
+            error: Box::new(e),
+        })?;
+        Ok(response.into_string()?)
+    }
+    fn post_form(
+        &self,
+        url: &str,
+        form: &[(&str, &str)],
+        headers: &[(&str, &str)],
+    ) -> Result<String, Error> {
+        let mut request = self.0.post(url);
+        for (key, value) in headers {
+            request = request.set(key, value);
+        }
+        let response = request.send_form(form).map_err(|e| Error::Request {
+            url: url.to_owned(),
+            error: Box::new(e),
+        })?;
+        Ok(response.into_string()?)
+    }
+}
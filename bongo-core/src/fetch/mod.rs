@@ -0,0 +1,410 @@
+use std::{collections::HashMap, time::Duration};
+
+use lofty::{Accessor, ItemKey, Tag};
+
+use crate::{
+    fingerprint::Fingerprint,
+    song::{GetTags, MusicDir, Song},
+};
+
+#[cfg(feature = "backend-acoustid")]
+pub mod acoustid;
+#[cfg(any(test, feature = "backend-fake"))]
+pub mod fake;
+pub mod http;
+#[cfg(feature = "backend-musicbrainz")]
+pub mod musicbrainz;
+#[cfg(feature = "backend-spotify")]
+pub mod spotify;
+
+///a source of song metadata.
+///each backend lives behind its own cargo feature
+pub trait MetadataBackend {
+    ///name shown to the user
+    fn name(&self) -> &'static str;
+    ///find songs matching the partial tags in `query`
+    /// # Errors
+    ///   [`Error`] if the backend can't be reached or returns an invalid response
+    fn search(&self, query: &Query) -> Result<Vec<Candidate>, Error>;
+    ///every track of the release with the backend specific `id`
+    /// # Errors
+    ///   [`Error`] if the backend can't be reached or returns an invalid response
+    fn release(&self, id: &str) -> Result<Vec<Candidate>, Error>;
+    ///whether [`Query::fingerprint`] should be filled in before searching
+    fn needs_fingerprint(&self) -> bool {
+        false
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Http(#[from] http::Error),
+    #[error("invalid response from metadata backend. {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("missing credentials: {0}")]
+    MissingCredentials(&'static str),
+    #[error("'{0}' was not found")]
+    NotFound(String),
+    #[error("metadata backend returned an error: {0}")]
+    Backend(String),
+}
+
+///the existing tags used to search for a song
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Query {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    ///file stem, used when the song has no title, artist or album
+    pub filename: Option<String>,
+    ///acoustic fingerprint, only computed for backends that need it
+    pub fingerprint: Option<Fingerprint>,
+}
+
+impl Query {
+    ///how closely `metadata` matches this query, from 0 to 1
+    #[must_use]
+    pub fn score(&self, metadata: &Metadata) -> f32 {
+        let fields = [
+            (&self.title, &metadata.title),
+            (&self.artist, &metadata.artist),
+            (&self.album, &metadata.album),
+        ];
+        let mut total = 0.0;
+        let mut count = 0.0;
+        for (wanted, found) in fields {
+            if let Some(wanted) = wanted {
+                total += similarity(wanted, found.as_deref().unwrap_or_default());
+                count += 1.0;
+            }
+        }
+        if count > 0.0 {
+            return total / count;
+        }
+        //untagged, compare the file name against everything the backend returned
+        let Some(filename) = &self.filename else {
+            return 0.0;
+        };
+        let found = [&metadata.artist, &metadata.album, &metadata.title]
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join(" ");
+        similarity(filename, &found)
+    }
+}
+
+///the share of words in common between `a` and `b`, ignoring case and punctuation
+#[allow(clippy::cast_precision_loss)]
+fn similarity(a: &str, b: &str) -> f32 {
+    let words = |s: &str| {
+        s.split(|c: char| !c.is_alphanumeric())
+            .filter(|w| !w.is_empty())
+            .map(str::to_lowercase)
+            .collect::<std::collections::HashSet<_>>()
+    };
+    let (a, b) = (words(a), words(b));
+    let union = a.union(&b).count();
+    if union == 0 {
+        return 0.0;
+    }
+    a.intersection(&b).count() as f32 / union as f32
+}
+
+///a possible match returned by a [`MetadataBackend`]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Candidate {
+    ///backend specific id of the track
+    pub id: String,
+    ///backend specific id of the release, for [`MetadataBackend::release`]
+    pub release_id: Option<String>,
+    ///how likely this is the right song, from 0 to 1
+    pub confidence: f32,
+    pub metadata: Metadata,
+}
+
+impl Candidate {
+    #[must_use]
+    pub fn with_confidence(mut self, confidence: f32) -> Self {
+        self.confidence = confidence;
+        self
+    }
+}
+
+impl std::fmt::Display for Candidate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({:.0}%)", self.metadata, self.confidence * 100.0)
+    }
+}
+
+///metadata returned by a backend
+#[derive(Debug, Default, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Metadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub track: Option<u32>,
+    pub year: Option<u32>,
+    pub isrc: Option<String>,
+    pub musicbrainz_recording_id: Option<String>,
+    pub musicbrainz_release_id: Option<String>,
+}
+
+impl Metadata {
+    ///read the fields bongo fetches from `tag`
+    #[must_use]
+    pub fn from_tag(tag: &Tag) -> Self {
+        let text = |key| tag.get_string(&key).map(ToOwned::to_owned);
+        Self {
+            title: tag.title().map(std::borrow::Cow::into_owned),
+            artist: tag.artist().map(std::borrow::Cow::into_owned),
+            album: tag.album().map(std::borrow::Cow::into_owned),
+            track: tag.track(),
+            year: tag.year(),
+            isrc: text(ItemKey::Isrc),
+            musicbrainz_recording_id: text(ItemKey::MusicBrainzRecordingId),
+            musicbrainz_release_id: text(ItemKey::MusicBrainzReleaseId),
+        }
+    }
+    ///every field by name, for displaying side by side
+    #[must_use]
+    pub fn fields(&self) -> [(&'static str, Option<String>); 8] {
+        [
+            ("title", self.title.clone()),
+            ("artist", self.artist.clone()),
+            ("album", self.album.clone()),
+            ("track", self.track.as_ref().map(ToString::to_string)),
+            ("year", self.year.as_ref().map(ToString::to_string)),
+            ("isrc", self.isrc.clone()),
+            ("recording id", self.musicbrainz_recording_id.clone()),
+            ("release id", self.musicbrainz_release_id.clone()),
+        ]
+    }
+    ///write the known fields into `tag`, leaving the rest untouched.
+    ///Returns the names of the fields the tag type can't store
+    pub fn apply(&self, tag: &mut Tag) -> Vec<&'static str> {
+        if let Some(title) = &self.title {
+            tag.set_title(title.clone());
+        }
+        if let Some(artist) = &self.artist {
+            tag.set_artist(artist.clone());
+        }
+        if let Some(album) = &self.album {
+            tag.set_album(album.clone());
+        }
+        if let Some(track) = self.track {
+            tag.set_track(track);
+        }
+        if let Some(year) = self.year {
+            tag.set_year(year);
+        }
+        let texts = [
+            ("isrc", ItemKey::Isrc, &self.isrc),
+            (
+                "recording id",
+                ItemKey::MusicBrainzRecordingId,
+                &self.musicbrainz_recording_id,
+            ),
+            (
+                "release id",
+                ItemKey::MusicBrainzReleaseId,
+                &self.musicbrainz_release_id,
+            ),
+        ];
+        let mut unsupported = Vec::new();
+        for (name, key, value) in texts {
+            if let Some(value) = value {
+                if !tag.insert_text(key, value.clone()) {
+                    unsupported.push(name);
+                }
+            }
+        }
+        unsupported
+    }
+}
+
+impl std::fmt::Display for Metadata {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "'{}'", self.title.as_deref().unwrap_or("Untitled Song"))?;
+        if let Some(artist) = &self.artist {
+            write!(f, " by {artist}")?;
+        }
+        if let Some(album) = &self.album {
+            write!(f, " in album {album}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Song {
+    ///the search query for this song
+    /// # Errors
+    ///   [`crate::song::Error`] if the song is untagged
+    pub fn query(&self) -> Result<Query, crate::song::Error> {
+        let tags = self.tagged.get_tag(&self.path)?;
+        let mut query = Query {
+            title: tags.title().map(std::borrow::Cow::into_owned),
+            artist: tags.artist().map(std::borrow::Cow::into_owned),
+            album: tags.album().map(std::borrow::Cow::into_owned),
+            filename: None,
+            fingerprint: None,
+        };
+        if query.title.is_none() && query.artist.is_none() && query.album.is_none() {
+            query.filename = self
+                .path
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned());
+        }
+        Ok(query)
+    }
+    ///write `metadata` into the primary tag and save the file
+    /// # Errors
+    ///   [`crate::song::Error`] if the song is untagged or can't be saved
+    pub fn apply_metadata(&mut self, metadata: &Metadata) -> Result<(), crate::song::Error> {
+        let unsupported = metadata.apply(self.tagged.get_tag_mut(&self.path)?);
+        if !unsupported.is_empty() {
+            tracing::warn!(
+                "the tags of '{}' can't store the {}, leaving them out",
+                self.path.to_string_lossy(),
+                unsupported.join(", ")
+            );
+        }
+        self.edit_tag(&|tags| {
+            metadata.apply(tags);
+            true
+        })
+    }
+}
+
+///every track of the release `id`. A failed lookup only means fewer candidates, so it is logged and
+///treated as an empty release, which also stops it being retried for every song
+fn release_tracks(backend: &dyn MetadataBackend, id: &str) -> Vec<Candidate> {
+    backend.release(id).unwrap_or_else(|e| {
+        tracing::warn!("{} failed to look up release '{id}'. {e}", backend.name());
+        Vec::new()
+    })
+}
+
+///add the tracks of `releases` that resemble `query` to `candidates`, scored against the query.
+///Tracks the search already returned are left alone
+fn add_release_tracks(
+    candidates: &mut Vec<Candidate>,
+    releases: &HashMap<String, Vec<Candidate>>,
+    query: &Query,
+) {
+    for track in releases.values().flatten() {
+        let confidence = query.score(&track.metadata);
+        if confidence > 0.0 && !candidates.iter().any(|c| c.id == track.id) {
+            candidates.push(track.clone().with_confidence(confidence));
+        }
+    }
+}
+
+///what to do with the candidates found for a song
+#[derive(Debug, Clone)]
+pub enum Review {
+    ///write the candidate at this index
+    Accept(usize),
+    ///write user edited metadata
+    Edit(Metadata),
+    ///leave the song untouched
+    Skip,
+    ///stop fetching
+    Quit,
+}
+
+impl Review {
+    ///accept the best candidate if it is at least `min_confidence`, without asking
+    #[must_use]
+    pub fn best(candidates: &[Candidate], min_confidence: f32) -> Self {
+        match candidates.first() {
+            Some(best) if best.confidence >= min_confidence => Self::Accept(0),
+            _ => Self::Skip,
+        }
+    }
+}
+
+impl MusicDir {
+    ///search `backend` for every song and let `review` decide which candidate to write.
+    ///`review` is given the song, its current metadata and the candidates, most confident first.
+    ///responses younger than `cache_ttl` are reused from the db, `None` disables the cache.
+    ///Once a candidate is accepted its whole release is looked up, so the rest of an album
+    ///is offered to later songs even when the search misses them
+    pub fn fetch(
+        &mut self,
+        backend: &dyn MetadataBackend,
+        cache_ttl: Option<Duration>,
+        mut review: impl FnMut(&Song, &Metadata, &[Candidate]) -> anyhow::Result<Review>,
+    ) -> anyhow::Result<()> {
+        let mut releases = HashMap::new();
+        for i in 0..self.songs.len() {
+            let song = &self.songs[i];
+            let mut query = song.query()?;
+            if backend.needs_fingerprint() {
+                match self.fingerprint(song, false) {
+                    Ok(fingerprint) => query.fingerprint = Some(fingerprint),
+                    Err(e) => {
+                        tracing::warn!(
+                            "unable to fingerprint '{}'. {e}",
+                            song.path.to_string_lossy()
+                        );
+                        continue;
+                    }
+                }
+            }
+            let candidates = match cache_ttl {
+                Some(ttl) => self
+                    .db
+                    .cached_search(backend, &query, song.uuid.as_ref(), ttl),
+                None => backend.search(&query).map_err(Into::into),
+            };
+            //one failed search, e.g. when rate limited, shouldn't stop the rest
+            let mut candidates = match candidates {
+                Ok(candidates) => candidates,
+                Err(e) => {
+                    tracing::warn!(
+                        "{} failed to search for '{}', skipping it. {e}",
+                        backend.name(),
+                        song.path.to_string_lossy()
+                    );
+                    continue;
+                }
+            };
+            add_release_tracks(&mut candidates, &releases, &query);
+            if candidates.is_empty() {
+                tracing::warn!(
+                    "{} found no match for '{}'",
+                    backend.name(),
+                    song.path.to_string_lossy()
+                );
+                continue;
+            }
+            candidates.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+            let current = Metadata::from_tag(song.tagged.get_tag(&song.path)?);
+            let metadata = match review(song, &current, &candidates)? {
+                Review::Accept(index) => match candidates.get(index) {
+                    Some(candidate) => {
+                        if let Some(id) = &candidate.release_id {
+                            releases
+                                .entry(id.clone())
+                                .or_insert_with(|| release_tracks(backend, id));
+                        }
+                        candidate.metadata.clone()
+                    }
+                    None => anyhow::bail!("there is no candidate {index}"),
+                },
+                Review::Edit(metadata) => metadata,
+                Review::Skip => continue,
+                Review::Quit => break,
+            };
+            if metadata == current {
+                continue;
+            }
+            tracing::info!("writing {metadata} to '{}'", song.path.to_string_lossy());
+            self.songs[i].apply_metadata(&metadata)?;
+        }
+        Ok(())
+    }
+}
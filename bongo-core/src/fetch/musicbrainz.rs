@@ -0,0 +1,423 @@
+use std::{
+    cell::Cell,
+    time::{Duration, Instant},
+};
+
+use super::{
+    http::{HttpClient, UreqClient},
+    Candidate, Error, Metadata, MetadataBackend, Query,
+};
+
+pub const API_URL: &str = "https://musicbrainz.org/ws/2";
+///musicbrainz asks clients to identify themselves with a contact url
+const USER_AGENT: &str = concat!(
+    "bongo/",
+    env!("CARGO_PKG_VERSION"),
+    " ( https://github.com/it-a-me/bongo )"
+);
+///musicbrainz allows one request per second
+const RATE_LIMIT: Duration = Duration::from_secs(1);
+
+///searches the musicbrainz web service
+pub struct MusicBrainz<C: HttpClient = UreqClient> {
+    client: C,
+    api_url: String,
+    last_request: Cell<Option<Instant>>,
+}
+
+impl<C: HttpClient> MusicBrainz<C> {
+    pub fn new(client: C) -> Self {
+        Self {
+            client,
+            api_url: API_URL.to_owned(),
+            last_request: Cell::new(None),
+        }
+    }
+    ///override the api url, e.g. for a local mirror
+    #[must_use]
+    pub fn with_url(mut self, api_url: String) -> Self {
+        self.api_url = api_url;
+        self
+    }
+    fn get(&self, path: &str, query: &[(&str, &str)]) -> Result<String, Error> {
+        if let Some(last) = self.last_request.get() {
+            std::thread::sleep(RATE_LIMIT.saturating_sub(last.elapsed()));
+        }
+        let mut query = query.to_vec();
+        query.push(("fmt", "json"));
+        let response = self.client.get(
+            &format!("{}/{path}", self.api_url),
+            &query,
+            &[("User-Agent", USER_AGENT)],
+        );
+        self.last_request.set(Some(Instant::now()));
+        Ok(response?)
+    }
+}
+
+impl<C: HttpClient> MetadataBackend for MusicBrainz<C> {
+    fn name(&self) -> &'static str {
+        "musicbrainz"
+    }
+    fn search(&self, query: &Query) -> Result<Vec<Candidate>, Error> {
+        let lucene = search_string(query);
+        let body = self.get("recording", &[("query", lucene.as_str()), ("limit", "5")])?;
+        let response: SearchResponse = serde_json::from_str(&body)?;
+        let mut candidates = Vec::new();
+        for recording in response.recordings {
+            for release in &recording.releases {
+                let track = release
+                    .media
+                    .iter()
+                    .flat_map(|m| &m.track)
+                    .find_map(|t| t.number.parse().ok());
+                let metadata = Metadata {
+                    title: Some(recording.title.clone()),
+                    artist: credit(&recording.artist_credit),
+                    album: Some(release.title.clone()),
+                    track,
+                    year: year(release.date.as_deref()),
+                    isrc: recording.isrcs.first().cloned(),
+                    musicbrainz_recording_id: Some(recording.id.clone()),
+                    musicbrainz_release_id: Some(release.id.clone()),
+                };
+                //blend the musicbrainz relevance score with our own comparison
+                let confidence =
+                    f32::midpoint(f32::from(recording.score) / 100.0, query.score(&metadata));
+                candidates.push(Candidate {
+                    id: recording.id.clone(),
+                    release_id: Some(release.id.clone()),
+                    confidence,
+                    metadata,
+                });
+            }
+        }
+        Ok(candidates)
+    }
+    fn release(&self, id: &str) -> Result<Vec<Candidate>, Error> {
+        let body = self.get(
+            &format!("release/{id}"),
+            &[("inc", "recordings+artist-credits+isrcs")],
+        )?;
+        let release: Release = serde_json::from_str(&body)?;
+        let album_artist = credit(&release.artist_credit);
+        Ok(release
+            .media
+            .iter()
+            .flat_map(|m| &m.tracks)
+            .map(|track| Candidate {
+                id: track.recording.id.clone(),
+                release_id: Some(release.id.clone()),
+                confidence: 1.0,
+                metadata: Metadata {
+                    title: Some(track.recording.title.clone()),
+                    artist: credit(&track.recording.artist_credit).or_else(|| album_artist.clone()),
+                    album: Some(release.title.clone()),
+                    track: track.number.parse().ok(),
+                    year: year(release.date.as_deref()),
+                    isrc: track.recording.isrcs.first().cloned(),
+                    musicbrainz_recording_id: Some(track.recording.id.clone()),
+                    musicbrainz_release_id: Some(release.id.clone()),
+                },
+            })
+            .collect())
+    }
+}
+
+///build a lucene query such as `recording:"Title" AND artist:"Artist"`
+fn search_string(query: &Query) -> String {
+    let mut filters = Vec::new();
+    if let Some(title) = &query.title {
+        filters.push(format!("recording:\"{}\"", escape(title)));
+    }
+    if let Some(artist) = &query.artist {
+        filters.push(format!("artist:\"{}\"", escape(artist)));
+    }
+    if let Some(album) = &query.album {
+        filters.push(format!("release:\"{}\"", escape(album)));
+    }
+    if filters.is_empty() {
+        escape(query.filename.as_deref().unwrap_or_default())
+    } else {
+        filters.join(" AND ")
+    }
+}
+
+///escape lucene special characters
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "+-&|!(){}[]^\"~*?:\\/".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+///join an artist credit into a single name, e.g. `Artist feat. Other`
+fn credit(credits: &[ArtistCredit]) -> Option<String> {
+    let name = credits
+        .iter()
+        .flat_map(|c| [c.name.as_str(), c.joinphrase.as_str()])
+        .collect::<String>();
+    (!name.is_empty()).then_some(name)
+}
+
+///dates are YYYY, YYYY-MM or YYYY-MM-DD
+fn year(date: Option<&str>) -> Option<u32> {
+    date.and_then(|d| d.get(..4)).and_then(|y| y.parse().ok())
+}
+
+#[derive(serde::Deserialize)]
+struct SearchResponse {
+    recordings: Vec<Recording>,
+}
+
+#[derive(serde::Deserialize)]
+struct Recording {
+    id: String,
+    #[serde(default)]
+    score: u8,
+    title: String,
+    #[serde(rename = "artist-credit", default)]
+    artist_credit: Vec<ArtistCredit>,
+    #[serde(default)]
+    isrcs: Vec<String>,
+    #[serde(default)]
+    releases: Vec<Release>,
+}
+
+#[derive(serde::Deserialize)]
+struct ArtistCredit {
+    name: String,
+    #[serde(default)]
+    joinphrase: String,
+}
+
+#[derive(serde::Deserialize)]
+struct Release {
+    id: String,
+    title: String,
+    date: Option<String>,
+    #[serde(rename = "artist-credit", default)]
+    artist_credit: Vec<ArtistCredit>,
+    #[serde(default)]
+    media: Vec<Medium>,
+}
+
+///search results list a medium's matching tracks under `track`, lookups list all of them under `tracks`
+#[derive(serde::Deserialize)]
+struct Medium {
+    #[serde(default)]
+    track: Vec<SearchTrack>,
+    #[serde(default)]
+    tracks: Vec<Track>,
+}
+
+#[derive(serde::Deserialize)]
+struct SearchTrack {
+    number: String,
+}
+
+#[derive(serde::Deserialize)]
+struct Track {
+    number: String,
+    recording: Recording,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, time::Instant};
+
+    use super::{search_string, MusicBrainz, RATE_LIMIT, USER_AGENT};
+    use crate::fetch::{
+        http::{self, HttpClient},
+        Error, MetadataBackend, Query,
+    };
+
+    const SEARCH: &str = r#"{"count": 1, "recordings": [{
+        "id": "rec1",
+        "score": 100,
+        "title": "Some Title",
+        "artist-credit": [{"name": "Some Artist", "joinphrase": " feat. "}, {"name": "Other"}],
+        "isrcs": ["USABC0100001"],
+        "releases": [{
+            "id": "rel1",
+            "title": "Some Album",
+            "date": "2001-02-03",
+            "media": [{"track": [{"number": "4"}]}]
+        }]
+    }]}"#;
+    const RELEASE: &str = r#"{
+        "id": "rel1",
+        "title": "Some Album",
+        "date": "2001",
+        "artist-credit": [{"name": "Album Artist"}],
+        "media": [{"tracks": [
+            {"number": "1", "recording": {"id": "rec1", "title": "One"}},
+            {"number": "A2", "recording": {
+                "id": "rec2",
+                "title": "Two",
+                "artist-credit": [{"name": "Guest"}],
+                "isrcs": ["USABC0100002"]
+            }}
+        ]}]
+    }"#;
+
+    ///the url and parameters of every request
+    type Requests = RefCell<Vec<(String, Vec<(String, String)>)>>;
+
+    ///answers every request with `response`, a body or an error status, and records the requests
+    struct Stub {
+        response: Result<&'static str, u16>,
+        requests: Requests,
+    }
+
+    impl Stub {
+        fn new(response: Result<&'static str, u16>) -> Self {
+            Self {
+                response,
+                requests: RefCell::default(),
+            }
+        }
+    }
+
+    impl HttpClient for &Stub {
+        fn get(
+            &self,
+            url: &str,
+            query: &[(&str, &str)],
+            headers: &[(&str, &str)],
+        ) -> Result<String, http::Error> {
+            assert_eq!(headers, [("User-Agent", USER_AGENT)]);
+            let query = query
+                .iter()
+                .map(|(k, v)| ((*k).to_owned(), (*v).to_owned()))
+                .collect();
+            self.requests.borrow_mut().push((url.to_owned(), query));
+            match self.response {
+                Ok(body) => Ok(body.to_owned()),
+                Err(status) => Err(http::Error::Request {
+                    url: url.to_owned(),
+                    error: Box::new(ureq::Error::Status(
+                        status,
+                        ureq::Response::new(status, "Service Unavailable", "").unwrap(),
+                    )),
+                }),
+            }
+        }
+        fn post_form(
+            &self,
+            _url: &str,
+            _form: &[(&str, &str)],
+            _headers: &[(&str, &str)],
+        ) -> Result<String, http::Error> {
+            unreachable!("musicbrainz only sends GET requests")
+        }
+    }
+
+    fn musicbrainz(stub: &Stub) -> MusicBrainz<&Stub> {
+        MusicBrainz::new(stub).with_url("http://mb.test/ws/2".to_owned())
+    }
+
+    fn query() -> Query {
+        Query {
+            title: Some("Some Title".to_owned()),
+            artist: Some("Some Artist feat. Other".to_owned()),
+            ..Query::default()
+        }
+    }
+
+    #[test]
+    fn escapes_lucene_syntax() {
+        let query = Query {
+            title: Some("What? (Live)".to_owned()),
+            album: Some("AC/DC".to_owned()),
+            ..Query::default()
+        };
+        assert_eq!(
+            search_string(&query),
+            r#"recording:"What\? \(Live\)" AND release:"AC\/DC""#
+        );
+    }
+
+    #[test]
+    fn search_parses_recordings() {
+        let stub = Stub::new(Ok(SEARCH));
+        let candidates = musicbrainz(&stub).search(&query()).unwrap();
+        assert_eq!(candidates.len(), 1);
+        let candidate = &candidates[0];
+        assert_eq!(candidate.id, "rec1");
+        assert_eq!(candidate.release_id.as_deref(), Some("rel1"));
+        assert!((candidate.confidence - 1.0).abs() < f32::EPSILON);
+        let metadata = &candidate.metadata;
+        assert_eq!(metadata.title.as_deref(), Some("Some Title"));
+        assert_eq!(metadata.artist.as_deref(), Some("Some Artist feat. Other"));
+        assert_eq!(metadata.album.as_deref(), Some("Some Album"));
+        assert_eq!(metadata.track, Some(4));
+        assert_eq!(metadata.year, Some(2001));
+        assert_eq!(metadata.isrc.as_deref(), Some("USABC0100001"));
+        assert_eq!(metadata.musicbrainz_recording_id.as_deref(), Some("rec1"));
+        assert_eq!(metadata.musicbrainz_release_id.as_deref(), Some("rel1"));
+
+        let requests = stub.requests.borrow();
+        let (url, params) = &requests[0];
+        assert_eq!(url, "http://mb.test/ws/2/recording");
+        assert!(params.contains(&(
+            "query".to_owned(),
+            r#"recording:"Some Title" AND artist:"Some Artist feat. Other""#.to_owned()
+        )));
+        assert!(params.contains(&("fmt".to_owned(), "json".to_owned())));
+    }
+
+    #[test]
+    fn search_without_results_is_empty() {
+        let stub = Stub::new(Ok(r#"{"count": 0, "recordings": []}"#));
+        assert!(musicbrainz(&stub).search(&query()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn release_lists_every_track() {
+        let stub = Stub::new(Ok(RELEASE));
+        let tracks = musicbrainz(&stub).release("rel1").unwrap();
+        assert_eq!(
+            stub.requests.borrow()[0].0,
+            "http://mb.test/ws/2/release/rel1"
+        );
+        let ids = tracks.iter().map(|c| c.id.as_str()).collect::<Vec<_>>();
+        assert_eq!(ids, ["rec1", "rec2"]);
+        //tracks without their own credit fall back to the album artist
+        assert_eq!(tracks[0].metadata.artist.as_deref(), Some("Album Artist"));
+        assert_eq!(tracks[0].metadata.track, Some(1));
+        assert_eq!(tracks[1].metadata.artist.as_deref(), Some("Guest"));
+        //vinyl side numbers aren't track numbers
+        assert_eq!(tracks[1].metadata.track, None);
+        assert_eq!(tracks[1].metadata.year, Some(2001));
+    }
+
+    #[test]
+    fn http_errors_are_returned() {
+        let stub = Stub::new(Err(503));
+        let error = musicbrainz(&stub).search(&query()).unwrap_err();
+        assert!(matches!(error, Error::Http(http::Error::Request { .. })));
+    }
+
+    #[test]
+    fn invalid_responses_are_returned() {
+        let stub = Stub::new(Ok("<html>maintenance</html>"));
+        let error = musicbrainz(&stub).search(&query()).unwrap_err();
+        assert!(matches!(error, Error::Json(_)));
+    }
+
+    #[test]
+    fn requests_are_rate_limited_even_after_errors() {
+        let stub = Stub::new(Err(503));
+        let musicbrainz = musicbrainz(&stub);
+        let start = Instant::now();
+        musicbrainz.search(&query()).unwrap_err();
+        musicbrainz.search(&query()).unwrap_err();
+        assert!(start.elapsed() >= RATE_LIMIT);
+        assert_eq!(stub.requests.borrow().len(), 2);
+    }
+}
@@ -0,0 +1,408 @@
+use std::{
+    cell::RefCell,
+    time::{Duration, Instant},
+};
+
+use super::{
+    http::{HttpClient, UreqClient},
+    Candidate, Error, Metadata, MetadataBackend, Query,
+};
+
+pub const API_URL: &str = "https://api.spotify.com/v1";
+pub const AUTH_URL: &str = "https://accounts.spotify.com";
+
+///the client id and secret of a spotify app
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub id: String,
+    pub secret: String,
+}
+impl Credentials {
+    #[must_use]
+    pub fn new(id: &str, secret: &str) -> Self {
+        Self {
+            id: id.to_owned(),
+            secret: secret.to_owned(),
+        }
+    }
+}
+
+///searches the spotify web api using the client credentials flow
+pub struct Spotify<C: HttpClient = UreqClient> {
+    client: C,
+    credentials: Credentials,
+    api_url: String,
+    auth_url: String,
+    token: RefCell<Option<(String, Instant)>>,
+}
+
+impl Spotify {
+    ///read the client id and secret from `RSPOTIFY_CLIENT_ID` and `RSPOTIFY_CLIENT_SECRET`
+    /// # Errors
+    ///   [`Error::MissingCredentials`]
+    pub fn from_env() -> Result<Self, Error> {
+        let var = |name| std::env::var(name).ok().filter(|v: &String| !v.is_empty());
+        let (Some(id), Some(secret)) = (var("RSPOTIFY_CLIENT_ID"), var("RSPOTIFY_CLIENT_SECRET"))
+        else {
+            return Err(Error::MissingCredentials(
+                "set RSPOTIFY_CLIENT_ID and RSPOTIFY_CLIENT_SECRET",
+            ));
+        };
+        Ok(Self::new(UreqClient::new(), Credentials { id, secret }))
+    }
+}
+
+impl<C: HttpClient> Spotify<C> {
+    pub fn new(client: C, credentials: Credentials) -> Self {
+        Self {
+            client,
+            credentials,
+            api_url: API_URL.to_owned(),
+            auth_url: AUTH_URL.to_owned(),
+            token: RefCell::new(None),
+        }
+    }
+    ///override the api and accounts urls
+    #[must_use]
+    pub fn with_urls(mut self, api_url: String, auth_url: String) -> Self {
+        self.api_url = api_url;
+        self.auth_url = auth_url;
+        self
+    }
+    fn token(&self) -> Result<String, Error> {
+        if let Some((token, expires)) = &*self.token.borrow() {
+            if Instant::now() < *expires {
+                return Ok(token.clone());
+            }
+        }
+        let body = self.client.post_form(
+            &format!("{}/api/token", self.auth_url),
+            &[
+                ("grant_type", "client_credentials"),
+                ("client_id", self.credentials.id.as_str()),
+                ("client_secret", self.credentials.secret.as_str()),
+            ],
+            &[],
+        )?;
+        let token: Token = serde_json::from_str(&body)?;
+        //refresh a little early so a token never expires mid request
+        let expires = Instant::now() + Duration::from_secs(token.expires_in.saturating_sub(60));
+        *self.token.borrow_mut() = Some((token.access_token.clone(), expires));
+        Ok(token.access_token)
+    }
+    fn get(&self, path: &str, query: &[(&str, &str)]) -> Result<String, Error> {
+        let bearer = format!("Bearer {}", self.token()?);
+        Ok(self.client.get(
+            &format!("{}/{path}", self.api_url),
+            query,
+            &[("Authorization", bearer.as_str())],
+        )?)
+    }
+}
+
+impl<C: HttpClient> MetadataBackend for Spotify<C> {
+    fn name(&self) -> &'static str {
+        "spotify"
+    }
+    fn search(&self, query: &Query) -> Result<Vec<Candidate>, Error> {
+        let q = search_string(query);
+        let body = self.get(
+            "search",
+            &[("q", q.as_str()), ("type", "track"), ("limit", "5")],
+        )?;
+        let response: SearchResponse = serde_json::from_str(&body)?;
+        Ok(response
+            .tracks
+            .items
+            .into_iter()
+            .map(|track| track.into_candidate(query))
+            .collect())
+    }
+    fn release(&self, id: &str) -> Result<Vec<Candidate>, Error> {
+        let body = self.get(&format!("albums/{id}"), &[])?;
+        let album: FullAlbum = serde_json::from_str(&body)?;
+        let summary = Album {
+            id: Some(album.id),
+            name: album.name,
+            release_date: album.release_date,
+        };
+        Ok(album
+            .tracks
+            .items
+            .into_iter()
+            .map(|track| {
+                Track {
+                    id: track.id,
+                    name: track.name,
+                    artists: track.artists,
+                    album: summary.clone(),
+                    number: track.track_number,
+                    external_ids: ExternalIds::default(),
+                }
+                //every track of a requested release is an exact match
+                .into_candidate(&Query::default())
+                .with_confidence(1.0)
+            })
+            .collect())
+    }
+}
+
+///build a spotify field filter query such as `track:"Some Title" artist:"Some Artist"`.
+///Values are quoted, otherwise a filter only applies to their first word
+fn search_string(query: &Query) -> String {
+    let mut filters = Vec::new();
+    let fields = [
+        ("track", &query.title),
+        ("artist", &query.artist),
+        ("album", &query.album),
+    ];
+    for (field, value) in fields {
+        if let Some(value) = value {
+            //spotify has no escape for quotes inside a quoted value
+            filters.push(format!("{field}:\"{}\"", value.replace('"', " ")));
+        }
+    }
+    if filters.is_empty() {
+        query.filename.clone().unwrap_or_default()
+    } else {
+        filters.join(" ")
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct Token {
+    access_token: String,
+    expires_in: u64,
+}
+
+#[derive(serde::Deserialize)]
+struct SearchResponse {
+    tracks: Page,
+}
+
+#[derive(serde::Deserialize)]
+struct Page {
+    items: Vec<Track>,
+}
+
+#[derive(serde::Deserialize)]
+struct Track {
+    id: Option<String>,
+    name: String,
+    artists: Vec<Named>,
+    album: Album,
+    #[serde(rename = "track_number")]
+    number: Option<u32>,
+    #[serde(default)]
+    external_ids: ExternalIds,
+}
+
+#[derive(serde::Deserialize)]
+struct Named {
+    name: String,
+}
+
+#[derive(serde::Deserialize, Clone)]
+struct Album {
+    id: Option<String>,
+    name: String,
+    release_date: Option<String>,
+}
+
+#[derive(serde::Deserialize, Default)]
+struct ExternalIds {
+    isrc: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct FullAlbum {
+    id: String,
+    name: String,
+    release_date: Option<String>,
+    tracks: AlbumTracks,
+}
+
+#[derive(serde::Deserialize)]
+struct AlbumTracks {
+    items: Vec<AlbumTrack>,
+}
+
+#[derive(serde::Deserialize)]
+struct AlbumTrack {
+    id: Option<String>,
+    name: String,
+    artists: Vec<Named>,
+    track_number: Option<u32>,
+}
+
+impl Track {
+    fn into_candidate(self, query: &Query) -> Candidate {
+        let artist = self
+            .artists
+            .into_iter()
+            .map(|a| a.name)
+            .collect::<Vec<_>>()
+            .join(", ");
+        let metadata = Metadata {
+            title: Some(self.name),
+            artist: (!artist.is_empty()).then_some(artist),
+            album: Some(self.album.name),
+            track: self.number,
+            //release dates are YYYY, YYYY-MM or YYYY-MM-DD
+            year: self
+                .album
+                .release_date
+                .and_then(|d| d.get(..4).and_then(|y| y.parse().ok())),
+            isrc: self.external_ids.isrc,
+            ..Default::default()
+        };
+        Candidate {
+            id: self.id.unwrap_or_default(),
+            release_id: self.album.id,
+            confidence: query.score(&metadata),
+            metadata,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::{search_string, Credentials, Spotify};
+    use crate::fetch::{
+        http::{Error, HttpClient},
+        MetadataBackend, Query,
+    };
+
+    const TOKEN: &str = r#"{"access_token": "abc", "token_type": "bearer", "expires_in": 3600}"#;
+    const SEARCH: &str = r#"{"tracks": {"items": [{
+        "id": "track1",
+        "name": "Some Title",
+        "artists": [{"name": "Some Artist"}],
+        "album": {"id": "album1", "name": "Some Album", "release_date": "2001-02-03"},
+        "track_number": 4,
+        "external_ids": {"isrc": "USABC0100001"}
+    }]}}"#;
+
+    ///the url and parameters of every request
+    type Requests = RefCell<Vec<(String, Vec<(String, String)>)>>;
+
+    ///answers like the spotify api and records every request
+    #[derive(Default)]
+    struct Stub {
+        requests: Requests,
+    }
+
+    impl Stub {
+        fn record(&self, url: &str, params: &[(&str, &str)]) {
+            let params = params
+                .iter()
+                .map(|(k, v)| ((*k).to_owned(), (*v).to_owned()))
+                .collect();
+            self.requests.borrow_mut().push((url.to_owned(), params));
+        }
+        fn count(&self, url: &str) -> usize {
+            self.requests
+                .borrow()
+                .iter()
+                .filter(|(u, _)| u == url)
+                .count()
+        }
+    }
+
+    impl HttpClient for &Stub {
+        fn get(
+            &self,
+            url: &str,
+            query: &[(&str, &str)],
+            headers: &[(&str, &str)],
+        ) -> Result<String, Error> {
+            self.record(url, query);
+            assert_eq!(headers, [("Authorization", "Bearer abc")]);
+            assert_eq!(url, "http://api.test/search");
+            Ok(SEARCH.to_owned())
+        }
+        fn post_form(
+            &self,
+            url: &str,
+            form: &[(&str, &str)],
+            _headers: &[(&str, &str)],
+        ) -> Result<String, Error> {
+            self.record(url, form);
+            assert_eq!(url, "http://auth.test/api/token");
+            assert!(form.contains(&("grant_type", "client_credentials")));
+            assert!(form.contains(&("client_id", "id")));
+            assert!(form.contains(&("client_secret", "secret")));
+            Ok(TOKEN.to_owned())
+        }
+    }
+
+    fn spotify(stub: &Stub) -> Spotify<&Stub> {
+        Spotify::new(stub, Credentials::new("id", "secret"))
+            .with_urls("http://api.test".to_owned(), "http://auth.test".to_owned())
+    }
+
+    fn query() -> Query {
+        Query {
+            title: Some("Some Title".to_owned()),
+            artist: Some("Some Artist".to_owned()),
+            ..Query::default()
+        }
+    }
+
+    #[test]
+    fn quotes_multi_word_filters() {
+        let mut query = query();
+        query.album = Some("An \"Album\"".to_owned());
+        assert_eq!(
+            search_string(&query),
+            r#"track:"Some Title" artist:"Some Artist" album:"An  Album ""#
+        );
+    }
+
+    #[test]
+    fn searches_with_filename_when_untagged() {
+        let query = Query {
+            filename: Some("01 some file".to_owned()),
+            ..Query::default()
+        };
+        assert_eq!(search_string(&query), "01 some file");
+    }
+
+    #[test]
+    fn search_fetches_a_token_and_parses_tracks() {
+        let stub = Stub::default();
+        let candidates = spotify(&stub).search(&query()).unwrap();
+        assert_eq!(candidates.len(), 1);
+        let candidate = &candidates[0];
+        assert_eq!(candidate.id, "track1");
+        assert_eq!(candidate.release_id.as_deref(), Some("album1"));
+        assert!((candidate.confidence - 1.0).abs() < f32::EPSILON);
+        let metadata = &candidate.metadata;
+        assert_eq!(metadata.title.as_deref(), Some("Some Title"));
+        assert_eq!(metadata.artist.as_deref(), Some("Some Artist"));
+        assert_eq!(metadata.album.as_deref(), Some("Some Album"));
+        assert_eq!(metadata.track, Some(4));
+        assert_eq!(metadata.year, Some(2001));
+        assert_eq!(metadata.isrc.as_deref(), Some("USABC0100001"));
+
+        let requests = stub.requests.borrow();
+        let (_, params) = requests.last().unwrap();
+        assert!(params.contains(&(
+            "q".to_owned(),
+            r#"track:"Some Title" artist:"Some Artist""#.to_owned()
+        )));
+        assert!(params.contains(&("type".to_owned(), "track".to_owned())));
+    }
+
+    #[test]
+    fn reuses_the_token_until_it_expires() {
+        let stub = Stub::default();
+        let spotify = spotify(&stub);
+        spotify.search(&query()).unwrap();
+        spotify.search(&query()).unwrap();
+        assert_eq!(stub.count("http://auth.test/api/token"), 1);
+        assert_eq!(stub.count("http://api.test/search"), 2);
+    }
+}
@@ -0,0 +1,274 @@
+use redb::ReadableTable;
+
+use crate::{
+    db::FINGERPRINTTABLE,
+    song::{MusicDir, Song},
+};
+
+///acoustid only looks at the start of a song
+#[cfg(feature = "fingerprint")]
+const MAX_SECONDS: u64 = 120;
+///chromaprint's `TEST2` algorithm, the default of fpcalc
+#[cfg(feature = "fingerprint")]
+const ALGORITHM: u8 = 1;
+
+///a chromaprint compatible acoustic fingerprint
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Fingerprint {
+    ///length of the whole song in seconds
+    pub duration: u32,
+    pub raw: Vec<u32>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[cfg(feature = "fingerprint")]
+    #[error("unable to decode audio. {0}")]
+    Decode(#[from] symphonia::core::errors::Error),
+    #[error("file contains no audio track")]
+    NoTrack,
+    #[error("unknown sample rate or channel count")]
+    UnknownFormat,
+    #[error("unable to fingerprint audio. {0}")]
+    Fingerprint(String),
+    #[error("bongo was built without the fingerprint feature")]
+    Unsupported,
+}
+
+impl Fingerprint {
+    ///decode the start of `song` and fingerprint it
+    /// # Errors
+    ///   [`Error`] if the audio can't be decoded
+    #[cfg(feature = "fingerprint")]
+    pub fn compute(song: &Song) -> Result<Self, Error> {
+        use lofty::AudioFile;
+        use rusty_chromaprint::{Configuration, Fingerprinter};
+        use symphonia::core::{
+            audio::SampleBuffer, codecs::DecoderOptions, formats::FormatOptions,
+            io::MediaSourceStream, meta::MetadataOptions, probe::Hint,
+        };
+
+        let file = std::fs::File::open(&song.path)?;
+        let stream = MediaSourceStream::new(Box::new(file), std::default::Default::default());
+        let mut hint = Hint::new();
+        if let Some(ext) = song.path.extension().and_then(std::ffi::OsStr::to_str) {
+            hint.with_extension(ext);
+        }
+        let mut format = symphonia::default::get_probe()
+            .format(
+                &hint,
+                stream,
+                &FormatOptions::default(),
+                &MetadataOptions::default(),
+            )?
+            .format;
+        let track = format.default_track().ok_or(Error::NoTrack)?;
+        let track_id = track.id;
+        let sample_rate = track.codec_params.sample_rate.ok_or(Error::UnknownFormat)?;
+        let channels = track
+            .codec_params
+            .channels
+            .ok_or(Error::UnknownFormat)?
+            .count();
+        let mut decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &DecoderOptions::default())?;
+
+        let mut printer = Fingerprinter::new(&Configuration::preset_test2());
+        printer
+            .start(
+                sample_rate,
+                u32::try_from(channels).map_err(|_| Error::UnknownFormat)?,
+            )
+            .map_err(|e| Error::Fingerprint(format!("{e:?}")))?;
+        let max_frames = MAX_SECONDS * u64::from(sample_rate);
+        let mut frames = 0;
+        let mut buffer: Option<SampleBuffer<i16>> = None;
+        while frames < max_frames {
+            let packet = match format.next_packet() {
+                Ok(packet) => packet,
+                Err(symphonia::core::errors::Error::IoError(e))
+                    if e.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    break
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if packet.track_id() != track_id {
+                continue;
+            }
+            let decoded = decoder.decode(&packet)?;
+            let buffer = buffer.get_or_insert_with(|| {
+                SampleBuffer::new(decoded.capacity() as u64, *decoded.spec())
+            });
+            buffer.copy_interleaved_ref(decoded);
+            printer.consume(buffer.samples());
+            frames += (buffer.samples().len() / channels) as u64;
+        }
+        printer.finish();
+        let duration = song.tagged.properties().duration().as_secs();
+        Ok(Self {
+            duration: u32::try_from(duration).unwrap_or(u32::MAX),
+            raw: printer.fingerprint().to_vec(),
+        })
+    }
+    ///always fails, bongo was built without audio decoding
+    /// # Errors
+    ///   [`Error::Unsupported`]
+    #[cfg(not(feature = "fingerprint"))]
+    pub fn compute(_song: &Song) -> Result<Self, Error> {
+        Err(Error::Unsupported)
+    }
+    ///the compressed, base64 encoded form used by fpcalc and acoustid
+    #[cfg(feature = "fingerprint")]
+    #[must_use]
+    pub fn compress(&self) -> String {
+        use base64::Engine;
+
+        let len = self.raw.len();
+        #[allow(clippy::cast_possible_truncation)]
+        let mut bytes = vec![ALGORITHM, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+        //each value is xored with the previous one and stored as the gaps between its set bits.
+        //gaps of 7 or more overflow into a second, 5 bit wide stream
+        let mut normal = Vec::new();
+        let mut exceptional = Vec::new();
+        let mut previous = 0;
+        for &value in &self.raw {
+            let mut diff = value ^ previous;
+            previous = value;
+            let mut bit = 1;
+            let mut last_bit = 0;
+            while diff != 0 {
+                if diff & 1 == 1 {
+                    let gap = bit - last_bit;
+                    if gap >= 7 {
+                        normal.push(7);
+                        exceptional.push(gap - 7);
+                    } else {
+                        normal.push(gap);
+                    }
+                    last_bit = bit;
+                }
+                diff >>= 1;
+                bit += 1;
+            }
+            normal.push(0);
+        }
+        pack_bits(&normal, 3, &mut bytes);
+        pack_bits(&exceptional, 5, &mut bytes);
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+    }
+}
+
+///pack the low `width` bits of each value, least significant bit first
+#[cfg(feature = "fingerprint")]
+fn pack_bits(values: &[u8], width: u32, out: &mut Vec<u8>) {
+    let mut buffer = 0u32;
+    let mut buffered = 0;
+    for &value in values {
+        buffer |= u32::from(value) << buffered;
+        buffered += width;
+        while buffered >= 8 {
+            #[allow(clippy::cast_possible_truncation)]
+            out.push(buffer as u8);
+            buffer >>= 8;
+            buffered -= 8;
+        }
+    }
+    if buffered > 0 {
+        #[allow(clippy::cast_possible_truncation)]
+        out.push(buffer as u8);
+    }
+}
+
+impl MusicDir {
+    ///the stored fingerprint of `song`, computing and storing it if needed
+    pub fn fingerprint(&self, song: &Song, force: bool) -> anyhow::Result<Fingerprint> {
+        let Some(uuid) = &song.uuid else {
+            return Ok(Fingerprint::compute(song)?);
+        };
+        if !force {
+            let reader = self.db.0.begin_read()?;
+            //the table doesn't exist until the first fingerprint is stored
+            if let Ok(table) = reader.open_table(FINGERPRINTTABLE) {
+                if let Some(fingerprint) = table.get(uuid)? {
+                    return Ok(fingerprint.value());
+                }
+            }
+        }
+        tracing::info!("fingerprinting '{}'", song.path.to_string_lossy());
+        let fingerprint = Fingerprint::compute(song)?;
+        let writer = self.db.0.begin_write()?;
+        {
+            let mut table = writer.open_table(FINGERPRINTTABLE)?;
+            table.insert(uuid, &fingerprint)?;
+        }
+        writer.commit()?;
+        Ok(fingerprint)
+    }
+    ///fingerprint every song and store the results in the db
+    pub fn fingerprint_all(&self, force: bool) -> anyhow::Result<()> {
+        for song in &self.songs {
+            if let Err(e) = self.fingerprint(song, force) {
+                tracing::warn!(
+                    "unable to fingerprint '{}'. {e}",
+                    song.path.to_string_lossy()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "fingerprint"))]
+mod tests {
+    use base64::Engine;
+
+    use super::{Fingerprint, ALGORITHM};
+
+    ///the bytes behind [`Fingerprint::compress`]
+    fn compressed(raw: &[u32]) -> Vec<u8> {
+        let fingerprint = Fingerprint {
+            duration: 0,
+            raw: raw.to_vec(),
+        };
+        base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(fingerprint.compress())
+            .unwrap()
+    }
+
+    ///the vectors of chromaprint's own compressor tests, `tests/test_fingerprint_compressor.cpp`
+    #[test]
+    fn matches_chromaprint() {
+        let cases: [(&[u32], &[u8]); 6] = [
+            (&[1], &[1]),
+            (&[7], &[73, 0]),
+            (&[1 << 6], &[7, 0]),
+            (&[1 << 8], &[7, 2]),
+            (&[1, 0], &[65, 0]),
+            (&[1, 1], &[1, 0]),
+        ];
+        for (raw, body) in cases {
+            #[allow(clippy::cast_possible_truncation)]
+            let mut expected = vec![ALGORITHM, 0, 0, raw.len() as u8];
+            expected.extend_from_slice(body);
+            assert_eq!(compressed(raw), expected, "{raw:?}");
+        }
+    }
+
+    #[test]
+    fn header_holds_the_length() {
+        let bytes = compressed(&vec![0; 0x1_0203]);
+        assert_eq!(bytes[..4], [ALGORITHM, 1, 2, 3]);
+    }
+
+    #[test]
+    fn uses_url_safe_base64() {
+        let fingerprint = Fingerprint {
+            duration: 0,
+            raw: vec![1],
+        };
+        assert_eq!(fingerprint.compress(), "AQAAAQE");
+    }
+}
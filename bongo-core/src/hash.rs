@@ -0,0 +1,355 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    ops::Range,
+    path::Path,
+    time::SystemTime,
+};
+
+use lofty::FileType;
+
+use crate::scan;
+
+const ID3V2_HEADER: u64 = 10;
+const ID3V1_SIZE: u64 = 128;
+const APE_FOOTER: u64 = 32;
+const OGG_HEADER: u64 = 27;
+
+///hex encoded blake3 hash of the audio in `path`, leaving out tags so retagging doesn't change it.
+///The format is detected as when reading the song, see [`crate::scan::probe`].
+///Files lofty doesn't know and files that don't parse are hashed whole
+pub fn audio_hash(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let file_type = scan::probe(path).ok().and_then(|probe| probe.file_type());
+    let ranges = match file_type {
+        Some(
+            FileType::Mpeg | FileType::Aac | FileType::Ape | FileType::WavPack | FileType::Mpc,
+        ) => vec![between_tags(&mut file, len)?],
+        Some(FileType::Flac) => vec![flac_audio(&mut file, len)?],
+        Some(FileType::Mp4) => mp4_audio(&mut file, len)?,
+        Some(FileType::Opus | FileType::Vorbis | FileType::Speex) => ogg_audio(&mut file, len)?,
+        Some(FileType::Wav) => iff_audio(&mut file, len, Iff::Riff)?,
+        Some(FileType::Aiff) => iff_audio(&mut file, len, Iff::Aiff)?,
+        _ => vec![0..len],
+    };
+    hash_ranges(&mut file, &ranges)
+}
+
+///size in bytes and modification time in seconds since the unix epoch
+pub(crate) fn stamp(path: &Path) -> io::Result<(u64, u64)> {
+    let metadata = path.metadata()?;
+    let modified = metadata
+        .modified()?
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    Ok((metadata.len(), modified))
+}
+
+///hash the bytes in `ranges` as if they were one stream
+fn hash_ranges(file: &mut File, ranges: &[Range<u64>]) -> io::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0; 64 * 1024];
+    for range in ranges {
+        file.seek(SeekFrom::Start(range.start))?;
+        let mut reader = file.by_ref().take(range.end.saturating_sub(range.start));
+        loop {
+            let read = reader.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+        }
+    }
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+///add `range` to `ranges`, extending the last one if they touch
+fn push_range(ranges: &mut Vec<Range<u64>>, range: Range<u64>) {
+    match ranges.last_mut() {
+        Some(last) if last.end == range.start => last.end = range.end,
+        _ => ranges.push(range),
+    }
+}
+
+fn read_at<const N: usize>(file: &mut File, offset: u64) -> io::Result<[u8; N]> {
+    let mut buffer = [0; N];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+///the length of the id3v2 tag at the start of `file`, 0 if there is none
+fn id3v2_len(file: &mut File, len: u64) -> io::Result<u64> {
+    if len < ID3V2_HEADER {
+        return Ok(0);
+    }
+    let header = read_at::<10>(file, 0)?;
+    if &header[..3] != b"ID3" {
+        return Ok(0);
+    }
+    //the size is stored in 4 bytes of 7 bits each
+    let size = header[6..]
+        .iter()
+        .fold(0u64, |size, byte| (size << 7) | u64::from(byte & 0x7f));
+    let footer = if header[5] & 0x10 == 0 {
+        0
+    } else {
+        ID3V2_HEADER
+    };
+    Ok((ID3V2_HEADER + size + footer).min(len))
+}
+
+///the bytes between a leading id3v2 tag and trailing ape and id3v1 tags,
+///the tags mpeg, aac, monkey's audio, wavpack and musepack files carry
+fn between_tags(file: &mut File, len: u64) -> io::Result<Range<u64>> {
+    let start = id3v2_len(file, len)?;
+    let mut end = len;
+    if end >= start + ID3V1_SIZE && &read_at::<3>(file, end - ID3V1_SIZE)? == b"TAG" {
+        end -= ID3V1_SIZE;
+    }
+    if end >= start + APE_FOOTER {
+        let footer = read_at::<32>(file, end - APE_FOOTER)?;
+        if &footer[..8] == b"APETAGEX" {
+            //the size includes the footer but not the optional header
+            let size = u64::from(u32::from_le_bytes([
+                footer[12], footer[13], footer[14], footer[15],
+            ]));
+            let has_header = footer[23] & 0x80 != 0;
+            let size = size + if has_header { APE_FOOTER } else { 0 };
+            end = end.saturating_sub(size).max(start);
+        }
+    }
+    Ok(start..end)
+}
+
+///the frames after the metadata blocks of a flac file
+fn flac_audio(file: &mut File, len: u64) -> io::Result<Range<u64>> {
+    let mut offset = id3v2_len(file, len)?;
+    if offset + 4 > len || &read_at::<4>(file, offset)? != b"fLaC" {
+        return Ok(0..len);
+    }
+    offset += 4;
+    while offset + 4 <= len {
+        let header = read_at::<4>(file, offset)?;
+        let size = u64::from(u32::from_be_bytes([0, header[1], header[2], header[3]]));
+        offset += 4 + size;
+        //the high bit marks the last metadata block
+        if header[0] & 0x80 != 0 {
+            break;
+        }
+    }
+    Ok(offset.min(len)..len)
+}
+
+///the payload of the mdat boxes of an mp4 file. Tags live in moov, along with sample offsets
+///that change whenever the tags grow, so nothing else is hashed
+fn mp4_audio(file: &mut File, len: u64) -> io::Result<Vec<Range<u64>>> {
+    let mut ranges = Vec::new();
+    let mut offset = 0;
+    while offset + 8 <= len {
+        let header = read_at::<8>(file, offset)?;
+        let mut size = u64::from(u32::from_be_bytes([
+            header[0], header[1], header[2], header[3],
+        ]));
+        let mut header_len = 8;
+        match size {
+            //a 64 bit size follows the name
+            1 if offset + 16 <= len => {
+                size = u64::from_be_bytes(read_at::<8>(file, offset + 8)?);
+                header_len = 16;
+            }
+            //the box extends to the end of the file
+            0 => size = len - offset,
+            _ => {}
+        }
+        if size < header_len {
+            return Ok(vec![0..len]);
+        }
+        let end = offset.saturating_add(size).min(len);
+        if &header[4..] == b"mdat" {
+            ranges.push(offset + header_len..end);
+        }
+        offset = end;
+    }
+    if ranges.is_empty() {
+        return Ok(vec![0..len]);
+    }
+    Ok(ranges)
+}
+
+///the packets of an ogg file except the comment header, which is the second packet of every stream.
+///Page headers are left out too, pages are renumbered when the comments grow
+fn ogg_audio(file: &mut File, len: u64) -> io::Result<Vec<Range<u64>>> {
+    let mut ranges = Vec::new();
+    //packets seen so far in each stream, by serial number
+    let mut packets = HashMap::<u32, usize>::new();
+    let mut offset = 0;
+    while offset + OGG_HEADER <= len {
+        let header = read_at::<27>(file, offset)?;
+        if &header[..4] != b"OggS" {
+            return Ok(vec![0..len]);
+        }
+        let serial = u32::from_le_bytes([header[14], header[15], header[16], header[17]]);
+        let mut data = offset + OGG_HEADER + u64::from(header[26]);
+        //a truncated page, hash what is there
+        if data > len {
+            push_range(&mut ranges, offset..len);
+            break;
+        }
+        //the lacing values follow the header, each the length of a segment.
+        //A value below 255 ends a packet
+        let mut lacing = vec![0; usize::from(header[26])];
+        file.read_exact(&mut lacing)?;
+        let packet = packets.entry(serial).or_default();
+        for lace in lacing {
+            let end = data + u64::from(lace);
+            if *packet != 1 {
+                push_range(&mut ranges, data..end);
+            }
+            if lace < 255 {
+                *packet += 1;
+            }
+            data = end;
+        }
+        offset = data;
+    }
+    Ok(ranges)
+}
+
+///the layout of an interchange file format container
+#[derive(Clone, Copy)]
+enum Iff {
+    ///riff wave, little endian
+    Riff,
+    ///aiff and aiff-c, big endian
+    Aiff,
+}
+
+///the format and sample chunks of a wave or aiff file, tags live in chunks of their own
+fn iff_audio(file: &mut File, len: u64, iff: Iff) -> io::Result<Vec<Range<u64>>> {
+    if len < 12 {
+        return Ok(vec![0..len]);
+    }
+    let header = read_at::<12>(file, 0)?;
+    let (magic, forms, keep): (&[u8; 4], &[&[u8; 4]], [&[u8; 4]; 2]) = match iff {
+        Iff::Riff => (b"RIFF", &[b"WAVE"], [b"fmt ", b"data"]),
+        Iff::Aiff => (b"FORM", &[b"AIFF", b"AIFC"], [b"COMM", b"SSND"]),
+    };
+    if header[..4] != magic[..] || !forms.iter().any(|form| header[8..] == form[..]) {
+        return Ok(vec![0..len]);
+    }
+    let mut ranges = Vec::new();
+    let mut offset = 12;
+    while offset + 8 <= len {
+        let chunk = read_at::<8>(file, offset)?;
+        let size = [chunk[4], chunk[5], chunk[6], chunk[7]];
+        let size = u64::from(match iff {
+            Iff::Riff => u32::from_le_bytes(size),
+            Iff::Aiff => u32::from_be_bytes(size),
+        });
+        let end = (offset + 8 + size).min(len);
+        if keep.iter().any(|id| chunk[..4] == id[..]) {
+            ranges.push(offset..end);
+        }
+        //chunks are padded to an even length
+        offset = end + size % 2;
+    }
+    if ranges.is_empty() {
+        return Ok(vec![0..len]);
+    }
+    Ok(ranges)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use lofty::{Accessor, AudioFile, TaggedFileExt};
+
+    use super::audio_hash;
+    use crate::test_util;
+
+    ///give `path` a tag long enough to move the audio, spanning several ogg pages
+    fn retag(path: &Path) {
+        let mut file = lofty::read_from_path(path).unwrap();
+        if file.primary_tag().is_none() {
+            file.insert_tag(lofty::Tag::new(file.primary_tag_type()));
+        }
+        let tag = file.primary_tag_mut().unwrap();
+        tag.set_title("a long title ".repeat(1000));
+        tag.set_comment("a comment".to_owned());
+        file.save_to_path(path).unwrap();
+    }
+
+    ///flip the byte at `offset` in `path`, counting from the end if negative
+    fn corrupt(path: &Path, offset: isize) {
+        let mut bytes = std::fs::read(path).unwrap();
+        let i = usize::try_from(offset).unwrap_or_else(|_| bytes.len() - offset.unsigned_abs());
+        bytes[i] ^= 0xFF;
+        std::fs::write(path, bytes).unwrap();
+    }
+
+    ///tagging `name` keeps its audio hash, changing the audio byte at `offset` doesn't
+    fn check(name: &str, offset: Option<isize>) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = test_util::song(dir.path(), name);
+        let hash = audio_hash(&path).unwrap();
+        let contents = hash_file(&path).unwrap();
+
+        test_util::tagged_song(dir.path(), name, "Title", "Artist");
+        assert_ne!(hash_file(&path).unwrap(), contents);
+        assert_eq!(audio_hash(&path).unwrap(), hash, "{name} after tagging");
+        retag(&path);
+        assert_eq!(audio_hash(&path).unwrap(), hash, "{name} after retagging");
+
+        if let Some(offset) = offset {
+            let path = test_util::song(dir.path(), name);
+            corrupt(&path, offset);
+            assert_ne!(audio_hash(&path).unwrap(), hash, "{name} after corruption");
+        }
+    }
+
+    #[test]
+    fn mpeg_tags_are_left_out() {
+        check("song.mp3", Some(1000));
+    }
+
+    #[test]
+    fn flac_tags_are_left_out() {
+        //the fixture has no frames to corrupt
+        check("song.flac", None);
+    }
+
+    #[test]
+    fn mp4_tags_are_left_out() {
+        //inside mdat, which follows the 28 byte ftyp box and its own 8 byte header
+        check("song.m4a", Some(40));
+    }
+
+    #[test]
+    fn ape_tags_are_left_out() {
+        check("song.ape", Some(-1));
+    }
+
+    #[test]
+    fn ogg_comments_are_left_out() {
+        check("song.opus", Some(-1));
+    }
+
+    #[test]
+    fn wav_tags_are_left_out() {
+        check("song.wav", Some(-1));
+    }
+
+    #[test]
+    fn unknown_formats_are_hashed_whole() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("song.xyz");
+        std::fs::write(&path, b"some bytes").unwrap();
+        let whole = blake3::hash(b"some bytes").to_hex().to_string();
+        assert_eq!(audio_hash(&path).unwrap(), whole);
+    }
+}
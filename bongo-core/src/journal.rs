@@ -0,0 +1,306 @@
+use std::path::{Path, PathBuf};
+
+use redb::ReadableTable;
+use relative_path::RelativePath;
+
+use crate::{
+    cache::now,
+    db::{Database, SongUuid, JOURNALTABLE},
+    hash,
+    song::MusicDir,
+    sort::Mode,
+    transfer,
+};
+
+///a sort recorded in the db so it can be undone
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct JournalEntry {
+    ///seconds since the unix epoch
+    pub timestamp: u64,
+    pub mode: Mode,
+    ///where files were copied to, the music dir itself for moves
+    pub destination: PathBuf,
+    pub moves: Vec<JournaledMove>,
+    pub undone: bool,
+    ///the sort left the db alone, so undoing it does too
+    #[serde(default)]
+    pub ignore_db: bool,
+    ///the sort created [`JournalEntry::destination`], so undoing it removes it once empty
+    pub created_destination: bool,
+}
+
+///a single file moved or copied by a sort
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct JournaledMove {
+    pub uuid: Option<SongUuid>,
+    ///relative to the music dir
+    pub old_path: RelativePath,
+    ///relative to [`JournalEntry::destination`]
+    pub new_path: RelativePath,
+    ///audio hash of a copy when it was made, see [`crate::hash::audio_hash`].
+    ///Undoing leaves copies that no longer match, `None` for moves
+    pub audio_hash: Option<String>,
+}
+
+impl Database {
+    ///add a sort to the journal. Returns its operation id
+    pub fn record_sort(
+        &self,
+        mode: Mode,
+        destination: &Path,
+        moves: Vec<JournaledMove>,
+        ignore_db: bool,
+        created_destination: bool,
+    ) -> anyhow::Result<u64> {
+        let entry = JournalEntry {
+            timestamp: now(),
+            mode,
+            destination: destination.to_path_buf(),
+            moves,
+            undone: false,
+            ignore_db,
+            created_destination,
+        };
+        let writer = self.0.begin_write()?;
+        let id = {
+            let mut table = writer.open_table(JOURNALTABLE)?;
+            let id = match table.iter()?.next_back() {
+                Some(last) => last?.0.value() + 1,
+                None => 1,
+            };
+            table.insert(id, &entry)?;
+            id
+        };
+        writer.commit()?;
+        Ok(id)
+    }
+    ///every recorded operation, oldest first
+    pub fn history(&self) -> anyhow::Result<Vec<(u64, JournalEntry)>> {
+        let reader = self.0.begin_read()?;
+        //the table doesn't exist until the first sort is recorded
+        let Ok(table) = reader.open_table(JOURNALTABLE) else {
+            return Ok(Vec::new());
+        };
+        let mut history = Vec::new();
+        for entry in table.iter()? {
+            let (id, entry) = entry?;
+            history.push((id.value(), entry.value()));
+        }
+        Ok(history)
+    }
+}
+
+impl MusicDir {
+    ///reverse operation `id`, or the most recent one that hasn't been undone.
+    ///Songs moved back are updated in the db, unless the sort ignored it.
+    ///Copies that changed since the sort are left, as are directories that aren't empty.
+    ///Returns the id of the undone operation
+    pub fn undo(&mut self, id: Option<u64>) -> anyhow::Result<u64> {
+        let history = self.db.history()?;
+        let (id, mut entry) = match id {
+            Some(id) => history
+                .into_iter()
+                .find(|(i, _)| *i == id)
+                .ok_or_else(|| anyhow::anyhow!("there is no operation {id}"))?,
+            None => history
+                .into_iter()
+                .rev()
+                .find(|(_, e)| !e.undone)
+                .ok_or_else(|| anyhow::anyhow!("there is nothing to undo"))?,
+        };
+        if entry.undone {
+            anyhow::bail!("operation {id} has already been undone");
+        }
+        for journaled in entry.moves.iter().rev() {
+            let new_path = journaled.new_path.rebase(entry.destination.clone());
+            let old_path = journaled.old_path.rebase(self.root.clone());
+            if !new_path.exists() {
+                tracing::warn!(
+                    "'{}' no longer exists, leaving it",
+                    new_path.to_string_lossy()
+                );
+                continue;
+            }
+            match entry.mode {
+                Mode::Copy => {
+                    if let Some(copied) = &journaled.audio_hash {
+                        if hash::audio_hash(&new_path)? != *copied {
+                            tracing::warn!(
+                                "'{}' has changed since it was copied, leaving it",
+                                new_path.to_string_lossy()
+                            );
+                            continue;
+                        }
+                    }
+                    tracing::info!("removing '{}'", new_path.to_string_lossy());
+                    std::fs::remove_file(&new_path)?;
+                    remove_empty_parents(&new_path, &entry.destination);
+                }
+                Mode::Move => {
+                    if old_path.exists() {
+                        tracing::warn!(
+                            "'{}' has been replaced, leaving '{}'",
+                            old_path.to_string_lossy(),
+                            new_path.to_string_lossy()
+                        );
+                        continue;
+                    }
+                    tracing::info!(
+                        "moving '{}' back to '{}'",
+                        new_path.to_string_lossy(),
+                        old_path.to_string_lossy()
+                    );
+                    std::fs::create_dir_all(old_path.parent().unwrap())?;
+                    transfer::move_file(&new_path, &old_path)?;
+                    if let Some(song) = self.songs.iter_mut().find(|s| s.path == new_path) {
+                        song.path = old_path;
+                    }
+                }
+            }
+        }
+        if entry.mode == Mode::Copy
+            && entry.created_destination
+            && std::fs::remove_dir(&entry.destination).is_ok()
+        {
+            tracing::info!(
+                "removed empty directory '{}'",
+                entry.destination.to_string_lossy()
+            );
+        }
+        entry.undone = true;
+        let writer = self.db.0.begin_write()?;
+        writer.open_table(JOURNALTABLE)?.insert(id, &entry)?;
+        writer.commit()?;
+        if entry.mode == Mode::Move && !entry.ignore_db {
+            self.update(false)?;
+        }
+        Ok(id)
+    }
+}
+
+///remove the directories between `path` and `root` left empty by removing `path`
+fn remove_empty_parents(path: &Path, root: &Path) {
+    for dir in path.ancestors().skip(1) {
+        if dir == root || !dir.starts_with(root) {
+            break;
+        }
+        //fails on the first directory that still has files
+        if std::fs::remove_dir(dir).is_err() {
+            break;
+        }
+        tracing::info!("removed empty directory '{}'", dir.to_string_lossy());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use redb::ReadableTable;
+
+    use crate::{
+        db::SONGTABLE,
+        scan::Scan,
+        song::MusicDir,
+        sort::{OnCollision, SortOptions},
+        template::Template,
+        test_util,
+    };
+
+    fn options() -> SortOptions {
+        SortOptions {
+            template: "{artist}/{title}.{ext}".parse::<Template>().unwrap(),
+            on_collision: OnCollision::Abort,
+            ..SortOptions::default()
+        }
+    }
+
+    fn init(root: &Path) -> MusicDir {
+        MusicDir::init(root.to_path_buf(), false, Scan::default()).unwrap()
+    }
+
+    ///the paths stored in the db, sorted
+    fn stored_paths(music_dir: &MusicDir) -> Vec<String> {
+        let reader = music_dir.db.0.begin_read().unwrap();
+        let table = reader.open_table(SONGTABLE).unwrap();
+        let mut paths = table
+            .iter()
+            .unwrap()
+            .map(|e| e.unwrap().1.value().old_path.to_string())
+            .collect::<Vec<_>>();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn undoing_a_move_restores_files_and_the_db() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = test_util::tagged_song(dir.path(), "rip/a.mp3", "Title", "Artist");
+        let mut music_dir = init(dir.path());
+        music_dir.sort(None, false, false, &options()).unwrap();
+        assert_eq!(stored_paths(&music_dir), ["Artist/Title.mp3"]);
+
+        assert_eq!(music_dir.undo(None).unwrap(), 1);
+        assert!(a.exists());
+        assert!(!dir.path().join("Artist/Title.mp3").exists());
+        assert_eq!(stored_paths(&music_dir), ["rip/a.mp3"]);
+        assert!(music_dir.db.history().unwrap()[0].1.undone);
+        let error = music_dir.undo(None).unwrap_err();
+        assert!(error.to_string().contains("nothing to undo"), "{error}");
+    }
+
+    #[test]
+    fn undoing_a_move_leaves_replaced_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        test_util::tagged_song(dir.path(), "a.mp3", "Title", "Artist");
+        let mut music_dir = init(dir.path());
+        music_dir.sort(None, true, false, &options()).unwrap();
+        std::fs::write(dir.path().join("a.mp3"), "new").unwrap();
+
+        music_dir.undo(Some(1)).unwrap();
+        assert_eq!(std::fs::read(dir.path().join("a.mp3")).unwrap(), b"new");
+        assert!(dir.path().join("Artist/Title.mp3").exists());
+    }
+
+    #[test]
+    fn undoing_a_copy_removes_the_copies_and_their_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let copies = tempfile::tempdir().unwrap();
+        let destination = copies.path().join("copy");
+        let a = test_util::tagged_song(dir.path(), "a.mp3", "Title", "Artist");
+        let mut music_dir = init(dir.path());
+        music_dir
+            .sort(Some(destination.clone()), false, false, &options())
+            .unwrap();
+        assert!(destination.join("Artist/Title.mp3").exists());
+
+        music_dir.undo(None).unwrap();
+        assert!(a.exists());
+        assert!(!destination.exists());
+    }
+
+    #[test]
+    fn undoing_a_copy_leaves_changed_copies_and_existing_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        test_util::tagged_song(dir.path(), "a.mp3", "a", "Artist");
+        test_util::tagged_song(dir.path(), "b.mp3", "b", "Artist");
+        let mut music_dir = init(dir.path());
+        music_dir
+            .sort(
+                Some(destination.path().to_path_buf()),
+                false,
+                false,
+                &options(),
+            )
+            .unwrap();
+        //retagging keeps the audio, replacing it doesn't
+        test_util::tagged_song(destination.path(), "Artist/a.mp3", "retagged", "Artist");
+        std::fs::write(destination.path().join("Artist/b.mp3"), "replaced").unwrap();
+
+        music_dir.undo(None).unwrap();
+        assert!(!destination.path().join("Artist/a.mp3").exists());
+        assert!(destination.path().join("Artist/b.mp3").exists());
+        assert!(destination.path().exists());
+    }
+}
@@ -6,11 +6,26 @@
     clippy::style
 )]
 #![allow(clippy::module_name_repetitions)]
+pub mod cache;
 pub mod db;
 mod error;
 pub use error::Error;
+pub mod fetch;
+pub mod fingerprint;
+pub mod hash;
+pub mod journal;
+pub mod progress;
+pub mod sanitize;
+pub mod scan;
 pub mod song;
-mod sort;
+pub mod sort;
+pub mod sync;
+pub mod template;
+#[cfg(test)]
+mod test_util;
+pub mod transcode;
+mod transfer;
+pub mod verify;
 pub mod rexports {
     pub use redb;
 }
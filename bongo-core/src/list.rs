@@ -2,8 +2,8 @@ use lofty::{Accessor, TaggedFileExt};
 
 pub fn list(song: &lofty::TaggedFile) -> Option<String> {
     let Some(tag) = song.tag(song.primary_tag_type()) else {
-            return None
-                        };
+        return None;
+    };
     let title = if let Some(title) = tag.title() {
         format!("'{title}'")
     } else {
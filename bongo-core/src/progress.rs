@@ -0,0 +1,79 @@
+use redb::ReadableTable;
+
+use crate::{
+    db::{Database, SORTPLANTABLE, SORTSTEPTABLE},
+    sort::Plan,
+};
+
+///the only key of [`SORTPLANTABLE`], there is at most one unfinished sort
+const CURRENT: &str = "current";
+
+///how far a step of a stored [`Plan`] got
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum StepStatus {
+    Pending,
+    Done,
+    ///both its source and destination disappeared while the sort was interrupted
+    Skipped,
+}
+
+impl Database {
+    ///store `plan` with every step pending, before any file is touched
+    pub fn start_sort(&self, plan: &Plan) -> anyhow::Result<()> {
+        if self.unfinished_sort()?.is_some() {
+            anyhow::bail!("an interrupted sort has not finished. Continue it with --resume or discard it with --abandon");
+        }
+        let writer = self.0.begin_write()?;
+        {
+            writer.open_table(SORTPLANTABLE)?.insert(CURRENT, plan)?;
+            let mut steps = writer.open_table(SORTSTEPTABLE)?;
+            for i in 0..plan.steps.len() as u64 {
+                steps.insert(i, &StepStatus::Pending)?;
+            }
+        }
+        writer.commit()?;
+        Ok(())
+    }
+    ///the plan of a sort that was interrupted and the status of each of its steps
+    pub fn unfinished_sort(&self) -> anyhow::Result<Option<(Plan, Vec<StepStatus>)>> {
+        let reader = self.0.begin_read()?;
+        //the tables don't exist until the first sort
+        let (Ok(plans), Ok(steps)) = (
+            reader.open_table(SORTPLANTABLE),
+            reader.open_table(SORTSTEPTABLE),
+        ) else {
+            return Ok(None);
+        };
+        let Some(plan) = plans.get(CURRENT)? else {
+            return Ok(None);
+        };
+        let plan = plan.value();
+        let mut status = vec![StepStatus::Pending; plan.steps.len()];
+        for entry in steps.iter()? {
+            let (i, step) = entry?;
+            if let Some(s) = usize::try_from(i.value())
+                .ok()
+                .and_then(|i| status.get_mut(i))
+            {
+                *s = step.value();
+            }
+        }
+        Ok(Some((plan, status)))
+    }
+    pub fn mark_step(&self, step: usize, status: StepStatus) -> anyhow::Result<()> {
+        let writer = self.0.begin_write()?;
+        writer
+            .open_table(SORTSTEPTABLE)?
+            .insert(step as u64, &status)?;
+        writer.commit()?;
+        Ok(())
+    }
+    ///forget the stored plan once every step is done, or the sort is abandoned
+    pub fn finish_sort(&self) -> anyhow::Result<()> {
+        let writer = self.0.begin_write()?;
+        writer.delete_table(SORTPLANTABLE)?;
+        writer.delete_table(SORTSTEPTABLE)?;
+        writer.commit()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,260 @@
+use relative_path::RelativePath;
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+///characters FAT, exFAT and NTFS refuse in file names
+const WINDOWS_RESERVED_CHARS: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+const WINDOWS_RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+///extensions longer than this are treated as part of the file name when truncating
+const MAX_EXTENSION: usize = 10;
+///the shortest [`Sanitizer::max_len`] that leaves room for a file name next to any extension
+pub const MIN_LEN: usize = 16;
+
+///which file systems sorted paths must be valid on
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    ///only `/` and control characters are replaced
+    #[default]
+    Posix,
+    ///also valid on FAT, exFAT and NTFS music players
+    Windows,
+    ///like windows, with non ascii characters transliterated
+    Ascii,
+}
+
+///turns tag values into safe path components
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sanitizer {
+    pub profile: Profile,
+    ///maximum length of a single component in bytes, at least [`MIN_LEN`]
+    pub max_len: usize,
+}
+
+impl Default for Sanitizer {
+    fn default() -> Self {
+        Self {
+            profile: Profile::default(),
+            max_len: 255,
+        }
+    }
+}
+
+impl Sanitizer {
+    ///sanitize every component, the last one is treated as a file name
+    #[must_use]
+    pub fn path(&self, components: Vec<String>) -> RelativePath {
+        let last = components.len().saturating_sub(1);
+        components
+            .into_iter()
+            .enumerate()
+            .map(|(i, c)| self.component(&c, i == last))
+            .collect()
+    }
+    ///make `name` a single, visible path component valid for the profile
+    #[must_use]
+    pub fn component(&self, name: &str, is_file: bool) -> String {
+        let name = match self.profile {
+            Profile::Ascii => transliterate(name),
+            Profile::Posix | Profile::Windows => name.to_owned(),
+        };
+        let mut clean = name
+            .chars()
+            .map(|c| {
+                let reserved = match self.profile {
+                    Profile::Posix => c == '/',
+                    Profile::Windows | Profile::Ascii => WINDOWS_RESERVED_CHARS.contains(&c),
+                };
+                if reserved || c.is_control() {
+                    '_'
+                } else {
+                    c
+                }
+            })
+            .collect::<String>();
+        clean = clean.trim_end_matches(is_trailing).trim_start().to_owned();
+        //a leading dot would hide the file from bongo, `.` and `..` would escape the directory
+        if clean.starts_with('.') {
+            clean.replace_range(..1, "_");
+        }
+        if self.profile != Profile::Posix {
+            let stem = clean.split('.').next().unwrap_or_default();
+            let stem_len = stem.len();
+            if WINDOWS_RESERVED_NAMES
+                .iter()
+                .any(|r| r.eq_ignore_ascii_case(stem))
+            {
+                clean.insert(stem_len, '_');
+            }
+        }
+        if clean.is_empty() {
+            clean.push('_');
+        }
+        self.truncate(clean, is_file)
+    }
+    ///limit `name` to `max_len` bytes, keeping a file's extension
+    fn truncate(&self, mut name: String, is_file: bool) -> String {
+        if name.len() <= self.max_len {
+            return name;
+        }
+        //the extension is only kept if at least one character of the name fits before it
+        let first = name.chars().next().map_or(0, char::len_utf8);
+        let extension = name
+            .rfind('.')
+            .filter(|&dot| is_file && name.len() - dot <= MAX_EXTENSION + 1)
+            .filter(|&dot| first + name.len() - dot <= self.max_len)
+            .map(|dot| name.split_off(dot))
+            .unwrap_or_default();
+        let mut end = self.max_len - extension.len();
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        name.truncate(end);
+        let mut name = name.trim_end_matches(is_trailing).to_owned();
+        name.push_str(&extension);
+        name
+    }
+}
+
+///windows silently drops trailing dots and spaces
+fn is_trailing(c: char) -> bool {
+    c == '.' || c == ' '
+}
+
+///strip accents and replace the remaining non ascii characters
+fn transliterate(s: &str) -> String {
+    let mut ascii = String::with_capacity(s.len());
+    for c in s.nfd().filter(|c| !is_combining_mark(*c)) {
+        match c {
+            c if c.is_ascii() => ascii.push(c),
+            'ß' => ascii.push_str("ss"),
+            'æ' => ascii.push_str("ae"),
+            'Æ' => ascii.push_str("AE"),
+            'œ' => ascii.push_str("oe"),
+            'Œ' => ascii.push_str("OE"),
+            'ø' => ascii.push('o'),
+            'Ø' => ascii.push('O'),
+            'đ' | 'ð' => ascii.push('d'),
+            'Đ' | 'Ð' => ascii.push('D'),
+            'ł' => ascii.push('l'),
+            'Ł' => ascii.push('L'),
+            'þ' => ascii.push_str("th"),
+            'Þ' => ascii.push_str("Th"),
+            '‘' | '’' => ascii.push('\''),
+            '“' | '”' => ascii.push('"'),
+            '–' | '—' => ascii.push('-'),
+            _ => ascii.push('_'),
+        }
+    }
+    ascii
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Profile, Sanitizer, MIN_LEN};
+
+    fn sanitizer(profile: Profile, max_len: usize) -> Sanitizer {
+        Sanitizer { profile, max_len }
+    }
+
+    #[test]
+    fn replaces_reserved_characters() {
+        let posix = Sanitizer::default();
+        assert_eq!(posix.component("AC/DC: Live?", false), "AC_DC: Live?");
+        assert_eq!(posix.component("tab\there", false), "tab_here");
+        let windows = sanitizer(Profile::Windows, 255);
+        assert_eq!(windows.component("AC/DC: Live?", false), "AC_DC_ Live_");
+        assert_eq!(
+            windows.component(r#"a<b>c"d\e|f*g"#, false),
+            "a_b_c_d_e_f_g"
+        );
+    }
+
+    #[test]
+    fn renames_reserved_names() {
+        let windows = sanitizer(Profile::Windows, 255);
+        assert_eq!(windows.component("CON", false), "CON_");
+        assert_eq!(windows.component("con.mp3", true), "con_.mp3");
+        assert_eq!(windows.component("Lpt9.tar.gz", true), "Lpt9_.tar.gz");
+        assert_eq!(windows.component("CONSOLE", false), "CONSOLE");
+        assert_eq!(Sanitizer::default().component("CON", false), "CON");
+    }
+
+    #[test]
+    fn strips_trailing_dots_and_spaces() {
+        let posix = Sanitizer::default();
+        assert_eq!(posix.component("Album. . ", false), "Album");
+        assert_eq!(posix.component("  Title", false), "Title");
+        assert_eq!(posix.component("Vol. 1", false), "Vol. 1");
+    }
+
+    #[test]
+    fn never_hides_or_escapes() {
+        let posix = Sanitizer::default();
+        assert_eq!(posix.component(".hidden", false), "_hidden");
+        assert_eq!(posix.component(".", false), "_");
+        assert_eq!(posix.component("..", false), "_");
+        assert_eq!(posix.component("", false), "_");
+        assert_eq!(posix.component("   ", false), "_");
+    }
+
+    #[test]
+    fn transliterates_to_ascii() {
+        let ascii = sanitizer(Profile::Ascii, 255);
+        assert_eq!(ascii.component("Beyoncé", false), "Beyonce");
+        assert_eq!(
+            ascii.component("Ærøskøbing straße", false),
+            "AEroskobing strasse"
+        );
+        assert_eq!(ascii.component("“Quote” – 日本", false), "_Quote_ - __");
+    }
+
+    #[test]
+    fn truncates_on_char_boundaries() {
+        let short = sanitizer(Profile::Posix, MIN_LEN);
+        //11 two byte characters and the extension don't fit in 16 bytes
+        assert_eq!(short.component("ééééééééééé.flac", true), "ééééé.flac");
+        assert_eq!(short.component("éééééééééé", false), "éééééééé");
+        assert_eq!(short.component("日本語日本語日本語", false), "日本語日本");
+    }
+
+    #[test]
+    fn truncation_keeps_extensions_of_files_only() {
+        let short = sanitizer(Profile::Posix, MIN_LEN);
+        assert_eq!(
+            short.component("abcdefghijklmnop.mp3", true),
+            "abcdefghijkl.mp3"
+        );
+        assert_eq!(
+            short.component("abcdefghijklmnop.mp3", false),
+            "abcdefghijklmnop"
+        );
+        //too long to be an extension
+        assert_eq!(
+            short.component("a.verylongextension", true),
+            "a.verylongextens"
+        );
+    }
+
+    #[test]
+    fn truncation_strips_what_windows_would_drop() {
+        let short = sanitizer(Profile::Posix, MIN_LEN);
+        assert_eq!(
+            short.component("abcdefghij   xyz.mp3", true),
+            "abcdefghij.mp3"
+        );
+    }
+
+    #[test]
+    fn truncation_keeps_part_of_the_name() {
+        //below the minimum the extension is dropped instead of the whole name
+        let tiny = sanitizer(Profile::Posix, 4);
+        assert_eq!(tiny.component("日本語.flac", true), "日");
+        assert_eq!(tiny.component("abcdef.flac", true), "abcd");
+        let small = sanitizer(Profile::Posix, 7);
+        assert_eq!(small.component("abcdef.flac", true), "ab.flac");
+    }
+}
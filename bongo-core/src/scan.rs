@@ -0,0 +1,165 @@
+use std::{collections::BTreeMap, fs::File, io::BufReader, path::Path};
+
+use lofty::{FileType, Probe};
+
+///extensions of files that are common in music dirs but never songs, so their contents aren't checked
+const NOT_MUSIC: [&str; 20] = [
+    "jpg", "jpeg", "png", "gif", "bmp", "webp", "cue", "log", "lrc", "txt", "nfo", "md", "pdf",
+    "m3u", "m3u8", "pls", "sfv", "md5", "json", "db",
+];
+
+///which files in a music dir are songs
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Scan {
+    ///extensions that are always read as songs, e.g. ones lofty can only recognize by their contents
+    pub include: Vec<String>,
+    ///extensions that are never read as songs
+    pub exclude: Vec<String>,
+}
+
+impl Scan {
+    ///whether `path` is a song. Files with an extension lofty supports are, unless excluded.
+    ///Files with an unknown extension or none are recognized by their contents
+    #[must_use]
+    pub fn is_music_file(&self, path: &Path) -> bool {
+        if !path.is_file() {
+            return false;
+        }
+        let ext = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+        if let Some(ext) = &ext {
+            if contains(&self.exclude, ext) {
+                return false;
+            }
+            if contains(&self.include, ext) || FileType::from_ext(ext).is_some() {
+                return true;
+            }
+            if NOT_MUSIC.contains(&ext.as_str()) {
+                return false;
+            }
+        }
+        sniff(path)
+    }
+}
+
+///whether `list` contains `ext`, ignoring case and a leading dot
+fn contains(list: &[String], ext: &str) -> bool {
+    list.iter()
+        .any(|e| e.trim_start_matches('.').eq_ignore_ascii_case(ext))
+}
+
+///whether lofty recognizes the contents of `path`
+fn sniff(path: &Path) -> bool {
+    probe(path).is_ok_and(|probe| probe.file_type().is_some())
+}
+
+///open `path` for lofty with its format detected by its contents, or by its extension if they don't tell.
+///Reading songs and hashing their audio both go through this, so they agree on the format
+pub(crate) fn probe(path: &Path) -> lofty::Result<Probe<BufReader<File>>> {
+    Ok(Probe::open(path)?.guess_file_type()?)
+}
+
+///files that were skipped by a scan
+#[derive(Debug, Default)]
+pub(crate) struct Skipped {
+    ///files that aren't songs, counted by extension
+    other: BTreeMap<String, usize>,
+    ///songs whose tags can't be parsed
+    unreadable: usize,
+}
+
+impl Skipped {
+    pub(crate) fn add(&mut self, path: &Path) {
+        let ext = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        *self.other.entry(ext).or_default() += 1;
+    }
+    pub(crate) fn add_unreadable(&mut self, error: &impl std::fmt::Display) {
+        tracing::warn!("skipping song. {error}");
+        self.unreadable += 1;
+    }
+    ///warn about the skipped files, if there are any
+    pub(crate) fn warn(&self) {
+        if self.unreadable > 0 {
+            tracing::warn!(
+                "skipped {} songs that couldn't be read, `bongo verify` lists them",
+                self.unreadable
+            );
+        }
+        if self.other.is_empty() {
+            return;
+        }
+        let total = self.other.values().sum::<usize>();
+        let counts = self
+            .other
+            .iter()
+            .map(|(ext, count)| match ext.as_str() {
+                "" => format!("{count} without extension"),
+                ext => format!("{count} {ext}"),
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        tracing::warn!("skipped {total} files that aren't songs ({counts})");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lofty::FileType;
+
+    use super::{probe, Scan};
+    use crate::{hash, test_util};
+
+    #[test]
+    fn supported_extensions_are_songs_unless_excluded() {
+        let dir = tempfile::tempdir().unwrap();
+        let song = test_util::song(dir.path(), "song.mp3");
+        assert!(Scan::default().is_music_file(&song));
+        let scan = Scan {
+            exclude: vec![".MP3".to_owned()],
+            ..Scan::default()
+        };
+        assert!(!scan.is_music_file(&song));
+        assert!(!Scan::default().is_music_file(dir.path()));
+    }
+
+    #[test]
+    fn included_extensions_are_always_songs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("song.bin");
+        std::fs::write(&path, "not recognizable").unwrap();
+        assert!(!Scan::default().is_music_file(&path));
+        let scan = Scan {
+            include: vec!["bin".to_owned()],
+            ..Scan::default()
+        };
+        assert!(scan.is_music_file(&path));
+    }
+
+    #[test]
+    fn only_unknown_extensions_are_sniffed() {
+        let dir = tempfile::tempdir().unwrap();
+        let song = std::fs::read(test_util::song(dir.path(), "song.mp3")).unwrap();
+        for (name, is_song) in [("song", true), ("song.rip", true), ("cover.jpg", false)] {
+            let path = dir.path().join(name);
+            std::fs::write(&path, &song).unwrap();
+            assert_eq!(Scan::default().is_music_file(&path), is_song, "{name}");
+        }
+    }
+
+    #[test]
+    fn contents_decide_the_format_for_reading_and_hashing() {
+        let dir = tempfile::tempdir().unwrap();
+        let song = test_util::tagged_song(dir.path(), "song.mp3", "Title", "Artist");
+        let mislabeled = dir.path().join("song.flac");
+        std::fs::copy(&song, &mislabeled).unwrap();
+        let file_type = probe(&mislabeled).unwrap().file_type();
+        assert_eq!(file_type, Some(FileType::Mpeg));
+        assert_eq!(
+            hash::audio_hash(&mislabeled).unwrap(),
+            hash::audio_hash(&song).unwrap()
+        );
+    }
+}
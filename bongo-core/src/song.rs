@@ -1,41 +1,88 @@
-use crate::db::{Database, DbEntry, SongUuid, SONGTABLE};
+use crate::{
+    db::{Database, DbEntry, SongUuid, SONGTABLE},
+    hash,
+    scan::{self, Scan, Skipped},
+};
 use anyhow::Result;
-use lofty::{AudioFile, Tag, TaggedFile, TaggedFileExt};
+use lofty::{
+    aac::AacFile,
+    ape::ApeFile,
+    flac::FlacFile,
+    iff::{aiff::AiffFile, wav::WavFile},
+    mp4::Mp4File,
+    mpeg::MpegFile,
+    musepack::MpcFile,
+    ogg::{OpusFile, SpeexFile, VorbisFile},
+    wavpack::WavPackFile,
+    AudioFile, FileType, ItemKey, MergeTag, ParseOptions, Probe, SplitTag, Tag, TagExt, TagType,
+    TaggedFile, TaggedFileExt,
+};
 use redb::ReadableTable;
 use relative_path::RelativePath;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     path::{Path, PathBuf},
     str::FromStr,
 };
 use uuid::Uuid;
 
+///the item bongo stores its uuid in
+const UUID_ITEM: &str = "BONGO_UUID";
+
+///the key of the uuid item in tags of `tag_type`.
+///ID3v2 keeps it in a TXXX frame and MP4 in a freeform atom, vorbis comments and APE use the name as is
+pub(crate) fn uuid_key(tag_type: TagType) -> ItemKey {
+    ItemKey::Unknown(match tag_type {
+        TagType::Id3v2 => format!("TXXX:{UUID_ITEM}"),
+        TagType::Mp4Ilst => format!("----:com.apple.iTunes:{UUID_ITEM}"),
+        _ => UUID_ITEM.to_owned(),
+    })
+}
+
 pub struct Song {
     pub(crate) tagged: TaggedFile,
     pub(crate) uuid: Option<SongUuid>,
+    ///the uuid was read from the catalog number, where older versions of bongo stored it
+    pub(crate) legacy_uuid: bool,
     pub path: PathBuf,
 }
 
 impl Song {
     pub fn parse(path: PathBuf) -> Result<Self, Error> {
-        let mut tagged =
-            lofty::read_from_path(&path).map_err(|e| OpenError::from(e).at(path.clone()))?;
-        let tags = tagged.get_tag_mut(&path)?;
-        let uuid = match tags
-            .get_string(&lofty::ItemKey::CatalogNumber)
-            .map(Uuid::from_str)
-        {
-            Some(Ok(uuid)) => Some(uuid.into()),
-            _ => None,
+        //the contents decide the format, so songs with a wrong extension can still be read
+        let mut tagged = scan::probe(&path)
+            .and_then(Probe::read)
+            .map_err(|e| OpenError::from(e).at(path.clone()))?;
+        if tagged.primary_tag().is_none() {
+            //give untagged files an empty tag so they can still be sorted, fingerprinted and fetched
+            tracing::debug!("'{}' is untagged", path.to_string_lossy());
+            tagged.insert_tag(Tag::new(tagged.primary_tag_type()));
+        }
+        let tags = tagged.get_tag(&path)?;
+        let parse_uuid = |key: &ItemKey| tags.get_string(key).and_then(|s| Uuid::from_str(s).ok());
+        let (uuid, legacy_uuid) = match parse_uuid(&uuid_key(tags.tag_type())) {
+            Some(uuid) => (Some(uuid.into()), false),
+            None => {
+                let legacy = parse_uuid(&ItemKey::CatalogNumber);
+                (legacy.map(Into::into), legacy.is_some())
+            }
         };
-        Ok(Self { tagged, uuid, path })
+        Ok(Self {
+            tagged,
+            uuid,
+            legacy_uuid,
+            path,
+        })
     }
     fn clean_tags(&mut self) -> Result<()> {
         let tags = self.tags_mut()?;
         let pre_clean = tags.item_count();
         tags.remove_empty();
         if tags.item_count() < pre_clean {
-            self.tagged.save_to_path(&self.path)?;
+            self.edit_tag(&|tags| {
+                tags.remove_empty();
+                true
+            })?;
         }
         Ok(())
     }
@@ -45,19 +92,43 @@ impl Song {
             return Ok(());
         }
         let uuid = Uuid::new_v4();
-        {
-            let tags = self.tags_mut()?;
-            tags.re_map(lofty::TagType::Id3v2);
-            if !tags.insert_text(lofty::ItemKey::CatalogNumber, uuid.to_string()) {
-                return Err(OpenError::WriteTag.at(self.path.clone()));
+        let legacy_uuid = self.legacy_uuid;
+        self.edit_tag(&|tags| {
+            let inserted = tags.insert_text(uuid_key(tags.tag_type()), uuid.to_string());
+            //the old uuid would otherwise linger in the catalog number
+            if legacy_uuid {
+                tags.remove_key(&ItemKey::CatalogNumber);
             }
-        }
-        self.tagged
-            .save_to_path(&self.path)
-            .map_err(|e| OpenError::Save(e).at(self.path.clone()))?;
+            inserted
+        })?;
         self.uuid = Some(uuid.into());
+        self.legacy_uuid = false;
+        Ok(())
+    }
+    ///move a uuid from the catalog number into [`UUID_ITEM`]
+    fn migrate_uuid(&mut self) -> Result<(), Error> {
+        let Some(uuid) = self.uuid.clone().filter(|_| self.legacy_uuid) else {
+            return Ok(());
+        };
+        self.edit_tag(&|tags| {
+            let inserted = tags.insert_text(uuid_key(tags.tag_type()), uuid.to_string());
+            tags.remove_key(&ItemKey::CatalogNumber);
+            inserted
+        })?;
+        self.legacy_uuid = false;
         Ok(())
     }
+    ///apply `edit` to the primary tag, in memory and in the file. Only the primary tag is written,
+    ///through the file's own tag format so items lofty's generic [`Tag`] can't hold are kept
+    pub(crate) fn edit_tag(&mut self, edit: &dyn Fn(&mut Tag) -> bool) -> Result<(), Error> {
+        let path = self.path.clone();
+        let file_type = self.tagged.file_type();
+        let tags = self.tags_mut()?;
+        if !edit(tags) {
+            return Err(OpenError::WriteTag.at(path));
+        }
+        save_primary(&path, file_type, tags, edit).map_err(|e| OpenError::Save(e).at(path))
+    }
     #[allow(dead_code)]
     fn tags(&self) -> Result<&Tag, Error> {
         self.tagged
@@ -71,21 +142,234 @@ impl Song {
     }
     fn to_db_entry(&self, root: &Path) -> anyhow::Result<DbEntry> {
         let relative_path = RelativePath::new(root, &self.path)?;
+        let (size, modified) = hash::stamp(&self.path)?;
         Ok(DbEntry {
             old_path: relative_path,
+            audio_hash: Some(hash::audio_hash(&self.path)?),
+            size,
+            modified,
         })
     }
     pub fn to_map(&self) -> Result<HashMap<String, String>, anyhow::Error> {
         let tags = self.tagged.get_tag(&self.path)?;
         Ok(tags
+            .items()
+            .filter_map(|i| i.value().text().map(|s| (key_name(i.key()), s.to_owned())))
+            .collect())
+    }
+    ///write the items of `map` that differ from the current tags.
+    ///text items missing from `map` are removed.  Returns the names of the changed items
+    ///check that [`Song::apply_map`] can store every key of `map`, without touching the file
+    pub fn check_map(&self, map: &HashMap<String, String>) -> Result<(), Error> {
+        let tags = self.tagged.get_tag(&self.path)?;
+        let tag_type = tags.tag_type();
+        let existing = tags
+            .items()
+            .filter(|i| i.value().text().is_some())
+            .map(|i| key_name(i.key()))
+            .collect::<HashSet<_>>();
+        for name in map.keys().filter(|name| !existing.contains(*name)) {
+            if parse_item_key(name, tag_type)
+                .map_key(tag_type, true)
+                .is_none()
+            {
+                return Err(OpenError::UnsupportedKey(name.clone()).at(self.path.clone()));
+            }
+        }
+        Ok(())
+    }
+    pub fn apply_map(&mut self, map: &HashMap<String, String>) -> Result<Vec<String>, Error> {
+        let path = self.path.clone();
+        let file_type = self.tagged.file_type();
+        let tags = self.tagged.get_tag_mut(&path)?;
+        let tag_type = tags.tag_type();
+        let existing = tags
             .items()
             .filter_map(|i| {
                 i.value()
                     .text()
-                    .map(|s| (format!("{:?}", i.key()), s.to_owned()))
+                    .map(|s| (key_name(i.key()), (i.key().clone(), s.to_owned())))
             })
-            .collect())
+            .collect::<HashMap<_, _>>();
+        //resolve every key before touching the tag so a bad key leaves the song unmodified
+        let mut inserts = Vec::new();
+        for (name, value) in map {
+            match existing.get(name) {
+                Some((_, old)) if old == value => {}
+                Some((key, _)) => inserts.push((name, key.clone(), value)),
+                None => {
+                    let key = parse_item_key(name, tag_type);
+                    if key.map_key(tag_type, true).is_none() {
+                        return Err(OpenError::UnsupportedKey(name.clone()).at(path));
+                    }
+                    inserts.push((name, key, value));
+                }
+            }
+        }
+        let mut changed = Vec::new();
+        let mut removed = Vec::new();
+        for (name, (key, _)) in &existing {
+            if !map.contains_key(name) {
+                tags.remove_key(key);
+                removed.push(key.clone());
+                changed.push(name.clone());
+            }
+        }
+        let mut inserted = Vec::new();
+        for (name, key, value) in inserts {
+            if !tags.insert_text(key.clone(), value.clone()) {
+                return Err(OpenError::UnsupportedKey(name.clone()).at(path));
+            }
+            inserted.push((key, value.clone()));
+            changed.push(name.clone());
+        }
+        if !changed.is_empty() {
+            //the same changes again, on the tag in the file's own format
+            let edit = |tags: &mut Tag| {
+                for key in &removed {
+                    tags.remove_key(key);
+                }
+                inserted
+                    .iter()
+                    .all(|(key, value)| tags.insert_text(key.clone(), value.clone()))
+            };
+            save_primary(&path, file_type, tags, &edit)
+                .map_err(|e| OpenError::Save(e).at(path.clone()))?;
+        }
+        Ok(changed)
+    }
+}
+
+///apply `edit` to the primary tag of `path`, read in the file's own format.
+///Formats bongo doesn't know the primary tag of are saved from `generic`, which `edit` was already applied to
+fn save_primary(
+    path: &Path,
+    file_type: FileType,
+    generic: &Tag,
+    edit: &dyn Fn(&mut Tag) -> bool,
+) -> lofty::Result<()> {
+    let mut file = std::fs::File::open(path)?;
+    let options = ParseOptions::new().read_properties(false);
+    match file_type {
+        FileType::Mpeg => {
+            let native = MpegFile::read_from(&mut file, options)?.id3v2().cloned();
+            save_native(native, path, edit)
+        }
+        FileType::Aac => {
+            let native = AacFile::read_from(&mut file, options)?.id3v2().cloned();
+            save_native(native, path, edit)
+        }
+        FileType::Wav => {
+            let native = WavFile::read_from(&mut file, options)?.id3v2().cloned();
+            save_native(native, path, edit)
+        }
+        FileType::Aiff => {
+            let native = AiffFile::read_from(&mut file, options)?.id3v2().cloned();
+            save_native(native, path, edit)
+        }
+        FileType::Flac => {
+            let native = FlacFile::read_from(&mut file, options)?
+                .vorbis_comments()
+                .cloned();
+            save_native(native, path, edit)
+        }
+        FileType::Opus => {
+            let native = OpusFile::read_from(&mut file, options)?
+                .vorbis_comments()
+                .clone();
+            save_native(Some(native), path, edit)
+        }
+        FileType::Vorbis => {
+            let native = VorbisFile::read_from(&mut file, options)?
+                .vorbis_comments()
+                .clone();
+            save_native(Some(native), path, edit)
+        }
+        FileType::Speex => {
+            let native = SpeexFile::read_from(&mut file, options)?
+                .vorbis_comments()
+                .clone();
+            save_native(Some(native), path, edit)
+        }
+        FileType::Mp4 => {
+            let native = Mp4File::read_from(&mut file, options)?.ilst().cloned();
+            save_native(native, path, edit)
+        }
+        FileType::Ape => {
+            let native = ApeFile::read_from(&mut file, options)?.ape().cloned();
+            save_native(native, path, edit)
+        }
+        FileType::WavPack => {
+            let native = WavPackFile::read_from(&mut file, options)?.ape().cloned();
+            save_native(native, path, edit)
+        }
+        FileType::Mpc => {
+            let native = MpcFile::read_from(&mut file, options)?.ape().cloned();
+            save_native(native, path, edit)
+        }
+        _ => generic.save_to_path(path),
+    }
+}
+
+///apply `edit` to `native`, or a new tag if the file has none, and write it to `path`.
+///Items that don't fit lofty's generic [`Tag`] are split off and merged back untouched
+fn save_native<T>(
+    native: Option<T>,
+    path: &Path,
+    edit: &dyn Fn(&mut Tag) -> bool,
+) -> lofty::Result<()>
+where
+    T: SplitTag + Default + TagExt<Err = lofty::LoftyError>,
+    T::Remainder: MergeTag<Merged = T>,
+{
+    let (remainder, mut tag) = native.unwrap_or_default().split_tag();
+    edit(&mut tag);
+    remainder.merge_tag(tag).save_to_path(path)
+}
+
+///keys that can be added by name even if the song doesn't contain them yet
+const KNOWN_KEYS: [ItemKey; 22] = [
+    ItemKey::TrackTitle,
+    ItemKey::TrackArtist,
+    ItemKey::AlbumTitle,
+    ItemKey::AlbumArtist,
+    ItemKey::TrackNumber,
+    ItemKey::TrackTotal,
+    ItemKey::DiscNumber,
+    ItemKey::DiscTotal,
+    ItemKey::Year,
+    ItemKey::RecordingDate,
+    ItemKey::Genre,
+    ItemKey::Comment,
+    ItemKey::Composer,
+    ItemKey::Lyricist,
+    ItemKey::Label,
+    ItemKey::Isrc,
+    ItemKey::CatalogNumber,
+    ItemKey::Bpm,
+    ItemKey::Lyrics,
+    ItemKey::FlagCompilation,
+    ItemKey::MusicBrainzRecordingId,
+    ItemKey::MusicBrainzReleaseId,
+];
+
+///the name used for an item in [`Song::to_map`]
+fn key_name(key: &ItemKey) -> String {
+    format!("{key:?}")
+}
+
+///inverse of [`key_name`]. Falls back to the native key of `tag_type`
+fn parse_item_key(name: &str, tag_type: TagType) -> ItemKey {
+    if let Some(key) = KNOWN_KEYS.into_iter().find(|k| key_name(k) == name) {
+        return key;
     }
+    if let Some(unknown) = name
+        .strip_prefix("Unknown(\"")
+        .and_then(|n| n.strip_suffix("\")"))
+    {
+        return ItemKey::Unknown(unknown.to_owned());
+    }
+    ItemKey::from_key(tag_type, name)
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -100,6 +384,8 @@ pub enum OpenError {
     InvalidUuid(#[from] uuid::Error),
     #[error("untagged file")]
     UntaggedFile,
+    #[error("tag key '{0}' is not supported by this file")]
+    UnsupportedKey(String),
 }
 impl OpenError {
     pub fn at(self, path: PathBuf) -> Error {
@@ -114,17 +400,28 @@ pub struct Error {
     path: PathBuf,
 }
 
+///a song that shared its uuid with another song and was given a new one, see [`MusicDir::update`]
+#[derive(Debug)]
+pub struct Reissued {
+    pub path: PathBuf,
+    ///the song that kept the uuid
+    pub original: PathBuf,
+    pub old_uuid: SongUuid,
+    pub new_uuid: SongUuid,
+}
+
 pub struct MusicDir {
     pub(crate) songs: Vec<Song>,
     pub(crate) playlists: Vec<PathBuf>,
     pub(crate) root: PathBuf,
     pub(crate) db: Database,
+    pub(crate) scan: Scan,
 }
 
 impl MusicDir {
-    pub fn init(root: PathBuf, force: bool) -> Result<Self> {
+    pub fn init(root: PathBuf, force: bool, scan: Scan) -> Result<Self> {
         let db = Database::init(&root, force)?;
-        let songs = Self::find_songs(&root)?;
+        let songs = Self::find_songs(&root, &scan)?;
         let playlists = Self::find_playlists(&root)?;
         let writer = db.0.begin_write()?;
         {
@@ -139,30 +436,131 @@ impl MusicDir {
             playlists,
             root,
             db,
+            scan,
         };
         self_.update(true)?;
         Ok(self_)
     }
-    pub fn update(&mut self, write_uuid: bool) -> anyhow::Result<()> {
+    ///add new and changed songs to the db and forget removed ones.
+    ///With `write_uuid`, songs without a uuid get one and copies sharing a uuid with another song
+    ///get a new one. Returns the songs whose uuid was replaced
+    pub fn update(&mut self, write_uuid: bool) -> anyhow::Result<Vec<Reissued>> {
+        let legacy = self.songs.iter().filter(|s| s.legacy_uuid).count();
+        if legacy > 0 {
+            tracing::warn!(
+                "{legacy} songs store their uuid in the catalog number. Run `bongo migrate-uuids` to move them"
+            );
+        }
+        let mut reissued = Vec::new();
         if write_uuid {
             self.ensure_uuids()?;
+            reissued = self.reissue_duplicate_uuids()?;
         }
         self.append_songs()?;
         self.clean_old_uuid()?;
         for song in &mut self.songs {
             song.clean_tags()?;
         }
-        Ok(())
+        Ok(reissued)
+    }
+    ///songs sharing a uuid, as the index of the original and the indices of its copies.
+    ///The original is the song at the path stored in the db, or else the least recently modified one
+    fn duplicate_uuids(&self) -> anyhow::Result<Vec<(usize, Vec<usize>)>> {
+        let mut by_uuid = HashMap::<_, Vec<usize>>::new();
+        for (i, song) in self.songs.iter().enumerate() {
+            if let Some(uuid) = &song.uuid {
+                by_uuid.entry(uuid.0).or_default().push(i);
+            }
+        }
+        let reader = self.db.0.begin_read()?;
+        //the table doesn't exist until the first update
+        let song_tbl = reader.open_table(SONGTABLE).ok();
+        let mut duplicates = Vec::new();
+        for (uuid, mut songs) in by_uuid.into_iter().filter(|(_, s)| s.len() > 1) {
+            let db_path = match &song_tbl {
+                Some(table) => table
+                    .get(&SongUuid(uuid))?
+                    .map(|e| e.value().old_path.rebase(self.root.clone())),
+                None => None,
+            };
+            let original = songs
+                .iter()
+                .position(|&i| Some(&self.songs[i].path) == db_path.as_ref())
+                .unwrap_or_else(|| {
+                    //a copy made without preserving times is newer than its original
+                    (0..songs.len())
+                        .min_by_key(|&i| {
+                            hash::stamp(&self.songs[songs[i]].path).map_or(u64::MAX, |s| s.1)
+                        })
+                        .unwrap_or_default()
+                });
+            let original = songs.remove(original);
+            duplicates.push((original, songs));
+        }
+        Ok(duplicates)
+    }
+    ///give every copy found by [`MusicDir::duplicate_uuids`] a new uuid
+    fn reissue_duplicate_uuids(&mut self) -> anyhow::Result<Vec<Reissued>> {
+        let mut reissued = Vec::new();
+        for (original, copies) in self.duplicate_uuids()? {
+            let original = self.songs[original].path.clone();
+            for copy in copies {
+                let song = &mut self.songs[copy];
+                let old_uuid = song.uuid.clone().expect("duplicates have a uuid");
+                song.write_uuid(true)?;
+                let new_uuid = song.uuid.clone().expect("a uuid was just written");
+                tracing::warn!(
+                    "'{}' shares uuid '{old_uuid}' with '{}'. Gave it '{new_uuid}'",
+                    song.path.to_string_lossy(),
+                    original.to_string_lossy()
+                );
+                reissued.push(Reissued {
+                    path: song.path.clone(),
+                    original: original.clone(),
+                    old_uuid,
+                    new_uuid,
+                });
+            }
+        }
+        Ok(reissued)
     }
     fn append_songs(&mut self) -> anyhow::Result<()> {
+        //copies would overwrite the entry of their original
+        let copies = self
+            .duplicate_uuids()?
+            .into_iter()
+            .flat_map(|(_, copies)| copies)
+            .collect::<HashSet<_>>();
         let writer = self.db.0.begin_write()?;
         {
             let mut song_tbl = writer.open_table(SONGTABLE)?;
-            for song in &self.songs {
-                if let Some(uuid) = &song.uuid {
-                    if song_tbl.get(uuid)?.is_none() {
-                        tracing::info!("adding '{}' to db", song.path.to_string_lossy());
-                        song_tbl.insert(uuid, song.to_db_entry(&self.root)?)?;
+            for (i, song) in self.songs.iter().enumerate() {
+                if copies.contains(&i) {
+                    tracing::warn!(
+                        "not adding '{}' to db, it shares its uuid with another song. Run `bongo update` to give it a new one",
+                        song.path.to_string_lossy()
+                    );
+                } else if let Some(uuid) = &song.uuid {
+                    match song_tbl.get(uuid)?.map(|e| e.value()) {
+                        Some(entry) if entry.is_current(&song.path)? => {
+                            //moved or renamed without changing, the hash still holds
+                            let old_path = RelativePath::new(&self.root, &song.path)?;
+                            if entry.old_path != old_path {
+                                tracing::info!(
+                                    "updating the path of '{}' in db",
+                                    song.path.to_string_lossy()
+                                );
+                                song_tbl.insert(uuid, DbEntry { old_path, ..entry })?;
+                            }
+                        }
+                        entry => {
+                            if entry.is_none() {
+                                tracing::info!("adding '{}' to db", song.path.to_string_lossy());
+                            }
+                            tracing::debug!("hashing '{}'", song.path.to_string_lossy());
+                            let entry = song.to_db_entry(&self.root)?;
+                            song_tbl.insert(uuid, entry)?;
+                        }
                     }
                 } else {
                     tracing::warn!(
@@ -175,6 +573,21 @@ impl MusicDir {
         writer.commit()?;
         Ok(())
     }
+    ///move uuids that older versions of bongo stored in the catalog number into their own item.
+    ///Catalog numbers that aren't uuids were never used by bongo and are left alone.
+    ///Returns the number of migrated songs
+    pub fn migrate_uuids(&mut self) -> anyhow::Result<usize> {
+        let mut migrated = 0;
+        for song in self.songs.iter_mut().filter(|s| s.legacy_uuid) {
+            tracing::info!(
+                "moving the uuid of '{}' out of its catalog number",
+                song.path.to_string_lossy()
+            );
+            song.migrate_uuid()?;
+            migrated += 1;
+        }
+        Ok(migrated)
+    }
     fn ensure_uuids(&mut self) -> anyhow::Result<()> {
         for song in self.songs.iter_mut().filter(|s| s.uuid.is_none()) {
             tracing::info!("writing uuid to '{}'", song.path.to_string_lossy());
@@ -213,34 +626,67 @@ impl MusicDir {
         writer.commit()?;
         Ok(())
     }
-    pub fn open(dir: &Path) -> Result<Self> {
+    pub fn open(dir: &Path, scan: Scan) -> Result<Self> {
         let db = Database::open(dir)?;
         let db_root = db.1.parent().expect("db is both a file and a directory?");
-        let songs = Self::find_songs(db_root)?;
+        let songs = Self::find_songs(db_root, &scan)?;
         let playlists = Self::find_playlists(db_root)?;
         Ok(Self {
             songs,
             playlists,
             root: db_root.to_path_buf(),
             db,
+            scan,
         })
     }
+    ///the directory containing the bongo db
+    #[must_use]
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
     pub fn list(&self) {
         for song in &self.songs {
             println!("{}", song.path.to_string_lossy());
         }
     }
-    fn find_songs(root: &Path) -> Result<Vec<Song>> {
-        walkdir::WalkDir::new(root)
+    ///parse every music file below `root`. Files whose tags can't be parsed are skipped with a warning
+    pub fn find_songs(root: &Path, scan: &Scan) -> Result<Vec<Song>> {
+        let mut skipped = Skipped::default();
+        let mut songs = Vec::new();
+        for path in Self::walk(root, scan, &mut skipped)? {
+            match Song::parse(path) {
+                Ok(song) => songs.push(song),
+                Err(e) => skipped.add_unreadable(&e),
+            }
+        }
+        skipped.warn();
+        Ok(songs)
+    }
+    ///every music file below `root`, without parsing them
+    pub(crate) fn find_music_files(root: &Path, scan: &Scan) -> Result<Vec<PathBuf>> {
+        let mut skipped = Skipped::default();
+        let paths = Self::walk(root, scan, &mut skipped)?;
+        skipped.warn();
+        Ok(paths)
+    }
+    ///every music file below `root`, counting the other files in `skipped`
+    fn walk(root: &Path, scan: &Scan, skipped: &mut Skipped) -> Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for entry in walkdir::WalkDir::new(root)
             .max_depth(5)
             .follow_links(false)
             .into_iter()
-            .filter_entry(|e| !e.file_name().to_string_lossy().starts_with('.'))
-            .collect::<Result<Vec<_>, walkdir::Error>>()?
-            .into_iter()
-            .filter(|e| is_music_file(e.path()))
-            .map(|d| Song::parse(d.into_path()).map_err(Into::into))
-            .collect::<Result<Vec<_>>>()
+            //skip hidden entries, but not a hidden root such as a temporary directory
+            .filter_entry(|e| e.depth() == 0 || !e.file_name().to_string_lossy().starts_with('.'))
+        {
+            let path = entry?.into_path();
+            if scan.is_music_file(&path) {
+                paths.push(path);
+            } else if path.is_file() {
+                skipped.add(&path);
+            }
+        }
+        Ok(paths)
     }
     fn find_playlists(root: &Path) -> Result<Vec<PathBuf>> {
         let paths = root.read_dir()?;
@@ -272,14 +718,194 @@ impl GetTags for TaggedFile {
             .ok_or_else(|| OpenError::UntaggedFile.at(path.to_path_buf()))
     }
 }
-fn is_music_file(path: &Path) -> bool {
-    let music_filetypes = vec!["mp3", "flac", "aac"];
-    if !path.is_file() {
-        return false;
-    }
-    let Some(Some(ext)) = path.extension().map(std::ffi::OsStr::to_str) else {
-        tracing::warn!("file '{}' does not contain a valid filetype", path.to_string_lossy());
-        return false;
-    };
-    music_filetypes.contains(&ext)
+
+#[cfg(test)]
+mod tests {
+    use lofty::{AudioFile, ItemKey, TagType};
+
+    use super::{key_name, uuid_key, MusicDir, Song};
+    use crate::{fetch::Metadata, scan::Scan, sort::SortOptions, template::Template, test_util};
+
+    ///write a uuid into a new song named `name` and read it back under the native key of `tag_type`
+    fn round_trip(name: &str, tag_type: TagType) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = test_util::tagged_song(dir.path(), name, "Title", "Artist");
+        let key = key_name(&uuid_key(tag_type));
+
+        let mut song = Song::parse(path.clone()).unwrap();
+        assert_eq!(song.uuid, None);
+        let before = song.to_map().unwrap();
+        song.write_uuid(false).unwrap();
+        let uuid = song.uuid.clone().unwrap();
+
+        let mut song = Song::parse(path.clone()).unwrap();
+        assert_eq!(song.uuid, Some(uuid.clone()));
+        assert!(!song.legacy_uuid);
+        let mut after = song.to_map().unwrap();
+        assert_eq!(after.remove(&key), Some(uuid.to_string()));
+        assert_eq!(after, before);
+
+        //saving other changes through lofty's generic tag keeps the uuid under its key
+        let mut map = song.to_map().unwrap();
+        map.insert(key_name(&ItemKey::TrackTitle), "Retitled".to_owned());
+        assert_eq!(
+            song.apply_map(&map).unwrap(),
+            [key_name(&ItemKey::TrackTitle)]
+        );
+        let mut song = Song::parse(path.clone()).unwrap();
+        assert_eq!(song.uuid, Some(uuid.clone()));
+        let map = song.to_map().unwrap();
+        assert_eq!(map.get(&key), Some(&uuid.to_string()));
+        assert_eq!(
+            map.get(&key_name(&ItemKey::TrackTitle)).map(String::as_str),
+            Some("Retitled")
+        );
+        assert_eq!(
+            map.get(&key_name(&ItemKey::TrackArtist))
+                .map(String::as_str),
+            Some("Artist")
+        );
+
+        //so do fetched metadata and cleaning out empty items
+        let album = Metadata {
+            album: Some("Album".to_owned()),
+            ..Metadata::default()
+        };
+        song.apply_metadata(&album).unwrap();
+        let mut map = song.to_map().unwrap();
+        map.insert(key_name(&ItemKey::Comment), String::new());
+        song.apply_map(&map).unwrap();
+        song.clean_tags().unwrap();
+        let song = Song::parse(path).unwrap();
+        assert_eq!(song.uuid, Some(uuid.clone()));
+        let map = song.to_map().unwrap();
+        assert_eq!(map.get(&key), Some(&uuid.to_string()));
+        assert_eq!(
+            map.get(&key_name(&ItemKey::AlbumTitle)).map(String::as_str),
+            Some("Album")
+        );
+        assert_eq!(map.get(&key_name(&ItemKey::Comment)), None);
+    }
+
+    #[test]
+    fn uuid_round_trips_in_id3v2() {
+        round_trip("song.mp3", TagType::Id3v2);
+    }
+
+    #[test]
+    fn uuid_round_trips_in_flac() {
+        round_trip("song.flac", TagType::VorbisComments);
+    }
+
+    #[test]
+    fn uuid_round_trips_in_mp4() {
+        round_trip("song.m4a", TagType::Mp4Ilst);
+    }
+
+    #[test]
+    fn uuid_round_trips_in_ape() {
+        round_trip("song.ape", TagType::Ape);
+    }
+
+    #[test]
+    fn uuid_round_trips_in_opus() {
+        round_trip("song.opus", TagType::VorbisComments);
+    }
+
+    #[test]
+    fn uuid_is_written_to_untagged_songs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = test_util::song(dir.path(), "song.mp3");
+        let mut song = Song::parse(path.clone()).unwrap();
+        song.write_uuid(false).unwrap();
+        assert_eq!(Song::parse(path).unwrap().uuid, song.uuid);
+    }
+
+    #[test]
+    fn existing_uuids_are_kept_unless_forced() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = test_util::tagged_song(dir.path(), "song.flac", "Title", "Artist");
+        let mut song = Song::parse(path.clone()).unwrap();
+        song.write_uuid(false).unwrap();
+        let uuid = song.uuid.clone();
+        song.write_uuid(false).unwrap();
+        assert_eq!(song.uuid, uuid);
+        song.write_uuid(true).unwrap();
+        assert_ne!(song.uuid, uuid);
+        assert_eq!(Song::parse(path).unwrap().uuid, song.uuid);
+    }
+
+    #[test]
+    fn legacy_uuids_move_out_of_the_catalog_number() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = test_util::tagged_song(dir.path(), "song.mp3", "Title", "Artist");
+        let uuid = uuid::Uuid::new_v4().to_string();
+        let mut song = Song::parse(path.clone()).unwrap();
+        song.tags_mut()
+            .unwrap()
+            .insert_text(ItemKey::CatalogNumber, uuid.clone());
+        song.tagged.save_to_path(&path).unwrap();
+
+        let mut song = Song::parse(path.clone()).unwrap();
+        assert!(song.legacy_uuid);
+        assert_eq!(
+            song.uuid.as_ref().map(ToString::to_string),
+            Some(uuid.clone())
+        );
+        song.migrate_uuid().unwrap();
+
+        let song = Song::parse(path).unwrap();
+        assert!(!song.legacy_uuid);
+        assert_eq!(song.uuid.as_ref().map(ToString::to_string), Some(uuid));
+        let map = song.to_map().unwrap();
+        assert!(!map.contains_key(&key_name(&ItemKey::CatalogNumber)));
+        assert!(map.contains_key(&key_name(&uuid_key(TagType::Id3v2))));
+    }
+
+    #[test]
+    fn unreadable_songs_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let song = test_util::tagged_song(dir.path(), "song.flac", "Title", "Artist");
+        std::fs::write(dir.path().join("broken.mp3"), b"not an mp3").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), b"not a song").unwrap();
+        let songs = MusicDir::find_songs(dir.path(), &Scan::default()).unwrap();
+        assert_eq!(
+            songs.into_iter().map(|s| s.path).collect::<Vec<_>>(),
+            [song]
+        );
+    }
+
+    #[test]
+    fn sorted_songs_keep_their_uuid_over_copies() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        test_util::tagged_song(root, "a.mp3", "Title", "Artist");
+        let mut music_dir = MusicDir::init(root.to_path_buf(), false, Scan::default()).unwrap();
+        let uuid = music_dir.songs[0].uuid.clone().unwrap();
+        let options = SortOptions {
+            template: "{artist}/{title}.{ext}".parse::<Template>().unwrap(),
+            ..SortOptions::default()
+        };
+        music_dir.sort(None, false, false, &options).unwrap();
+        drop(music_dir);
+
+        //copied keeping its modification time, like `cp -p`
+        let sorted = root.join("Artist/Title.mp3");
+        let copy = root.join("a copy.mp3");
+        std::fs::copy(&sorted, &copy).unwrap();
+        let modified = sorted.metadata().unwrap().modified().unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&copy)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        let mut music_dir = MusicDir::open(root, Scan::default()).unwrap();
+        let reissued = music_dir.update(true).unwrap();
+        assert_eq!(reissued.len(), 1);
+        assert_eq!(reissued[0].path, copy);
+        assert_eq!(reissued[0].original, sorted);
+        assert_eq!(reissued[0].old_uuid.0, uuid.0);
+    }
 }
@@ -1,9 +1,199 @@
-use std::path::PathBuf;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    path::{Path, PathBuf},
+};
 
-use lofty::Accessor;
+use lofty::{Accessor, AudioFile};
 use relative_path::RelativePath;
 
-use crate::song::{GetTags, MusicDir};
+use crate::{
+    hash,
+    journal::JournaledMove,
+    progress::StepStatus,
+    sanitize::Sanitizer,
+    song::{GetTags, MusicDir, Song},
+    template::Template,
+    transcode::{self, Preset, Transcoding},
+    transfer,
+};
+
+///how `sort` lays out files
+#[derive(Debug, Clone, Default)]
+pub struct SortOptions {
+    pub template: Template,
+    ///applied to every rendered path component
+    pub sanitizer: Sanitizer,
+    pub on_collision: OnCollision,
+    ///move cover art, cue sheets, rip logs and lyrics along with the songs next to them
+    pub sidecars: bool,
+    ///remove directories that moving left empty
+    pub prune: bool,
+    ///link instead of copying into the destination directory
+    pub link: Option<Link>,
+    ///encode these file types when copying into the destination directory
+    pub transcode: Transcoding,
+}
+
+///how files are linked into a destination directory
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Link {
+    ///hard links, only within one file system
+    Hard,
+    ///symbolic links to the original files
+    Sym,
+    ///copy on write clones, only on btrfs, xfs, apfs and similar
+    Reflink,
+}
+
+///files carried along with the songs in their directory
+const SIDECAR_EXTENSIONS: [&str; 6] = ["jpg", "jpeg", "png", "cue", "log", "lrc"];
+
+///what to do when several songs would be sorted to the same path, or the path is already taken
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnCollision {
+    ///refuse to sort anything
+    #[default]
+    Abort,
+    ///leave every song but the first where it is
+    Skip,
+    ///add ` (2)`, ` (3)`, .. to the file name
+    Number,
+    ///add the track number, bitrate or uuid to the file name, whichever tells the songs apart
+    Disambiguate,
+}
+
+///whether a [`Plan`] copies into another directory or moves files within the music dir
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    Copy,
+    Move,
+}
+
+///a single copy or move
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Step {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    ///encode the source with this instead of copying it
+    #[serde(default)]
+    pub transcode: Option<Preset>,
+}
+
+///a destination that more than one song would be written to, or that already holds a file
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Collision {
+    pub destination: PathBuf,
+    pub sources: Vec<PathBuf>,
+    ///the destination already exists
+    pub exists: bool,
+}
+
+///everything sorting would do, computed without touching the file system.
+///Can be saved and applied later with [`MusicDir::apply_plan`]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Plan {
+    pub mode: Mode,
+    ///the music dir the plan was made for
+    pub root: PathBuf,
+    ///the directory files are sorted into, the music dir itself when moving
+    pub destination: PathBuf,
+    ///songs that are already in place are left out
+    pub steps: Vec<Step>,
+    ///collisions left unresolved by [`OnCollision::Abort`]
+    pub collisions: Vec<Collision>,
+    ///songs left in place by [`OnCollision::Skip`]
+    #[serde(default)]
+    pub skipped: Vec<PathBuf>,
+    ///directories that don't exist yet
+    pub create_dirs: Vec<PathBuf>,
+    ///directories every file would be moved out of, deepest first
+    pub emptied_dirs: Vec<PathBuf>,
+    ///remove `emptied_dirs` after moving
+    #[serde(default)]
+    pub prune: bool,
+    ///link instead of copying, files that can't be linked are copied
+    #[serde(default)]
+    pub link: Option<Link>,
+}
+
+impl std::fmt::Display for Plan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let verb = match (self.mode, self.link) {
+            (Mode::Copy, None) => "copy",
+            (Mode::Copy, Some(Link::Hard)) => "hardlink",
+            (Mode::Copy, Some(Link::Sym)) => "symlink",
+            (Mode::Copy, Some(Link::Reflink)) => "reflink",
+            (Mode::Move, _) => "move",
+        };
+        //destinations are shown relative to where files end up
+        let base = match self.mode {
+            Mode::Move => &self.root,
+            Mode::Copy => &self.destination,
+        };
+        let sources = self
+            .steps
+            .iter()
+            .map(|s| relative_to(&self.root, &s.source))
+            .collect::<Vec<_>>();
+        let width = sources.iter().map(|s| s.chars().count()).max().unwrap_or(0);
+        for (step, source) in self.steps.iter().zip(&sources) {
+            let transcode = step
+                .transcode
+                .map(|preset| format!(" ({preset})"))
+                .unwrap_or_default();
+            writeln!(
+                f,
+                "{verb} {source:<width$}  ->  {}{transcode}",
+                relative_to(base, &step.destination)
+            )?;
+        }
+        for dir in &self.create_dirs {
+            writeln!(f, "create {}", relative_to(base, dir))?;
+        }
+        for source in &self.skipped {
+            writeln!(f, "skip   {}", relative_to(&self.root, source))?;
+        }
+        let emptied = if self.prune { "remove" } else { "empty " };
+        for dir in &self.emptied_dirs {
+            writeln!(f, "{emptied} {}", relative_to(&self.root, dir))?;
+        }
+        for collision in &self.collisions {
+            writeln!(
+                f,
+                "collision at '{}'",
+                collision.destination.to_string_lossy()
+            )?;
+            if collision.exists {
+                writeln!(f, "    already exists")?;
+            }
+            for source in &collision.sources {
+                writeln!(f, "    {}", relative_to(&self.root, source))?;
+            }
+        }
+        write!(
+            f,
+            "{} files to {verb}, {} skipped, {} directories to create, {} left empty, {} collisions",
+            self.steps.len(),
+            self.skipped.len(),
+            self.create_dirs.len(),
+            self.emptied_dirs.len(),
+            self.collisions.len()
+        )
+    }
+}
+
+fn relative_to(root: &Path, path: &Path) -> String {
+    match path.strip_prefix(root) {
+        Ok(relative) if relative.as_os_str().is_empty() => ".".to_owned(),
+        Ok(relative) => relative.to_string_lossy().into_owned(),
+        Err(_) => path.to_string_lossy().into_owned(),
+    }
+}
 
 impl MusicDir {
     pub fn sort(
@@ -11,83 +201,1044 @@ impl MusicDir {
         destination_dir: Option<PathBuf>,
         ignore_db: bool,
         auto_init: bool,
+        options: &SortOptions,
     ) -> anyhow::Result<()> {
-        if let Some(destination_dir) = destination_dir {
-            if self.root == destination_dir {
-                anyhow::bail!("source and destination directories are the same");
-            }
-            if !destination_dir.exists() {
-                std::fs::create_dir(&destination_dir)?;
+        let plan = self.plan_sort(destination_dir, options)?;
+        self.apply_plan(&plan, ignore_db, auto_init)
+    }
+
+    ///work out where every song would go without changing anything
+    pub fn plan_sort(
+        &self,
+        destination_dir: Option<PathBuf>,
+        options: &SortOptions,
+    ) -> anyhow::Result<Plan> {
+        let (mode, destination) = match destination_dir {
+            Some(destination_dir) => {
+                if self.root == destination_dir {
+                    anyhow::bail!("source and destination directories are the same");
+                }
+                if destination_dir.exists() && !destination_dir.is_dir() {
+                    anyhow::bail!("destination is not a directory");
+                }
+                (Mode::Copy, destination_dir)
             }
-            if !destination_dir.is_dir() {
-                anyhow::bail!("destination is not a directory");
+            None => (Mode::Move, self.root.clone()),
+        };
+        let mut by_destination = BTreeMap::<PathBuf, Vec<&Song>>::new();
+        let transcode = |song: &Song| {
+            options
+                .transcode
+                .preset(&song.path)
+                .filter(|_| mode == Mode::Copy)
+        };
+        for (dest, song) in self.song_paths(options)? {
+            let mut dest = dest.rebase(destination.clone());
+            if let Some(preset) = transcode(song) {
+                dest.set_extension(preset.extension());
             }
-            for (dest, source) in self.song_paths()? {
-                let dest = dest.rebase(destination_dir.clone());
-                tracing::info!(
-                    "copying '{}' to '{}'",
-                    source.to_string_lossy(),
-                    dest.to_string_lossy()
-                );
-                if &dest == source {
+            if dest == song.path {
+                if mode == Mode::Copy {
                     anyhow::bail!("unable to copy to self");
                 }
-                std::fs::create_dir_all(dest.parent().unwrap())?;
-                std::fs::copy(source, dest)?;
+                continue;
+            }
+            by_destination.entry(dest).or_default().push(song);
+        }
+
+        let mut taken = by_destination.keys().cloned().collect::<HashSet<_>>();
+        let mut steps = Vec::new();
+        let mut collisions = Vec::new();
+        let mut skipped = Vec::new();
+        for (dest, songs) in by_destination {
+            let exists = dest.exists();
+            if !exists && songs.len() == 1 {
+                steps.push(Step {
+                    source: songs[0].path.clone(),
+                    destination: dest,
+                    transcode: transcode(songs[0]),
+                });
+                continue;
+            }
+            tracing::debug!(
+                "{} songs collide at '{}'",
+                songs.len(),
+                dest.to_string_lossy()
+            );
+            let destinations = match options.on_collision {
+                OnCollision::Abort => {
+                    collisions.push(Collision {
+                        destination: dest.clone(),
+                        sources: songs.iter().map(|s| s.path.clone()).collect(),
+                        exists,
+                    });
+                    vec![Some(dest); songs.len()]
+                }
+                OnCollision::Skip => {
+                    let mut destinations = vec![None; songs.len()];
+                    if !exists {
+                        destinations[0] = Some(dest);
+                    }
+                    destinations
+                }
+                OnCollision::Number => {
+                    number(&dest, songs.len(), exists, &mut taken, &options.sanitizer)
+                }
+                OnCollision::Disambiguate => {
+                    disambiguate(&dest, &songs, &mut taken, &options.sanitizer).unwrap_or_else(
+                        || number(&dest, songs.len(), exists, &mut taken, &options.sanitizer),
+                    )
+                }
+            };
+            for (song, destination) in songs.into_iter().zip(destinations) {
+                match destination {
+                    Some(destination) => steps.push(Step {
+                        source: song.path.clone(),
+                        destination,
+                        transcode: transcode(song),
+                    }),
+                    None => {
+                        tracing::warn!(
+                            "skipping '{}', its destination is taken",
+                            song.path.to_string_lossy()
+                        );
+                        skipped.push(song.path.clone());
+                    }
+                }
+            }
+        }
+
+        if options.sidecars {
+            let sidecars = self.sidecar_steps(mode, &steps, &mut taken)?;
+            steps.extend(sidecars);
+        }
+
+        let mut create_dirs = BTreeSet::new();
+        for step in &steps {
+            for dir in step.destination.ancestors().skip(1) {
+                if dir.exists() || !create_dirs.insert(dir.to_path_buf()) {
+                    break;
+                }
+            }
+        }
+
+        let mut emptied_dirs = Vec::new();
+        if mode == Mode::Move {
+            let mut leaving = BTreeMap::<PathBuf, usize>::new();
+            //every directory a file is moved into, directly or below it
+            let mut arriving = HashSet::new();
+            for step in &steps {
+                if let Some(parent) = step.source.parent() {
+                    *leaving.entry(parent.to_path_buf()).or_default() += 1;
+                }
+                for dir in step.destination.ancestors().skip(1) {
+                    if !arriving.insert(dir) {
+                        break;
+                    }
+                }
+            }
+            //children sort after their parents, so a directory is only checked once everything below it has been
+            while let Some((dir, count)) = leaving.pop_last() {
+                if dir == self.root || arriving.contains(dir.as_path()) {
+                    continue;
+                }
+                //only list as many entries as are leaving, one more means something stays
+                if std::fs::read_dir(&dir)?.take(count + 1).count() != count {
+                    continue;
+                }
+                if let Some(parent) = dir.parent() {
+                    *leaving.entry(parent.to_path_buf()).or_default() += 1;
+                }
+                emptied_dirs.push(dir);
+            }
+        }
+
+        Ok(Plan {
+            mode,
+            root: self.root.clone(),
+            destination,
+            steps,
+            collisions,
+            skipped,
+            create_dirs: create_dirs.into_iter().collect(),
+            emptied_dirs,
+            prune: options.prune,
+            link: options.link.filter(|_| mode == Mode::Copy),
+        })
+    }
+
+    ///steps carrying the sidecar files next to the songs in `steps` along.
+    ///Lyrics named after a song follow that song, the rest go where most songs of their directory go.
+    ///When moving, shared files are left behind unless every song of their directory is moved
+    fn sidecar_steps(
+        &self,
+        mode: Mode,
+        steps: &[Step],
+        taken: &mut HashSet<PathBuf>,
+    ) -> anyhow::Result<Vec<Step>> {
+        let mut by_dir = BTreeMap::<&Path, Vec<&Step>>::new();
+        for step in steps {
+            if let Some(parent) = step.source.parent() {
+                by_dir.entry(parent).or_default().push(step);
             }
-            if auto_init {
-                Self::init(destination_dir, false)?;
+        }
+        let mut sidecars = Vec::new();
+        for (dir, songs) in by_dir {
+            let mut targets = BTreeMap::<&Path, usize>::new();
+            for step in &songs {
+                if let Some(parent) = step.destination.parent() {
+                    *targets.entry(parent).or_default() += 1;
+                }
             }
-        } else {
-            for (dest, source) in self.song_paths()? {
-                let dest = dest.rebase(self.root.clone());
-                if &dest != source {
-                    tracing::info!(
-                        "moving '{}' to '{}'",
-                        source.to_string_lossy(),
-                        dest.to_string_lossy()
+            let Some((target, _)) = targets.into_iter().max_by_key(|(_, count)| *count) else {
+                continue;
+            };
+            let staying = self
+                .songs
+                .iter()
+                .filter(|s| s.path.parent() == Some(dir))
+                .count()
+                > songs.len();
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path();
+                let Some(ext) = path
+                    .extension()
+                    .map(|e| e.to_string_lossy().to_lowercase())
+                    .filter(|e| SIDECAR_EXTENSIONS.contains(&e.as_str()))
+                else {
+                    continue;
+                };
+                if !path.is_file()
+                    || path
+                        .file_name()
+                        .is_some_and(|n| n.to_string_lossy().starts_with('.'))
+                {
+                    continue;
+                }
+                let song = songs
+                    .iter()
+                    .find(|s| ext == "lrc" && s.source.file_stem() == path.file_stem());
+                let destination = match song {
+                    Some(song) => song.destination.with_extension(&ext),
+                    None if mode == Mode::Move && staying => continue,
+                    None => target.join(path.file_name().unwrap_or_default()),
+                };
+                if destination == path {
+                    continue;
+                }
+                if destination.exists() || !taken.insert(destination.clone()) {
+                    tracing::warn!(
+                        "leaving '{}', '{}' is taken",
+                        path.to_string_lossy(),
+                        destination.to_string_lossy()
                     );
-                    std::fs::create_dir_all(dest.parent().unwrap())?;
-                    std::fs::copy(source, dest)?;
-                    std::fs::remove_file(source)?;
+                    continue;
+                }
+                sidecars.push(Step {
+                    source: path,
+                    destination,
+                    transcode: None,
+                });
+            }
+        }
+        Ok(sidecars)
+    }
+
+    ///carry out a plan made by [`MusicDir::plan_sort`], possibly in an earlier run
+    pub fn apply_plan(
+        &mut self,
+        plan: &Plan,
+        ignore_db: bool,
+        auto_init: bool,
+    ) -> anyhow::Result<()> {
+        if plan.root != self.root {
+            anyhow::bail!(
+                "plan was made for '{}', not '{}'",
+                plan.root.to_string_lossy(),
+                self.root.to_string_lossy()
+            );
+        }
+        if !plan.collisions.is_empty() {
+            anyhow::bail!(
+                "{} destinations collide. Run with --dry-run to see them, or choose what to do with --on-collision",
+                plan.collisions.len()
+            );
+        }
+        check_auto_init(plan, auto_init)?;
+        if let Some(step) = plan.steps.iter().find(|s| !s.source.exists()) {
+            anyhow::bail!(
+                "'{}' no longer exists. The plan is out of date",
+                step.source.to_string_lossy()
+            );
+        }
+        //destinations that existed were collisions when planning, anything there now arrived since
+        if let Some(step) = plan.steps.iter().find(|s| s.destination.exists()) {
+            anyhow::bail!(
+                "'{}' already exists. The plan is out of date",
+                step.destination.to_string_lossy()
+            );
+        }
+        self.db.start_sort(plan)?;
+        let status = vec![StepStatus::Pending; plan.steps.len()];
+        self.run_plan(plan, status, ignore_db, auto_init)
+    }
+
+    ///continue a sort that was interrupted, checking the step that was in progress
+    pub fn resume_sort(&mut self, ignore_db: bool, auto_init: bool) -> anyhow::Result<()> {
+        let Some((plan, mut status)) = self.db.unfinished_sort()? else {
+            anyhow::bail!("there is no interrupted sort to resume");
+        };
+        if plan.root != self.root {
+            anyhow::bail!(
+                "interrupted sort belongs to '{}'",
+                plan.root.to_string_lossy()
+            );
+        }
+        check_auto_init(&plan, auto_init)?;
+        self.recover_steps(&plan, &mut status)?;
+        let done = status.iter().filter(|s| **s == StepStatus::Done).count();
+        tracing::info!("resuming sort, {done} of {} files done", plan.steps.len());
+        //songs were scanned before recovering, some may have just lost their source
+        for (step, status) in plan.steps.iter().zip(&status) {
+            if *status == StepStatus::Done && plan.mode == Mode::Move {
+                if let Some(song) = self.songs.iter_mut().find(|s| s.path == step.source) {
+                    song.path = step.destination.clone();
+                }
+            }
+        }
+        self.run_plan(&plan, status, ignore_db, auto_init)
+    }
+
+    ///forget a sort that was interrupted, leaving the files it already sorted where they are.
+    ///Those are journaled, so the abandoned sort can still be undone
+    pub fn abandon_sort(&mut self, ignore_db: bool) -> anyhow::Result<()> {
+        let Some((plan, mut status)) = self.db.unfinished_sort()? else {
+            anyhow::bail!("there is no interrupted sort to abandon");
+        };
+        if plan.root != self.root {
+            anyhow::bail!(
+                "interrupted sort belongs to '{}'",
+                plan.root.to_string_lossy()
+            );
+        }
+        //keeps the step in progress from leaving half a file behind
+        self.recover_steps(&plan, &mut status)?;
+        let done = status.iter().filter(|s| **s == StepStatus::Done).count();
+        tracing::info!("abandoning sort, {done} of {} files done", plan.steps.len());
+        self.finish_plan(&plan, &status, ignore_db, false)?;
+        if plan.mode == Mode::Move && !ignore_db {
+            self.update(false)?;
+        }
+        Ok(())
+    }
+
+    ///check the pending steps of an interrupted sort, see [`MusicDir::recover_step`]
+    fn recover_steps(&self, plan: &Plan, status: &mut [StepStatus]) -> anyhow::Result<()> {
+        for (i, step) in plan.steps.iter().enumerate() {
+            if status[i] == StepStatus::Pending {
+                let recovered = self.recover_step(plan.mode, step)?;
+                if recovered != StepStatus::Pending {
+                    self.db.mark_step(i, recovered)?;
+                    status[i] = recovered;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    ///journal the finished steps of `plan` and forget it
+    fn finish_plan(
+        &self,
+        plan: &Plan,
+        status: &[StepStatus],
+        ignore_db: bool,
+        created_destination: bool,
+    ) -> anyhow::Result<()> {
+        let moves = plan
+            .steps
+            .iter()
+            .zip(status)
+            .filter(|(_, status)| **status == StepStatus::Done)
+            .map(|(step, _)| self.journal_step(plan, step))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if !moves.is_empty() {
+            let id = self.db.record_sort(
+                plan.mode,
+                &plan.destination,
+                moves,
+                ignore_db,
+                created_destination,
+            )?;
+            tracing::info!("recorded sort as operation {id}");
+        }
+        self.db.finish_sort()
+    }
+
+    fn run_plan(
+        &mut self,
+        plan: &Plan,
+        mut status: Vec<StepStatus>,
+        ignore_db: bool,
+        auto_init: bool,
+    ) -> anyhow::Result<()> {
+        //an interrupted sort may have created it, in which case undoing leaves it
+        let created_destination = plan.mode == Mode::Copy && !plan.destination.exists();
+        if created_destination {
+            std::fs::create_dir(&plan.destination)?;
+        }
+        for (i, step) in plan.steps.iter().enumerate() {
+            if status[i] != StepStatus::Pending {
+                continue;
+            }
+            //on failure the plan stays in the db for `resume_sort`
+            self.apply_step(plan.mode, plan.link, step)?;
+            self.db.mark_step(i, StepStatus::Done)?;
+            status[i] = StepStatus::Done;
+        }
+        self.finish_plan(plan, &status, ignore_db, created_destination)?;
+        if plan.prune {
+            for dir in &plan.emptied_dirs {
+                //only removes empty directories, anything added since the plan was made is kept
+                match std::fs::remove_dir(dir) {
+                    Ok(()) => tracing::info!("removed empty directory '{}'", dir.to_string_lossy()),
+                    Err(e) => tracing::warn!("unable to remove '{}'. {e}", dir.to_string_lossy()),
                 }
             }
-            if !ignore_db {
+        }
+        if plan.mode == Mode::Copy {
+            self.prune_transcodes();
+        }
+        match plan.mode {
+            Mode::Copy if auto_init => {
+                Self::init(plan.destination.clone(), false, self.scan.clone())?;
+            }
+            Mode::Move if !ignore_db => {
                 self.update(false)?;
             }
+            Mode::Copy | Mode::Move => {}
         }
         Ok(())
     }
 
-    fn song_paths(&self) -> anyhow::Result<Vec<(RelativePath, &PathBuf)>> {
+    fn apply_step(&mut self, mode: Mode, link: Option<Link>, step: &Step) -> anyhow::Result<()> {
+        std::fs::create_dir_all(step.destination.parent().unwrap())?;
+        match (mode, step.transcode, link) {
+            (Mode::Copy, Some(preset), _) => {
+                transcode::transcode(
+                    &step.source,
+                    &step.destination,
+                    preset,
+                    &self.cached_transcode(&step.source, preset)?,
+                    false,
+                )?;
+            }
+            (Mode::Copy, None, Some(link)) => {
+                tracing::info!(
+                    "linking '{}' to '{}'",
+                    step.source.to_string_lossy(),
+                    step.destination.to_string_lossy()
+                );
+                transfer::link_file(&step.source, &step.destination, link, false)?;
+            }
+            (Mode::Copy, None, None) => {
+                tracing::info!(
+                    "copying '{}' to '{}'",
+                    step.source.to_string_lossy(),
+                    step.destination.to_string_lossy()
+                );
+                transfer::copy_file(&step.source, &step.destination, false)?;
+            }
+            (Mode::Move, ..) => {
+                tracing::info!(
+                    "moving '{}' to '{}'",
+                    step.source.to_string_lossy(),
+                    step.destination.to_string_lossy()
+                );
+                transfer::move_file(&step.source, &step.destination)?;
+                if let Some(song) = self.songs.iter_mut().find(|s| s.path == step.source) {
+                    song.path = step.destination.clone();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn journal_step(&self, plan: &Plan, step: &Step) -> anyhow::Result<JournaledMove> {
+        let current = match plan.mode {
+            Mode::Copy => &step.source,
+            Mode::Move => &step.destination,
+        };
+        Ok(JournaledMove {
+            uuid: self
+                .songs
+                .iter()
+                .find(|s| &s.path == current)
+                .and_then(|s| s.uuid.clone()),
+            old_path: relative(&self.root, &step.source)?,
+            new_path: relative(&plan.destination, &step.destination)?,
+            audio_hash: match plan.mode {
+                Mode::Copy => Some(hash::audio_hash(&step.destination)?),
+                Mode::Move => None,
+            },
+        })
+    }
+
+    pub(crate) fn song_paths(
+        &self,
+        options: &SortOptions,
+    ) -> anyhow::Result<Vec<(RelativePath, &Song)>> {
         let mut paths = Vec::with_capacity(self.songs.len());
         for song in &self.songs {
-            let tags = song.tagged.get_tag(&song.path)?;
-            let mut title = if let Some(title) = tags.title() {
-                title
-            } else {
-                song.path
-                    .file_name()
-                    .ok_or(anyhow::anyhow!(
-                        "'{}' is not a file",
-                        song.path.to_string_lossy()
-                    ))?
-                    .to_string_lossy()
-            };
-            title.to_mut().push('.');
-            title
-                .to_mut()
-                .push_str(&song.path.extension().unwrap().to_string_lossy());
-            let album = tags.album().unwrap_or("Singles".into());
-            let artist = tags.artist().unwrap_or("UnknownArtist".into());
-            let relative_path = RelativePath::from(
-                [artist, album, title]
-                    .map(|s| s.to_string())
-                    .into_iter()
-                    .collect::<RelativePath>(),
-            );
-            paths.push((relative_path, &song.path));
+            let relative_path = options.sanitizer.path(options.template.render(song)?);
+            paths.push((relative_path, song));
         }
         Ok(paths)
     }
 }
+
+///initializing writes uuids into the songs, which would change the originals through hard or symbolic links
+fn check_auto_init(plan: &Plan, auto_init: bool) -> anyhow::Result<()> {
+    if auto_init && matches!(plan.link, Some(Link::Hard | Link::Sym)) {
+        anyhow::bail!("unable to initialize a db in a directory of hard or symbolic links");
+    }
+    Ok(())
+}
+
+impl MusicDir {
+    ///how far `step` got before a sort was interrupted.
+    ///A destination left next to its source is kept if it is a complete copy and removed otherwise.
+    ///Transcoded steps are compared against the cached transcode.
+    ///Steps whose source and destination are both gone are skipped
+    fn recover_step(&self, mode: Mode, step: &Step) -> anyhow::Result<StepStatus> {
+        match (step.source.exists(), step.destination.exists()) {
+            (true, false) => Ok(StepStatus::Pending),
+            (false, true) => Ok(StepStatus::Done),
+            (true, true) => {
+                let complete = match step.transcode {
+                    Some(preset) => {
+                        let cached = self.cached_transcode(&step.source, preset)?;
+                        cached.exists() && transfer::same_contents(&cached, &step.destination)?
+                    }
+                    None => transfer::same_contents(&step.source, &step.destination)?,
+                };
+                if complete {
+                    if mode == Mode::Move {
+                        std::fs::remove_file(&step.source)?;
+                    }
+                    Ok(StepStatus::Done)
+                } else {
+                    tracing::warn!(
+                        "'{}' is incomplete, redoing it",
+                        step.destination.to_string_lossy()
+                    );
+                    std::fs::remove_file(&step.destination)?;
+                    Ok(StepStatus::Pending)
+                }
+            }
+            (false, false) => {
+                tracing::warn!(
+                    "'{}' is missing, skipping it",
+                    step.source.to_string_lossy()
+                );
+                Ok(StepStatus::Skipped)
+            }
+        }
+    }
+}
+
+///`path` relative to `root`, without requiring either to exist
+pub(crate) fn relative(root: &Path, path: &Path) -> anyhow::Result<RelativePath> {
+    path.strip_prefix(root)?
+        .iter()
+        .map(|c| {
+            c.to_str()
+                .map(ToOwned::to_owned)
+                .ok_or_else(|| anyhow::anyhow!("invalid utf8 path '{}'", path.to_string_lossy()))
+        })
+        .collect()
+}
+
+///`dest` with `suffix` added to the file name, before the extension
+fn with_suffix(dest: &Path, suffix: &str, sanitizer: &Sanitizer) -> PathBuf {
+    let stem = dest.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match dest.extension() {
+        Some(ext) => format!("{stem}{suffix}.{}", ext.to_string_lossy()),
+        None => format!("{stem}{suffix}"),
+    };
+    dest.with_file_name(sanitizer.component(&name, true))
+}
+
+///keep the first of `count` songs at `dest`, unless it exists, and number the rest
+fn number(
+    dest: &Path,
+    count: usize,
+    exists: bool,
+    taken: &mut HashSet<PathBuf>,
+    sanitizer: &Sanitizer,
+) -> Vec<Option<PathBuf>> {
+    let mut destinations = Vec::with_capacity(count);
+    if !exists {
+        destinations.push(Some(dest.to_path_buf()));
+    }
+    let mut n = 2;
+    while destinations.len() < count {
+        let numbered = with_suffix(dest, &format!(" ({n})"), sanitizer);
+        n += 1;
+        if !numbered.exists() && taken.insert(numbered.clone()) {
+            destinations.push(Some(numbered));
+        }
+    }
+    destinations
+}
+
+///tell the songs apart by the first of track number, bitrate or uuid that differs between all of them
+fn disambiguate(
+    dest: &Path,
+    songs: &[&Song],
+    taken: &mut HashSet<PathBuf>,
+    sanitizer: &Sanitizer,
+) -> Option<Vec<Option<PathBuf>>> {
+    let fields: [&dyn Fn(&Song) -> Option<String>; 3] = [
+        &|song| {
+            let track = song.tagged.get_tag(&song.path).ok()?.track()?;
+            Some(format!(" (track {track})"))
+        },
+        &|song| {
+            Some(format!(
+                " ({}kbps)",
+                song.tagged.properties().audio_bitrate()?
+            ))
+        },
+        &|song| {
+            let uuid = song.uuid.as_ref()?.0.simple().to_string();
+            Some(format!(" ({})", &uuid[..8]))
+        },
+    ];
+    for field in fields {
+        let Some(destinations) = songs
+            .iter()
+            .map(|song| field(song).map(|suffix| with_suffix(dest, &suffix, sanitizer)))
+            .collect::<Option<Vec<_>>>()
+        else {
+            continue;
+        };
+        let unique = destinations.iter().collect::<HashSet<_>>().len() == destinations.len();
+        if unique
+            && destinations
+                .iter()
+                .all(|d| !d.exists() && !taken.contains(d))
+        {
+            taken.extend(destinations.iter().cloned());
+            return Some(destinations.into_iter().map(Some).collect());
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use lofty::{Accessor, AudioFile, TaggedFileExt};
+
+    use super::{OnCollision, Plan, SortOptions};
+    use crate::{progress::StepStatus, scan::Scan, song::MusicDir, template::Template, test_util};
+
+    ///a song by `artist` called `title`, with a track number if given
+    fn song(dir: &Path, name: &str, title: &str, artist: &str, track: Option<u32>) -> PathBuf {
+        let path = test_util::tagged_song(dir, name, title, artist);
+        if let Some(track) = track {
+            let mut file = lofty::read_from_path(&path).unwrap();
+            file.primary_tag_mut().unwrap().set_track(track);
+            file.save_to_path(&path).unwrap();
+        }
+        path
+    }
+
+    fn options(format: &str, on_collision: OnCollision) -> SortOptions {
+        SortOptions {
+            template: format.parse::<Template>().unwrap(),
+            on_collision,
+            ..SortOptions::default()
+        }
+    }
+
+    fn init(root: &Path) -> MusicDir {
+        MusicDir::init(root.to_path_buf(), false, Scan::default()).unwrap()
+    }
+
+    ///the destinations of `plan` relative to its root, sorted
+    fn destinations(plan: &Plan) -> Vec<String> {
+        let mut destinations = plan
+            .steps
+            .iter()
+            .map(|s| {
+                s.destination
+                    .strip_prefix(&plan.root)
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect::<Vec<_>>();
+        destinations.sort();
+        destinations
+    }
+
+    ///two songs that render to `Artist/Title.mp3`, plus a third already sorted there if `existing`
+    fn colliding(root: &Path, existing: bool) -> MusicDir {
+        song(root, "a.mp3", "Title", "Artist", Some(1));
+        song(root, "b.mp3", "Title", "Artist", Some(2));
+        if existing {
+            song(root, "Artist/Title.mp3", "Title", "Artist", Some(3));
+        }
+        init(root)
+    }
+
+    const FORMAT: &str = "{artist}/{title}.{ext}";
+
+    #[test]
+    fn plan_moves_songs_to_their_rendered_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        song(root, "a.mp3", "Title", "Artist", None);
+        song(root, "Other/Sorted.mp3", "Sorted", "Other", None);
+        let plan = init(root)
+            .plan_sort(None, &options(FORMAT, OnCollision::Abort))
+            .unwrap();
+        //songs already in place are left out
+        assert_eq!(destinations(&plan), ["Artist/Title.mp3"]);
+        assert_eq!(plan.create_dirs, [root.join("Artist")]);
+        assert!(plan.collisions.is_empty());
+        assert!(plan.emptied_dirs.is_empty());
+    }
+
+    #[test]
+    fn abort_reports_collisions_and_refuses_to_apply() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut music_dir = colliding(dir.path(), false);
+        let plan = music_dir
+            .plan_sort(None, &options(FORMAT, OnCollision::Abort))
+            .unwrap();
+        assert_eq!(plan.collisions.len(), 1);
+        assert_eq!(plan.collisions[0].sources.len(), 2);
+        assert!(!plan.collisions[0].exists);
+        assert!(music_dir.apply_plan(&plan, false, false).is_err());
+        assert!(dir.path().join("a.mp3").exists());
+    }
+
+    #[test]
+    fn abort_reports_files_already_at_the_destination() {
+        let dir = tempfile::tempdir().unwrap();
+        song(dir.path(), "a.mp3", "Title", "Artist", None);
+        song(dir.path(), "Artist/Title.mp3", "Title", "Artist", None);
+        let plan = init(dir.path())
+            .plan_sort(None, &options(FORMAT, OnCollision::Abort))
+            .unwrap();
+        assert_eq!(plan.collisions.len(), 1);
+        assert!(plan.collisions[0].exists);
+        assert_eq!(plan.collisions[0].sources, [dir.path().join("a.mp3")]);
+    }
+
+    #[test]
+    fn skip_keeps_the_first_song() {
+        let dir = tempfile::tempdir().unwrap();
+        let plan = colliding(dir.path(), false)
+            .plan_sort(None, &options(FORMAT, OnCollision::Skip))
+            .unwrap();
+        assert_eq!(destinations(&plan), ["Artist/Title.mp3"]);
+        assert_eq!(plan.skipped.len(), 1);
+
+        let dir = tempfile::tempdir().unwrap();
+        let plan = colliding(dir.path(), true)
+            .plan_sort(None, &options(FORMAT, OnCollision::Skip))
+            .unwrap();
+        assert!(plan.steps.is_empty());
+        assert_eq!(plan.skipped.len(), 2);
+    }
+
+    #[test]
+    fn number_numbers_every_song_after_the_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let plan = colliding(dir.path(), false)
+            .plan_sort(None, &options(FORMAT, OnCollision::Number))
+            .unwrap();
+        assert_eq!(
+            destinations(&plan),
+            ["Artist/Title (2).mp3", "Artist/Title.mp3"]
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let plan = colliding(dir.path(), true)
+            .plan_sort(None, &options(FORMAT, OnCollision::Number))
+            .unwrap();
+        assert_eq!(
+            destinations(&plan),
+            ["Artist/Title (2).mp3", "Artist/Title (3).mp3"]
+        );
+    }
+
+    #[test]
+    fn disambiguate_uses_the_track_number() {
+        let dir = tempfile::tempdir().unwrap();
+        let plan = colliding(dir.path(), false)
+            .plan_sort(None, &options(FORMAT, OnCollision::Disambiguate))
+            .unwrap();
+        assert_eq!(
+            destinations(&plan),
+            ["Artist/Title (track 1).mp3", "Artist/Title (track 2).mp3"]
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let plan = colliding(dir.path(), true)
+            .plan_sort(None, &options(FORMAT, OnCollision::Disambiguate))
+            .unwrap();
+        assert_eq!(
+            destinations(&plan),
+            ["Artist/Title (track 1).mp3", "Artist/Title (track 2).mp3"]
+        );
+    }
+
+    #[test]
+    fn disambiguate_falls_back_to_the_uuid() {
+        let dir = tempfile::tempdir().unwrap();
+        song(dir.path(), "a.mp3", "Title", "Artist", None);
+        song(dir.path(), "b.mp3", "Title", "Artist", None);
+        let music_dir = init(dir.path());
+        let plan = music_dir
+            .plan_sort(None, &options(FORMAT, OnCollision::Disambiguate))
+            .unwrap();
+        assert_eq!(plan.steps.len(), 2);
+        for step in &plan.steps {
+            let song = music_dir
+                .songs
+                .iter()
+                .find(|s| s.path == step.source)
+                .unwrap();
+            let uuid = song.uuid.as_ref().unwrap().0.simple().to_string();
+            let name = step.destination.file_name().unwrap().to_string_lossy();
+            assert_eq!(name, format!("Title ({}).mp3", &uuid[..8]));
+        }
+    }
+
+    #[test]
+    fn apply_plan_moves_the_songs() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = song(dir.path(), "a.mp3", "Title", "Artist", None);
+        let mut music_dir = init(dir.path());
+        let plan = music_dir
+            .plan_sort(None, &options(FORMAT, OnCollision::Abort))
+            .unwrap();
+        music_dir.apply_plan(&plan, false, false).unwrap();
+        assert!(!a.exists());
+        assert!(dir.path().join("Artist/Title.mp3").exists());
+        assert_eq!(music_dir.songs[0].path, dir.path().join("Artist/Title.mp3"));
+    }
+
+    #[test]
+    fn apply_plan_rejects_stale_plans() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = song(dir.path(), "a.mp3", "Title", "Artist", None);
+        let mut music_dir = init(dir.path());
+        let plan = music_dir
+            .plan_sort(None, &options(FORMAT, OnCollision::Abort))
+            .unwrap();
+
+        //a file arrived at the destination since planning
+        test_util::song(dir.path(), "Artist/Title.mp3");
+        let error = music_dir.apply_plan(&plan, false, false).unwrap_err();
+        assert!(error.to_string().contains("already exists"), "{error}");
+        std::fs::remove_file(dir.path().join("Artist/Title.mp3")).unwrap();
+
+        //the source left since planning
+        std::fs::remove_file(&a).unwrap();
+        let error = music_dir.apply_plan(&plan, false, false).unwrap_err();
+        assert!(error.to_string().contains("no longer exists"), "{error}");
+    }
+
+    #[test]
+    fn apply_plan_rejects_plans_for_another_music_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let other = tempfile::tempdir().unwrap();
+        song(dir.path(), "a.mp3", "Title", "Artist", None);
+        song(other.path(), "a.mp3", "Title", "Artist", None);
+        let plan = init(dir.path())
+            .plan_sort(None, &options(FORMAT, OnCollision::Abort))
+            .unwrap();
+        let error = init(other.path())
+            .apply_plan(&plan, false, false)
+            .unwrap_err();
+        assert!(error.to_string().contains("plan was made for"), "{error}");
+        assert!(other.path().join("a.mp3").exists());
+    }
+
+    #[test]
+    fn lyrics_follow_their_song_and_shared_sidecars_stay() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        song(root, "rip/a.mp3", "a", "Artist", None);
+        //already in place, so `rip` keeps a song and its cover
+        song(root, "rip/b.mp3", "b", "rip", None);
+        std::fs::write(root.join("rip/a.lrc"), "[00:00.00]a").unwrap();
+        std::fs::write(root.join("rip/cover.jpg"), "jpg").unwrap();
+        let mut options = options(FORMAT, OnCollision::Abort);
+        options.sidecars = true;
+        let plan = init(root).plan_sort(None, &options).unwrap();
+        assert_eq!(destinations(&plan), ["Artist/a.lrc", "Artist/a.mp3"]);
+    }
+
+    #[test]
+    fn shared_sidecars_go_where_most_songs_go() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        song(root, "rip/a.mp3", "a", "Artist", None);
+        song(root, "rip/b.mp3", "b", "Artist", None);
+        std::fs::write(root.join("rip/cover.jpg"), "jpg").unwrap();
+        std::fs::write(root.join("rip/.hidden.jpg"), "jpg").unwrap();
+        let mut options = options(FORMAT, OnCollision::Abort);
+        options.sidecars = true;
+        let plan = init(root).plan_sort(None, &options).unwrap();
+        assert_eq!(
+            destinations(&plan),
+            ["Artist/a.mp3", "Artist/b.mp3", "Artist/cover.jpg"]
+        );
+    }
+
+    #[test]
+    fn emptied_directories_are_pruned() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        song(root, "old/sub/a.mp3", "a", "Artist", None);
+        //keeps `kept` from being emptied
+        song(root, "kept/b.mp3", "b", "Artist", None);
+        std::fs::write(root.join("kept/notes.txt"), "notes").unwrap();
+        //files leave `Artist/x` but others arrive in `Artist`
+        song(root, "Artist/x/c.mp3", "c", "Artist", None);
+        let mut options = options(FORMAT, OnCollision::Abort);
+        options.prune = true;
+        let mut music_dir = init(root);
+        let plan = music_dir.plan_sort(None, &options).unwrap();
+        assert_eq!(
+            plan.emptied_dirs,
+            [
+                root.join("old/sub"),
+                root.join("old"),
+                root.join("Artist/x")
+            ]
+        );
+        music_dir.apply_plan(&plan, false, false).unwrap();
+        assert!(!root.join("old").exists());
+        assert!(!root.join("Artist/x").exists());
+        assert!(root.join("kept/notes.txt").exists());
+        assert!(root.join("Artist/c.mp3").exists());
+    }
+
+    #[test]
+    fn copying_leaves_directories_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        song(dir.path(), "old/a.mp3", "a", "Artist", None);
+        let mut options = options(FORMAT, OnCollision::Abort);
+        options.prune = true;
+        let plan = init(dir.path())
+            .plan_sort(Some(destination.path().join("copy")), &options)
+            .unwrap();
+        assert!(plan.emptied_dirs.is_empty());
+        assert_eq!(
+            plan.steps[0].destination,
+            destination.path().join("copy/Artist/a.mp3")
+        );
+    }
+
+    ///a plan for `a.mp3` and `b.mp3` that was stored but never run, as if interrupted
+    fn interrupted(root: &Path) -> (MusicDir, Plan) {
+        song(root, "a.mp3", "a", "Artist", None);
+        song(root, "b.mp3", "b", "Artist", None);
+        let music_dir = init(root);
+        let plan = music_dir
+            .plan_sort(None, &options(FORMAT, OnCollision::Abort))
+            .unwrap();
+        music_dir.db.start_sort(&plan).unwrap();
+        (music_dir, plan)
+    }
+
+    ///the music dir as the next run would find it
+    fn reopen(root: &Path, music_dir: MusicDir) -> MusicDir {
+        drop(music_dir);
+        MusicDir::open(root, Scan::default()).unwrap()
+    }
+
+    ///move the step with `source` by hand and mark it done
+    fn finish_step(music_dir: &MusicDir, plan: &Plan, source: &Path) {
+        let i = plan.steps.iter().position(|s| s.source == source).unwrap();
+        std::fs::create_dir_all(plan.steps[i].destination.parent().unwrap()).unwrap();
+        std::fs::rename(source, &plan.steps[i].destination).unwrap();
+        music_dir.db.mark_step(i, StepStatus::Done).unwrap();
+    }
+
+    #[test]
+    fn resume_finishes_the_remaining_steps() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let (music_dir, plan) = interrupted(root);
+        //the second step got as far as the rename before the interruption
+        finish_step(&music_dir, &plan, &root.join("a.mp3"));
+        std::fs::rename(root.join("b.mp3"), root.join("Artist/b.mp3")).unwrap();
+
+        let mut music_dir = reopen(root, music_dir);
+        music_dir.resume_sort(false, false).unwrap();
+        assert!(root.join("Artist/a.mp3").exists());
+        assert!(root.join("Artist/b.mp3").exists());
+        assert!(music_dir.db.unfinished_sort().unwrap().is_none());
+        assert_eq!(music_dir.db.history().unwrap()[0].1.moves.len(), 2);
+    }
+
+    #[test]
+    fn resume_skips_steps_whose_files_are_gone() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let (music_dir, _) = interrupted(root);
+        std::fs::remove_file(root.join("b.mp3")).unwrap();
+
+        let mut music_dir = reopen(root, music_dir);
+        music_dir.resume_sort(false, false).unwrap();
+        assert!(root.join("Artist/a.mp3").exists());
+        assert!(music_dir.db.unfinished_sort().unwrap().is_none());
+        let history = music_dir.db.history().unwrap();
+        assert_eq!(history[0].1.moves.len(), 1);
+        assert_eq!(history[0].1.moves[0].new_path.to_string(), "Artist/a.mp3");
+    }
+
+    #[test]
+    fn new_sorts_wait_for_the_interrupted_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut music_dir, plan) = interrupted(dir.path());
+        let error = music_dir.apply_plan(&plan, false, false).unwrap_err();
+        assert!(error.to_string().contains("--abandon"), "{error}");
+    }
+
+    #[test]
+    fn abandon_keeps_and_journals_the_finished_steps() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let (music_dir, plan) = interrupted(root);
+        finish_step(&music_dir, &plan, &root.join("a.mp3"));
+
+        let mut music_dir = reopen(root, music_dir);
+        music_dir.abandon_sort(false).unwrap();
+        assert!(root.join("Artist/a.mp3").exists());
+        assert!(root.join("b.mp3").exists());
+        assert!(music_dir.db.unfinished_sort().unwrap().is_none());
+        assert_eq!(music_dir.db.history().unwrap()[0].1.moves.len(), 1);
+        let error = music_dir.abandon_sort(false).unwrap_err();
+        assert!(error.to_string().contains("no interrupted sort"), "{error}");
+
+        //the abandoned sort can be undone, and a new one started
+        music_dir.undo(None).unwrap();
+        assert!(root.join("a.mp3").exists());
+        music_dir
+            .sort(None, false, false, &options(FORMAT, OnCollision::Abort))
+            .unwrap();
+        assert!(root.join("Artist/a.mp3").exists());
+        assert!(root.join("Artist/b.mp3").exists());
+    }
+}
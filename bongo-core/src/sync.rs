@@ -0,0 +1,505 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use relative_path::RelativePath;
+
+use crate::{
+    hash,
+    song::MusicDir,
+    sort::{relative, SortOptions},
+    transcode::{self, Preset},
+    transfer,
+};
+
+///what bongo synced to a destination, stored in the destination itself
+pub const MANIFESTNAME: &str = ".bongo-sync.json";
+
+///the songs synced to a destination by uuid
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct Manifest {
+    pub songs: BTreeMap<uuid::Uuid, Synced>,
+}
+
+///a song as it was when it was last synced
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Synced {
+    ///relative to the destination
+    pub path: RelativePath,
+    ///size of the source in bytes
+    pub size: u64,
+    ///modification time of the source in seconds since the unix epoch
+    pub modified: u64,
+    ///what the source was transcoded with
+    #[serde(default)]
+    pub preset: Option<Preset>,
+}
+
+impl Synced {
+    fn new(path: RelativePath, source: &Path, preset: Option<Preset>) -> std::io::Result<Self> {
+        let (size, modified) = hash::stamp(source)?;
+        Ok(Self {
+            path,
+            size,
+            modified,
+            preset,
+        })
+    }
+    ///whether the source looks unchanged since this sync
+    fn same_stamp(&self, other: &Self) -> bool {
+        self.size == other.size && self.modified == other.modified && self.preset == other.preset
+    }
+}
+
+impl Manifest {
+    ///the manifest in `destination`, empty if nothing was synced there yet
+    pub fn load(destination: &Path) -> anyhow::Result<Self> {
+        let path = destination.join(MANIFESTNAME);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        serde_json::from_str(&std::fs::read_to_string(&path)?)
+            .map_err(|e| anyhow::anyhow!("invalid manifest '{}'. {e}", path.to_string_lossy()))
+    }
+    fn save(&self, destination: &Path) -> anyhow::Result<()> {
+        let path = destination.join(MANIFESTNAME);
+        let temp = destination.join(format!("{MANIFESTNAME}.tmp"));
+        std::fs::write(&temp, serde_json::to_string_pretty(self)?)?;
+        std::fs::rename(temp, path)?;
+        Ok(())
+    }
+}
+
+///a change to the destination
+#[derive(Debug)]
+pub enum SyncAction {
+    ///a new or changed song
+    Copy {
+        source: PathBuf,
+        destination: PathBuf,
+        transcode: Option<Preset>,
+    },
+    ///a synced song whose sorted path changed
+    Move { from: PathBuf, to: PathBuf },
+    ///a synced song that is no longer in the music dir
+    Delete(PathBuf),
+}
+
+///everything a sync would change, see [`MusicDir::plan_sync`]
+#[derive(Debug)]
+pub struct SyncPlan {
+    pub destination: PathBuf,
+    pub actions: Vec<SyncAction>,
+    ///songs that are already up to date
+    pub unchanged: usize,
+    ///the manifest to save once every action is done
+    pub manifest: Manifest,
+}
+
+impl std::fmt::Display for SyncPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let relative = |path: &Path| {
+            path.strip_prefix(&self.destination)
+                .unwrap_or(path)
+                .to_string_lossy()
+                .into_owned()
+        };
+        let (mut copies, mut moves, mut deletes) = (0, 0, 0);
+        for action in &self.actions {
+            match action {
+                SyncAction::Copy {
+                    source,
+                    destination,
+                    transcode,
+                } => {
+                    copies += 1;
+                    let transcode = transcode
+                        .map(|preset| format!(" ({preset})"))
+                        .unwrap_or_default();
+                    writeln!(
+                        f,
+                        "copy   {}  ->  {}{transcode}",
+                        source.to_string_lossy(),
+                        relative(destination)
+                    )?;
+                }
+                SyncAction::Move { from, to } => {
+                    moves += 1;
+                    writeln!(f, "move   {}  ->  {}", relative(from), relative(to))?;
+                }
+                SyncAction::Delete(path) => {
+                    deletes += 1;
+                    writeln!(f, "delete {}", relative(path))?;
+                }
+            }
+        }
+        write!(
+            f,
+            "{copies} to copy, {moves} to move, {deletes} to delete, {} up to date",
+            self.unchanged
+        )
+    }
+}
+
+impl MusicDir {
+    ///work out how to make `destination` a sorted mirror of this music dir.
+    ///Songs are tracked by uuid, so renamed songs are moved rather than copied again.
+    ///With `delete`, songs that are no longer in the music dir are removed from `destination`
+    pub fn plan_sync(
+        &self,
+        destination: &Path,
+        options: &SortOptions,
+        delete: bool,
+    ) -> anyhow::Result<SyncPlan> {
+        if destination == self.root {
+            anyhow::bail!("source and destination directories are the same");
+        }
+        if destination.exists() && !destination.is_dir() {
+            anyhow::bail!("destination is not a directory");
+        }
+        let mut previous = Manifest::load(destination)?;
+        let mut manifest = Manifest::default();
+        let mut actions = Vec::new();
+        let mut unchanged = 0;
+        let mut claimed = HashSet::new();
+        for (path, song) in self.song_paths(options)? {
+            let Some(uuid) = &song.uuid else {
+                tracing::warn!(
+                    "skipping '{}', it has no uuid. Run `bongo update` first",
+                    song.path.to_string_lossy()
+                );
+                continue;
+            };
+            let uuid = uuid.0;
+            let preset = options.transcode.preset(&song.path);
+            let mut target = path.rebase(destination.to_path_buf());
+            if let Some(preset) = preset {
+                target.set_extension(preset.extension());
+            }
+            let synced = Synced::new(relative(destination, &target)?, &song.path, preset)?;
+            //compare against what would be written, the cached transcode for transcoded songs
+            let matches = |copy: &Path| -> anyhow::Result<bool> {
+                match preset {
+                    Some(preset) => {
+                        let cached = self.cached_transcode(&song.path, preset)?;
+                        Ok(cached.exists() && transfer::same_contents(&cached, copy)?)
+                    }
+                    None => Ok(transfer::same_contents(&song.path, copy)?),
+                }
+            };
+            let last = previous.songs.remove(&uuid);
+            let taken = |last: Option<Synced>, manifest: &mut Manifest| {
+                tracing::warn!(
+                    "skipping '{}', '{}' is taken",
+                    song.path.to_string_lossy(),
+                    target.to_string_lossy()
+                );
+                //keep tracking whatever was synced before
+                if let Some(last) = last {
+                    manifest.songs.insert(uuid, last);
+                }
+            };
+            if !claimed.insert(target.clone()) {
+                taken(last, &mut manifest);
+                continue;
+            }
+            let current = last
+                .as_ref()
+                .map(|l| l.path.rebase(destination.to_path_buf()))
+                .filter(|p| p.exists());
+            let up_to_date = match current {
+                Some(current) if current != target => {
+                    if target.exists() {
+                        taken(last, &mut manifest);
+                        continue;
+                    }
+                    let up_to_date =
+                        last.as_ref().is_some_and(|l| l.same_stamp(&synced)) || matches(&current)?;
+                    actions.push(SyncAction::Move {
+                        from: current,
+                        to: target.clone(),
+                    });
+                    up_to_date
+                }
+                Some(current) => {
+                    last.as_ref().is_some_and(|l| l.same_stamp(&synced)) || matches(&current)?
+                }
+                //a file bongo didn't sync, or a sync that was interrupted before saving its manifest
+                None if target.exists() => {
+                    if !matches(&target)? {
+                        taken(last, &mut manifest);
+                        continue;
+                    }
+                    true
+                }
+                None => false,
+            };
+            if up_to_date {
+                unchanged += 1;
+            } else {
+                actions.push(SyncAction::Copy {
+                    source: song.path.clone(),
+                    destination: target,
+                    transcode: preset,
+                });
+            }
+            manifest.songs.insert(uuid, synced);
+        }
+        for (uuid, last) in previous.songs {
+            if !delete {
+                manifest.songs.insert(uuid, last);
+                continue;
+            }
+            let path = last.path.rebase(destination.to_path_buf());
+            if path.exists() {
+                actions.push(SyncAction::Delete(path));
+            }
+        }
+        Ok(SyncPlan {
+            destination: destination.to_path_buf(),
+            actions,
+            unchanged,
+            manifest,
+        })
+    }
+
+    ///carry out a plan made by [`MusicDir::plan_sync`]
+    pub fn apply_sync(&self, plan: &SyncPlan, options: &SortOptions) -> anyhow::Result<()> {
+        std::fs::create_dir_all(&plan.destination)?;
+        for action in &plan.actions {
+            match action {
+                SyncAction::Copy {
+                    source,
+                    destination,
+                    transcode,
+                } => {
+                    tracing::info!(
+                        "copying '{}' to '{}'",
+                        source.to_string_lossy(),
+                        destination.to_string_lossy()
+                    );
+                    std::fs::create_dir_all(destination.parent().unwrap())?;
+                    //planning made sure anything at the destination is an outdated copy
+                    match (transcode, options.link) {
+                        (Some(preset), _) => transcode::transcode(
+                            source,
+                            destination,
+                            *preset,
+                            &self.cached_transcode(source, *preset)?,
+                            true,
+                        )?,
+                        (None, Some(link)) => transfer::link_file(source, destination, link, true)?,
+                        (None, None) => transfer::copy_file(source, destination, true)?,
+                    }
+                }
+                SyncAction::Move { from, to } => {
+                    tracing::info!(
+                        "moving '{}' to '{}'",
+                        from.to_string_lossy(),
+                        to.to_string_lossy()
+                    );
+                    std::fs::create_dir_all(to.parent().unwrap())?;
+                    transfer::move_file(from, to)?;
+                    prune_parents(from, &plan.destination);
+                }
+                SyncAction::Delete(path) => {
+                    tracing::info!("removing '{}'", path.to_string_lossy());
+                    std::fs::remove_file(path)?;
+                    prune_parents(path, &plan.destination);
+                }
+            }
+        }
+        //only saved once everything succeeded, an interrupted sync is picked up by comparing contents
+        plan.manifest.save(&plan.destination)?;
+        self.prune_transcodes();
+        Ok(())
+    }
+}
+
+///remove the directories above `path` that are now empty, up to `root`
+fn prune_parents(path: &Path, root: &Path) {
+    for dir in path.ancestors().skip(1) {
+        if dir == root || !dir.starts_with(root) || std::fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use lofty::{Accessor, AudioFile, TaggedFileExt};
+
+    use super::{Manifest, SyncAction, SyncPlan, MANIFESTNAME};
+    use crate::{scan::Scan, song::MusicDir, sort::SortOptions, template::Template, test_util};
+
+    fn options() -> SortOptions {
+        SortOptions {
+            template: "{artist}/{filename}.{ext}".parse::<Template>().unwrap(),
+            ..SortOptions::default()
+        }
+    }
+
+    ///a music dir with `a.mp3` and `b.mp3` by `Artist`
+    fn library(root: &Path) -> MusicDir {
+        test_util::tagged_song(root, "a.mp3", "a", "Artist");
+        test_util::tagged_song(root, "b.mp3", "b", "Artist");
+        MusicDir::init(root.to_path_buf(), false, Scan::default()).unwrap()
+    }
+
+    ///the music dir as the next run would find it
+    fn reopen(root: &Path, music_dir: MusicDir) -> MusicDir {
+        drop(music_dir);
+        MusicDir::open(root, Scan::default()).unwrap()
+    }
+
+    ///the actions of `plan`, with paths relative to its destination
+    fn actions(plan: &SyncPlan) -> Vec<String> {
+        let relative = |path: &Path| {
+            path.strip_prefix(&plan.destination)
+                .unwrap()
+                .to_string_lossy()
+                .into_owned()
+        };
+        plan.actions
+            .iter()
+            .map(|action| match action {
+                SyncAction::Copy { destination, .. } => format!("copy {}", relative(destination)),
+                SyncAction::Move { from, to } => {
+                    format!("move {} {}", relative(from), relative(to))
+                }
+                SyncAction::Delete(path) => format!("delete {}", relative(path)),
+            })
+            .collect()
+    }
+
+    ///plan and apply a sync, returning the plan
+    fn sync(music_dir: &MusicDir, destination: &Path, delete: bool) -> SyncPlan {
+        let plan = music_dir
+            .plan_sync(destination, &options(), delete)
+            .unwrap();
+        music_dir.apply_sync(&plan, &options()).unwrap();
+        plan
+    }
+
+    #[test]
+    fn first_sync_copies_every_song() {
+        let dir = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let destination = destination.path();
+        let music_dir = library(dir.path());
+
+        let plan = sync(&music_dir, destination, false);
+        assert_eq!(actions(&plan), ["copy Artist/a.mp3", "copy Artist/b.mp3"]);
+        assert_eq!(
+            std::fs::read(destination.join("Artist/a.mp3")).unwrap(),
+            std::fs::read(dir.path().join("a.mp3")).unwrap()
+        );
+        assert_eq!(Manifest::load(destination).unwrap().songs.len(), 2);
+
+        let plan = music_dir.plan_sync(destination, &options(), false).unwrap();
+        assert!(plan.actions.is_empty());
+        assert_eq!(plan.unchanged, 2);
+    }
+
+    #[test]
+    fn renamed_songs_are_moved_without_copying() {
+        let dir = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let destination = destination.path();
+        let music_dir = library(dir.path());
+        sync(&music_dir, destination, false);
+        std::fs::rename(dir.path().join("a.mp3"), dir.path().join("c.mp3")).unwrap();
+
+        let music_dir = reopen(dir.path(), music_dir);
+        let plan = sync(&music_dir, destination, false);
+        assert_eq!(actions(&plan), ["move Artist/a.mp3 Artist/c.mp3"]);
+        assert_eq!(plan.unchanged, 2);
+        assert!(!destination.join("Artist/a.mp3").exists());
+        assert_eq!(
+            std::fs::read(destination.join("Artist/c.mp3")).unwrap(),
+            std::fs::read(dir.path().join("c.mp3")).unwrap()
+        );
+    }
+
+    #[test]
+    fn retagged_songs_replace_their_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let destination = destination.path();
+        let music_dir = library(dir.path());
+        sync(&music_dir, destination, false);
+        let a = dir.path().join("a.mp3");
+        let mut file = lofty::read_from_path(&a).unwrap();
+        file.primary_tag_mut()
+            .unwrap()
+            .set_title("a longer title".to_owned());
+        file.save_to_path(&a).unwrap();
+        //padding may keep the size, and this may all happen within a second
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(60);
+        std::fs::File::options()
+            .write(true)
+            .open(&a)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+
+        let music_dir = reopen(dir.path(), music_dir);
+        let plan = sync(&music_dir, destination, false);
+        assert_eq!(actions(&plan), ["copy Artist/a.mp3"]);
+        assert_eq!(plan.unchanged, 1);
+        assert_eq!(
+            std::fs::read(destination.join("Artist/a.mp3")).unwrap(),
+            std::fs::read(&a).unwrap()
+        );
+    }
+
+    #[test]
+    fn delete_removes_songs_that_left_and_keeps_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let destination = destination.path();
+        let music_dir = library(dir.path());
+        sync(&music_dir, destination, false);
+        std::fs::write(destination.join("Artist/notes.txt"), "notes").unwrap();
+        std::fs::remove_file(dir.path().join("b.mp3")).unwrap();
+
+        let music_dir = reopen(dir.path(), music_dir);
+        //without --delete the copy stays and is still tracked
+        let plan = sync(&music_dir, destination, false);
+        assert!(plan.actions.is_empty());
+        assert_eq!(Manifest::load(destination).unwrap().songs.len(), 2);
+
+        let plan = sync(&music_dir, destination, true);
+        assert_eq!(actions(&plan), ["delete Artist/b.mp3"]);
+        assert!(!destination.join("Artist/b.mp3").exists());
+        assert!(destination.join("Artist/a.mp3").exists());
+        assert!(destination.join("Artist/notes.txt").exists());
+        assert_eq!(Manifest::load(destination).unwrap().songs.len(), 1);
+    }
+
+    #[test]
+    fn interrupted_syncs_pick_up_where_they_stopped() {
+        let dir = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let destination = destination.path();
+        let music_dir = library(dir.path());
+        sync(&music_dir, destination, false);
+        //interrupted after copying `a.mp3`, while writing the manifest
+        std::fs::remove_file(destination.join(MANIFESTNAME)).unwrap();
+        std::fs::remove_file(destination.join("Artist/b.mp3")).unwrap();
+        std::fs::write(
+            destination.join(format!("{MANIFESTNAME}.tmp")),
+            "{\"songs\": {",
+        )
+        .unwrap();
+
+        let plan = sync(&music_dir, destination, false);
+        assert_eq!(actions(&plan), ["copy Artist/b.mp3"]);
+        assert_eq!(plan.unchanged, 1);
+        assert_eq!(Manifest::load(destination).unwrap().songs.len(), 2);
+        assert!(!destination.join(format!("{MANIFESTNAME}.tmp")).exists());
+    }
+}
@@ -0,0 +1,464 @@
+use std::str::FromStr;
+
+use lofty::{Accessor, ItemKey, Tag};
+
+use crate::song::{GetTags, Song};
+
+///the layout bongo has always used
+pub const DEFAULT_FORMAT: &str =
+    r#"{artist|"UnknownArtist"}/{album|"Singles"}/{title|filename}.{ext}"#;
+
+///fields that can be used in a template
+const FIELDS: [&str; 11] = [
+    "title",
+    "artist",
+    "albumartist",
+    "album",
+    "year",
+    "track",
+    "disc",
+    "genre",
+    "compilation",
+    "filename",
+    "ext",
+];
+
+///a sort path template such as `{albumartist|artist}/{year} - {album}/{disc:02}-{track:02} {title}`.
+///
+///`{a|b|"text"}` uses the first of `a`, `b` or the literal `text` that is set.
+///`{track:02}` zero pads numbers to two digits.
+///`{if compilation}..{else}..{end}` renders one of two branches depending on whether a field is set.
+///`/` in the template separates directories
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template(Vec<Segment>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Text(String),
+    Field {
+        sources: Vec<Source>,
+        pad: Option<usize>,
+    },
+    If {
+        field: String,
+        then: Vec<Segment>,
+        otherwise: Vec<Segment>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Source {
+    Field(String),
+    Literal(String),
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum Error {
+    #[error("unclosed '{{' in template")]
+    Unclosed,
+    #[error("unknown field '{0}' in template. Expected one of {:?}", FIELDS)]
+    UnknownField(String),
+    #[error("invalid expression '{{{0}}}' in template")]
+    InvalidExpression(String),
+    #[error("'{{{0}}}' without a matching '{{if}}'")]
+    Unmatched(&'static str),
+    #[error("'{{if}}' without a matching '{{end}}'")]
+    MissingEnd,
+}
+
+impl Template {
+    ///render the directories and file name of `song`
+    /// # Errors
+    ///   [`crate::song::Error`] if the song is untagged
+    pub fn render(&self, song: &Song) -> Result<Vec<String>, crate::song::Error> {
+        let tags = song.tagged.get_tag(&song.path)?;
+        let mut components = vec![String::new()];
+        render(&self.0, &|field| lookup(song, tags, field), &mut components);
+        Ok(components)
+    }
+}
+
+impl Default for Template {
+    fn default() -> Self {
+        DEFAULT_FORMAT.parse().expect("default template is valid")
+    }
+}
+
+impl FromStr for Template {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        //each open `{if}` is a frame holding the field, its `then` branch and, after `{else}`, its `else` branch
+        let mut frames: Vec<(String, Vec<Segment>, Option<Vec<Segment>>)> = Vec::new();
+        let mut root = Vec::new();
+        let mut text = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut expr = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => expr.push(c),
+                            None => return Err(Error::Unclosed),
+                        }
+                    }
+                    let current = match frames.last_mut() {
+                        Some((_, _, Some(otherwise))) => otherwise,
+                        Some((_, then, None)) => then,
+                        None => &mut root,
+                    };
+                    if !text.is_empty() {
+                        current.push(Segment::Text(std::mem::take(&mut text)));
+                    }
+                    let expr = expr.trim();
+                    if let Some(field) = expr.strip_prefix("if ") {
+                        frames.push((field_name(field.trim())?, Vec::new(), None));
+                    } else if expr == "else" {
+                        match frames.last_mut() {
+                            Some((_, _, otherwise @ None)) => *otherwise = Some(Vec::new()),
+                            _ => return Err(Error::Unmatched("else")),
+                        }
+                    } else if expr == "end" {
+                        let (field, then, otherwise) =
+                            frames.pop().ok_or(Error::Unmatched("end"))?;
+                        let segment = Segment::If {
+                            field,
+                            then,
+                            otherwise: otherwise.unwrap_or_default(),
+                        };
+                        match frames.last_mut() {
+                            Some((_, _, Some(otherwise))) => otherwise.push(segment),
+                            Some((_, then, None)) => then.push(segment),
+                            None => root.push(segment),
+                        }
+                    } else {
+                        current.push(parse_field(expr)?);
+                    }
+                }
+                c => text.push(c),
+            }
+        }
+        if !frames.is_empty() {
+            return Err(Error::MissingEnd);
+        }
+        if !text.is_empty() {
+            root.push(Segment::Text(text));
+        }
+        Ok(Self(root))
+    }
+}
+
+///parse `a|b|"text":02`
+fn parse_field(expr: &str) -> Result<Segment, Error> {
+    let invalid = || Error::InvalidExpression(expr.to_owned());
+    let mut sources = Vec::new();
+    let mut chars = expr.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.next_if_eq(&'"').is_some() {
+            let mut literal = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some(c) => literal.push(c),
+                    None => return Err(invalid()),
+                }
+            }
+            sources.push(Source::Literal(literal));
+        } else {
+            let mut name = String::new();
+            while let Some(c) = chars.next_if(|c| c.is_alphanumeric() || *c == '_') {
+                name.push(c);
+            }
+            if name.is_empty() {
+                return Err(invalid());
+            }
+            sources.push(Source::Field(field_name(&name)?));
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        match chars.next() {
+            Some('|') => continue,
+            Some(':') => {
+                let width = chars.collect::<String>();
+                let pad = width.trim().parse().map_err(|_| invalid())?;
+                return Ok(Segment::Field {
+                    sources,
+                    pad: Some(pad),
+                });
+            }
+            None => return Ok(Segment::Field { sources, pad: None }),
+            Some(_) => return Err(invalid()),
+        }
+    }
+}
+
+fn field_name(name: &str) -> Result<String, Error> {
+    let name = name.to_lowercase();
+    if FIELDS.contains(&name.as_str()) {
+        Ok(name)
+    } else {
+        Err(Error::UnknownField(name))
+    }
+}
+
+///render `segments` into `components`, starting a new component at every `/` in literal text
+fn render(
+    segments: &[Segment],
+    lookup: &dyn Fn(&str) -> Option<String>,
+    components: &mut Vec<String>,
+) {
+    for segment in segments {
+        match segment {
+            Segment::Text(text) => {
+                let mut parts = text.split('/');
+                if let Some(first) = parts.next() {
+                    push(components, first);
+                }
+                for part in parts {
+                    components.push(part.to_owned());
+                }
+            }
+            Segment::Field { sources, pad } => {
+                let value = sources
+                    .iter()
+                    .find_map(|source| match source {
+                        Source::Field(field) => lookup(field),
+                        Source::Literal(literal) => Some(literal.clone()),
+                    })
+                    .unwrap_or_default();
+                let value = match (*pad, value.parse::<u64>()) {
+                    (Some(width), Ok(number)) => format!("{number:0width$}"),
+                    _ => value,
+                };
+                push(components, &value);
+            }
+            Segment::If {
+                field,
+                then,
+                otherwise,
+            } => {
+                let set = lookup(field).is_some_and(|v| !matches!(v.as_str(), "0" | "false"));
+                render(if set { then } else { otherwise }, lookup, components);
+            }
+        }
+    }
+}
+
+fn push(components: &mut [String], s: &str) {
+    components
+        .last_mut()
+        .expect("there is always a component")
+        .push_str(s);
+}
+
+///the value of `field` for `song`, `None` if it is missing or empty
+fn lookup(song: &Song, tags: &Tag, field: &str) -> Option<String> {
+    let text = |key| tags.get_string(&key).map(ToOwned::to_owned);
+    let value = match field {
+        "title" => tags.title().map(std::borrow::Cow::into_owned),
+        "artist" => tags.artist().map(std::borrow::Cow::into_owned),
+        "albumartist" => text(ItemKey::AlbumArtist),
+        "album" => tags.album().map(std::borrow::Cow::into_owned),
+        "year" => tags.year().as_ref().map(ToString::to_string),
+        "track" => tags.track().as_ref().map(ToString::to_string),
+        "disc" => tags.disk().as_ref().map(ToString::to_string),
+        "genre" => tags.genre().map(std::borrow::Cow::into_owned),
+        "compilation" => text(ItemKey::FlagCompilation),
+        "filename" => song
+            .path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned()),
+        "ext" => song
+            .path
+            .extension()
+            .map(|s| s.to_string_lossy().into_owned()),
+        _ => unreachable!("fields are checked when parsing"),
+    };
+    value.filter(|v| !v.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{render, Error, Template, DEFAULT_FORMAT};
+
+    ///render `template` with the fields in `values`
+    fn render_with(template: &str, values: &[(&str, &str)]) -> Vec<String> {
+        let template = template.parse::<Template>().unwrap();
+        let values = values.iter().copied().collect::<HashMap<_, _>>();
+        let lookup = |field: &str| values.get(field).map(|v| (*v).to_owned());
+        let mut components = vec![String::new()];
+        render(&template.0, &lookup, &mut components);
+        components
+    }
+
+    #[test]
+    fn slashes_separate_directories() {
+        let values = [
+            ("artist", "Artist"),
+            ("album", "Album"),
+            ("title", "Title"),
+            ("ext", "flac"),
+        ];
+        assert_eq!(
+            render_with(DEFAULT_FORMAT, &values),
+            ["Artist", "Album", "Title.flac"]
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_first_set_source() {
+        let template = r#"{albumartist|artist|"Nobody"}"#;
+        assert_eq!(render_with(template, &[("artist", "Artist")]), ["Artist"]);
+        assert_eq!(
+            render_with(template, &[("albumartist", "Band"), ("artist", "Artist")]),
+            ["Band"]
+        );
+        assert_eq!(render_with(template, &[]), ["Nobody"]);
+        assert_eq!(render_with("{title}", &[]), [""]);
+    }
+
+    #[test]
+    fn literals_keep_spaces_and_special_characters() {
+        assert_eq!(
+            render_with(r#"{album|"No | Album: 1"}"#, &[]),
+            ["No | Album: 1"]
+        );
+    }
+
+    #[test]
+    fn pads_numbers() {
+        assert_eq!(render_with("{track:02}", &[("track", "3")]), ["03"]);
+        assert_eq!(render_with("{track:02}", &[("track", "123")]), ["123"]);
+        assert_eq!(render_with("{disc:03}", &[("disc", "12")]), ["012"]);
+        //only numbers are padded
+        assert_eq!(render_with("{title:04}", &[("title", "ab")]), ["ab"]);
+    }
+
+    #[test]
+    fn conditionals_pick_a_branch() {
+        let template = r#"{if compilation}Various{else}{artist}{end}/{title}"#;
+        let values = [("artist", "Artist"), ("title", "Title")];
+        assert_eq!(render_with(template, &values), ["Artist", "Title"]);
+        let mut compilation = values.to_vec();
+        compilation.push(("compilation", "1"));
+        assert_eq!(render_with(template, &compilation), ["Various", "Title"]);
+        //flags stored as 0 or false count as unset
+        compilation.pop();
+        compilation.push(("compilation", "0"));
+        assert_eq!(render_with(template, &compilation), ["Artist", "Title"]);
+    }
+
+    #[test]
+    fn conditionals_without_else() {
+        let template = "{if year}{year} - {end}{album}";
+        assert_eq!(
+            render_with(template, &[("year", "2001"), ("album", "Album")]),
+            ["2001 - Album"]
+        );
+        assert_eq!(render_with(template, &[("album", "Album")]), ["Album"]);
+    }
+
+    #[test]
+    fn nested_conditionals() {
+        let template = "{if album}{if disc}{disc}-{else}x{end}{track}{else}single{end}";
+        assert_eq!(
+            render_with(template, &[("album", "A"), ("disc", "2"), ("track", "5")]),
+            ["2-5"]
+        );
+        assert_eq!(
+            render_with(template, &[("album", "A"), ("track", "5")]),
+            ["x5"]
+        );
+        assert_eq!(render_with(template, &[("track", "5")]), ["single"]);
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        assert_eq!(
+            render_with("{{{title}}}", &[("title", "Title")]),
+            ["{Title}"]
+        );
+        assert_eq!(render_with("a{{b}}c", &[]), ["a{b}c"]);
+    }
+
+    #[test]
+    fn fields_are_case_insensitive() {
+        assert_eq!(render_with("{Title}", &[("title", "Title")]), ["Title"]);
+    }
+
+    #[test]
+    fn default_template_parses() {
+        assert_eq!(Template::default(), DEFAULT_FORMAT.parse().unwrap());
+    }
+
+    #[test]
+    fn rejects_unclosed_braces() {
+        assert_eq!("{artist".parse::<Template>(), Err(Error::Unclosed));
+        assert_eq!("{if album}{album".parse::<Template>(), Err(Error::Unclosed));
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        assert_eq!(
+            "{bitrate}".parse::<Template>(),
+            Err(Error::UnknownField("bitrate".to_owned()))
+        );
+        assert_eq!(
+            "{if mood}x{end}".parse::<Template>(),
+            Err(Error::UnknownField("mood".to_owned()))
+        );
+    }
+
+    #[test]
+    fn rejects_stray_end_and_else() {
+        assert_eq!("{end}".parse::<Template>(), Err(Error::Unmatched("end")));
+        assert_eq!(
+            "{if album}a{end}{end}".parse::<Template>(),
+            Err(Error::Unmatched("end"))
+        );
+        assert_eq!("{else}".parse::<Template>(), Err(Error::Unmatched("else")));
+        assert_eq!(
+            "{if album}a{else}b{else}c{end}".parse::<Template>(),
+            Err(Error::Unmatched("else"))
+        );
+    }
+
+    #[test]
+    fn rejects_unfinished_conditionals() {
+        assert_eq!(
+            "{if album}{album}".parse::<Template>(),
+            Err(Error::MissingEnd)
+        );
+        assert_eq!(
+            "{if album}{if disc}{disc}{end}".parse::<Template>(),
+            Err(Error::MissingEnd)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        for expr in [
+            "{}",
+            "{artist|}",
+            "{track:xx}",
+            r#"{"open}"#,
+            "{artist title}",
+        ] {
+            assert!(
+                matches!(expr.parse::<Template>(), Err(Error::InvalidExpression(_))),
+                "{expr}"
+            );
+        }
+    }
+}
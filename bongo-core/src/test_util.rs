@@ -0,0 +1,237 @@
+//!minimal audio files for tests, written by hand so no encoder is needed
+
+use std::path::{Path, PathBuf};
+
+use lofty::{Accessor, AudioFile, TaggedFileExt};
+
+///write an untagged song named `name` into `dir`, in the format of its extension
+pub(crate) fn song(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).unwrap();
+    }
+    let ext = path.extension().unwrap().to_string_lossy().to_lowercase();
+    let bytes = match ext.as_str() {
+        "mp3" => mp3(),
+        "flac" => flac(),
+        "m4a" => m4a(),
+        "ape" => ape(),
+        "opus" => opus(),
+        "wav" => wav(),
+        ext => panic!("no fixture for '{ext}'"),
+    };
+    std::fs::write(&path, bytes).unwrap();
+    path
+}
+
+///write a song with a title and artist
+pub(crate) fn tagged_song(dir: &Path, name: &str, title: &str, artist: &str) -> PathBuf {
+    let path = song(dir, name);
+    let mut file = lofty::read_from_path(&path).unwrap();
+    if file.primary_tag().is_none() {
+        file.insert_tag(lofty::Tag::new(file.primary_tag_type()));
+    }
+    let tag = file.primary_tag_mut().unwrap();
+    tag.set_title(title.to_owned());
+    tag.set_artist(artist.to_owned());
+    file.save_to_path(&path).unwrap();
+    path
+}
+
+///mpeg 1 layer 3 frames of silence, 128 kbit/s at 44.1 kHz
+fn mp3() -> Vec<u8> {
+    const FRAME_LEN: usize = 417;
+    let mut bytes = Vec::new();
+    for _ in 0..16 {
+        let mut frame = vec![0; FRAME_LEN];
+        frame[..4].copy_from_slice(&[0xFF, 0xFB, 0x90, 0x64]);
+        bytes.extend(frame);
+    }
+    bytes
+}
+
+///a flac stream with only its streaminfo block
+fn flac() -> Vec<u8> {
+    let mut bytes = b"fLaC".to_vec();
+    //last metadata block, streaminfo, 34 bytes long
+    bytes.extend([0x80, 0, 0, 34]);
+    //min and max block size
+    bytes.extend(4096u16.to_be_bytes());
+    bytes.extend(4096u16.to_be_bytes());
+    //min and max frame size, unknown
+    bytes.extend([0; 6]);
+    //44100 Hz in 20 bits, 2 channels and 16 bits per sample, 44100 samples in 36 bits
+    let info: u64 = (44_100 << 44) | (1 << 41) | (15 << 36) | 44_100;
+    bytes.extend(info.to_be_bytes());
+    //md5 of the decoded audio, unknown
+    bytes.extend([0; 16]);
+    bytes
+}
+
+///an mp4 box
+fn mp4_box(name: &[u8; 4], content: &[u8]) -> Vec<u8> {
+    let mut bytes = u32::try_from(content.len() + 8)
+        .unwrap()
+        .to_be_bytes()
+        .to_vec();
+    bytes.extend(name);
+    bytes.extend(content);
+    bytes
+}
+
+///an aac track without samples
+fn m4a() -> Vec<u8> {
+    let mut ftyp = b"M4A ".to_vec();
+    ftyp.extend(0u32.to_be_bytes());
+    ftyp.extend(b"M4A mp42isom");
+
+    let mut mvhd = vec![0; 100];
+    mvhd[12..16].copy_from_slice(&1000u32.to_be_bytes());
+    mvhd[16..20].copy_from_slice(&1000u32.to_be_bytes());
+
+    let mut mdhd = vec![0; 24];
+    mdhd[12..16].copy_from_slice(&44_100u32.to_be_bytes());
+    mdhd[16..20].copy_from_slice(&44_100u32.to_be_bytes());
+
+    let mut hdlr = vec![0; 8];
+    hdlr.extend(b"soun");
+    hdlr.extend([0; 13]);
+
+    let mut mp4a = vec![0; 6];
+    //data reference index
+    mp4a.extend(1u16.to_be_bytes());
+    mp4a.extend([0; 8]);
+    //channels, bits per sample
+    mp4a.extend(2u16.to_be_bytes());
+    mp4a.extend(16u16.to_be_bytes());
+    mp4a.extend([0; 4]);
+    //sample rate, 16.16 fixed point
+    mp4a.extend((44_100u32 << 16).to_be_bytes());
+    let mut stsd = vec![0; 4];
+    stsd.extend(1u32.to_be_bytes());
+    stsd.extend(mp4_box(b"mp4a", &mp4a));
+
+    let stbl = mp4_box(b"stbl", &mp4_box(b"stsd", &stsd));
+    let minf = mp4_box(b"minf", &stbl);
+    let mut mdia = mp4_box(b"mdhd", &mdhd);
+    mdia.extend(mp4_box(b"hdlr", &hdlr));
+    mdia.extend(minf);
+    let trak = mp4_box(b"trak", &mp4_box(b"mdia", &mdia));
+    let mut moov = mp4_box(b"mvhd", &mvhd);
+    moov.extend(trak);
+
+    let mut bytes = mp4_box(b"ftyp", &ftyp);
+    //before moov, so growing moov never moves the samples
+    bytes.extend(mp4_box(b"mdat", &[0; 64]));
+    bytes.extend(mp4_box(b"moov", &moov));
+    bytes
+}
+
+///a monkey's audio header without frames
+fn ape() -> Vec<u8> {
+    let mut bytes = b"MAC ".to_vec();
+    //version 3.99 and padding
+    bytes.extend(3990u16.to_le_bytes());
+    bytes.extend([0; 2]);
+    //descriptor and header length, then seek table, header data, frame data, frame data high
+    //and terminating data lengths
+    for len in [52u32, 24, 0, 0, 0, 0, 0] {
+        bytes.extend(len.to_le_bytes());
+    }
+    bytes.extend([0; 16]);
+    //compression level, flags
+    bytes.extend(2000u16.to_le_bytes());
+    bytes.extend(0u16.to_le_bytes());
+    //blocks per frame, blocks in the final frame, total frames
+    bytes.extend(73_728u32.to_le_bytes());
+    bytes.extend(0u32.to_le_bytes());
+    bytes.extend(0u32.to_le_bytes());
+    //bits per sample, channels, sample rate
+    bytes.extend(16u16.to_le_bytes());
+    bytes.extend(2u16.to_le_bytes());
+    bytes.extend(44_100u32.to_le_bytes());
+    bytes
+}
+
+///crc32 as used by ogg, polynomial 0x04c11db7 without reflection
+fn ogg_crc(data: &[u8]) -> u32 {
+    let mut crc = 0u32;
+    for &byte in data {
+        crc ^= u32::from(byte) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 == 0 {
+                crc << 1
+            } else {
+                (crc << 1) ^ 0x04c1_1db7
+            };
+        }
+    }
+    crc
+}
+
+///an ogg page holding a single packet shorter than 255 bytes
+fn ogg_page(header_type: u8, granule: u64, sequence: u32, packet: &[u8]) -> Vec<u8> {
+    let mut page = b"OggS".to_vec();
+    page.push(0);
+    page.push(header_type);
+    page.extend(granule.to_le_bytes());
+    //stream serial number
+    page.extend(1u32.to_le_bytes());
+    page.extend(sequence.to_le_bytes());
+    page.extend([0; 4]);
+    page.push(1);
+    page.push(u8::try_from(packet.len()).unwrap());
+    page.extend(packet);
+    let crc = ogg_crc(&page);
+    page[22..26].copy_from_slice(&crc.to_le_bytes());
+    page
+}
+
+///an ogg opus stream with one second of audio in a single packet
+fn opus() -> Vec<u8> {
+    let mut head = b"OpusHead".to_vec();
+    head.push(1);
+    head.push(2);
+    //pre-skip, input sample rate, output gain, mapping family
+    head.extend(312u16.to_le_bytes());
+    head.extend(48_000u32.to_le_bytes());
+    head.extend(0i16.to_le_bytes());
+    head.push(0);
+
+    let mut tags = b"OpusTags".to_vec();
+    let vendor = b"bongo";
+    tags.extend(u32::try_from(vendor.len()).unwrap().to_le_bytes());
+    tags.extend(vendor);
+    tags.extend(0u32.to_le_bytes());
+
+    let mut bytes = ogg_page(0x02, 0, 0, &head);
+    bytes.extend(ogg_page(0, 0, 1, &tags));
+    //a silent 20ms celt frame
+    bytes.extend(ogg_page(0x04, 48_000 + 312, 2, &[0xF8, 0xFF, 0xFE]));
+    bytes
+}
+
+///a riff wave file with a few samples of 16 bit mono pcm
+fn wav() -> Vec<u8> {
+    let mut fmt = 1u16.to_le_bytes().to_vec();
+    fmt.extend(1u16.to_le_bytes());
+    fmt.extend(8000u32.to_le_bytes());
+    //byte rate, block align, bits per sample
+    fmt.extend(16_000u32.to_le_bytes());
+    fmt.extend(2u16.to_le_bytes());
+    fmt.extend(16u16.to_le_bytes());
+    let data = [0; 1600];
+
+    let mut riff = b"WAVE".to_vec();
+    riff.extend(b"fmt ");
+    riff.extend(u32::try_from(fmt.len()).unwrap().to_le_bytes());
+    riff.extend(fmt);
+    riff.extend(b"data");
+    riff.extend(u32::try_from(data.len()).unwrap().to_le_bytes());
+    riff.extend(data);
+
+    let mut bytes = b"RIFF".to_vec();
+    bytes.extend(u32::try_from(riff.len()).unwrap().to_le_bytes());
+    bytes.extend(riff);
+    bytes
+}
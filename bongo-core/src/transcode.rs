@@ -0,0 +1,380 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+};
+
+use lofty::{AudioFile, TaggedFileExt};
+
+use crate::{
+    db::SONGTABLE,
+    hash,
+    song::{uuid_key, MusicDir},
+    transfer,
+};
+
+///transcoded files are kept here, below the music dir, see [`MusicDir::cached_transcode`]
+pub const CACHEDIRNAME: &str = ".bongo-transcode";
+
+///an encoder and its settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum Preset {
+    ///opus at 96 kbit/s
+    #[serde(rename = "opus-96")]
+    Opus96,
+    ///opus at 128 kbit/s
+    #[serde(rename = "opus-128")]
+    Opus128,
+    ///mp3, lame's best variable bitrate
+    #[serde(rename = "mp3-v0")]
+    Mp3V0,
+    ///mp3 at 320 kbit/s
+    #[serde(rename = "mp3-320")]
+    Mp3320,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("expected '<extension>=<preset>', found '{0}'")]
+    InvalidRule(String),
+    #[error("unknown preset '{0}'. Expected one of opus-96, opus-128, mp3-v0, mp3-320")]
+    UnknownPreset(String),
+}
+
+impl Preset {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Opus96 => "opus-96",
+            Self::Opus128 => "opus-128",
+            Self::Mp3V0 => "mp3-v0",
+            Self::Mp3320 => "mp3-320",
+        }
+    }
+    ///extension of the transcoded files
+    #[must_use]
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Opus96 | Self::Opus128 => "opus",
+            Self::Mp3V0 | Self::Mp3320 => "mp3",
+        }
+    }
+    fn codec_args(self) -> &'static [&'static str] {
+        match self {
+            Self::Opus96 => &["-c:a", "libopus", "-b:a", "96k"],
+            Self::Opus128 => &["-c:a", "libopus", "-b:a", "128k"],
+            Self::Mp3V0 => &["-c:a", "libmp3lame", "-q:a", "0"],
+            Self::Mp3320 => &["-c:a", "libmp3lame", "-b:a", "320k"],
+        }
+    }
+}
+
+impl std::fmt::Display for Preset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Preset {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [Self::Opus96, Self::Opus128, Self::Mp3V0, Self::Mp3320]
+            .into_iter()
+            .find(|p| p.name() == s)
+            .ok_or_else(|| Error::UnknownPreset(s.to_owned()))
+    }
+}
+
+///which preset each source extension is transcoded with, e.g. `flac=opus-128`.
+///Files with other extensions are copied as they are
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(transparent)]
+pub struct Transcoding(pub BTreeMap<String, Preset>);
+
+impl Transcoding {
+    ///the preset for `path`, if its extension is transcoded
+    #[must_use]
+    pub fn preset(&self, path: &Path) -> Option<Preset> {
+        let ext = path.extension()?.to_string_lossy().to_lowercase();
+        self.0.get(&ext).copied()
+    }
+    ///add a rule in the form `<extension>=<preset>`
+    /// # Errors
+    ///   [`Error`] if the rule or its preset is invalid
+    pub fn add_rule(&mut self, rule: &str) -> Result<(), Error> {
+        let (ext, preset) = rule
+            .split_once('=')
+            .ok_or_else(|| Error::InvalidRule(rule.to_owned()))?;
+        let ext = ext.trim().trim_start_matches('.').to_lowercase();
+        self.0.insert(ext, preset.trim().parse()?);
+        Ok(())
+    }
+}
+
+///transcode `source` to `destination` with ffmpeg, copying its tags and cover art.
+///The result is kept at `cached` so unchanged songs are only encoded once.
+///Unless `replace`, an existing `destination` is an error
+pub(crate) fn transcode(
+    source: &Path,
+    destination: &Path,
+    preset: Preset,
+    cached: &Path,
+    replace: bool,
+) -> anyhow::Result<()> {
+    if cached.exists() {
+        tracing::debug!("using cached transcode of '{}'", source.to_string_lossy());
+    } else {
+        std::fs::create_dir_all(cached.parent().unwrap())?;
+        //ffmpeg picks the container from the extension
+        let partial = cached.with_extension(format!("partial.{}", preset.extension()));
+        tracing::info!(
+            "transcoding '{}' to {}",
+            source.to_string_lossy(),
+            preset.name()
+        );
+        let status = Command::new("ffmpeg")
+            .args(["-nostdin", "-loglevel", "error", "-y", "-i"])
+            .arg(source)
+            //tags and cover art are written by lofty, ffmpeg mangles pictures in ogg
+            .args(["-map", "0:a", "-map_metadata", "-1"])
+            .args(preset.codec_args())
+            .arg(&partial)
+            .status()
+            .map_err(|e| anyhow::anyhow!("unable to run ffmpeg, is it installed? {e}"))?;
+        if !status.success() {
+            //best effort, ffmpeg may not have created it
+            let _ = std::fs::remove_file(&partial);
+            anyhow::bail!(
+                "ffmpeg failed to transcode '{}'. {status}",
+                source.to_string_lossy()
+            );
+        }
+        copy_tags(source, &partial)?;
+        std::fs::rename(&partial, cached)?;
+    }
+    transfer::copy_file(cached, destination, replace)?;
+    Ok(())
+}
+
+///copy the primary tag of `source`, pictures included, into `target`
+fn copy_tags(source: &Path, target: &Path) -> anyhow::Result<()> {
+    let source = lofty::read_from_path(source)?;
+    let Some(tag) = source.primary_tag().or_else(|| source.first_tag()) else {
+        return Ok(());
+    };
+    let mut target_file = lofty::read_from_path(target)?;
+    let target_type = target_file.primary_tag_type();
+    let mut tag = tag.clone();
+    //each tag type keeps the uuid under its own key, remapping would carry over the old one
+    let source_key = uuid_key(tag.tag_type());
+    let uuid = tag.get_string(&source_key).map(str::to_owned);
+    tag.remove_key(&source_key);
+    tag.re_map(target_type);
+    if let Some(uuid) = uuid {
+        tag.insert_text(uuid_key(target_type), uuid);
+    }
+    target_file.insert_tag(tag);
+    target_file.save_to_path(target)?;
+    Ok(())
+}
+
+///where transcoded files of the music dir at `root` are cached
+#[must_use]
+pub fn cache_dir(root: &Path) -> PathBuf {
+    root.join(CACHEDIRNAME)
+}
+
+impl MusicDir {
+    ///where the transcode of `source` with `preset` is cached, whether or not it exists.
+    ///Named after the audio hash, size and modification time of `source`, so retagging it
+    ///transcodes it again. The audio hash is taken from the db while its entry is current
+    pub(crate) fn cached_transcode(
+        &self,
+        source: &Path,
+        preset: Preset,
+    ) -> anyhow::Result<PathBuf> {
+        Ok(cache_dir(&self.root).join(format!(
+            "{}.{}.{}",
+            self.transcode_key(source)?,
+            preset.name(),
+            preset.extension()
+        )))
+    }
+    fn transcode_key(&self, source: &Path) -> anyhow::Result<String> {
+        let (size, modified) = hash::stamp(source)?;
+        let audio_hash = match self.stored_audio_hash(source)? {
+            Some(audio_hash) => audio_hash,
+            None => hash::audio_hash(source)?,
+        };
+        Ok(format!("{audio_hash}-{size}-{modified}"))
+    }
+    ///the audio hash of the song at `path` stored in the db, unless the song changed since
+    fn stored_audio_hash(&self, path: &Path) -> anyhow::Result<Option<String>> {
+        let Some(uuid) = self
+            .songs
+            .iter()
+            .find(|s| s.path == path)
+            .and_then(|s| s.uuid.as_ref())
+        else {
+            return Ok(None);
+        };
+        let reader = self.db.0.begin_read()?;
+        //the table doesn't exist until the first update
+        let Ok(table) = reader.open_table(SONGTABLE) else {
+            return Ok(None);
+        };
+        let Some(entry) = table.get(uuid)? else {
+            return Ok(None);
+        };
+        let entry = entry.value();
+        Ok(if entry.is_current(path)? {
+            entry.audio_hash
+        } else {
+            None
+        })
+    }
+    ///remove cached transcodes whose source has changed or left the library.
+    ///Songs that changed since the last update are hashed, so this is skipped while the cache is empty
+    pub(crate) fn prune_transcodes(&self) {
+        let cache_dir = cache_dir(&self.root);
+        let Ok(entries) = std::fs::read_dir(&cache_dir) else {
+            return;
+        };
+        let entries = entries.filter_map(Result::ok).collect::<Vec<_>>();
+        if entries.is_empty() {
+            return;
+        }
+        let mut keys = HashSet::new();
+        for song in &self.songs {
+            match self.transcode_key(&song.path) {
+                Ok(key) => {
+                    keys.insert(key);
+                }
+                Err(e) => tracing::warn!("unable to hash '{}'. {e}", song.path.to_string_lossy()),
+            }
+        }
+        for entry in entries {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            //named `<key of the source>.<preset>.<extension>`
+            let key = name.split('.').next().unwrap_or_default();
+            if keys.contains(key) {
+                continue;
+            }
+            tracing::debug!("removing stale transcode '{name}'");
+            if let Err(e) = std::fs::remove_file(entry.path()) {
+                tracing::warn!("unable to remove '{}'. {e}", entry.path().to_string_lossy());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::{Path, PathBuf},
+        time::{Duration, SystemTime},
+    };
+
+    use lofty::{Accessor, AudioFile, TaggedFileExt};
+
+    use super::{cache_dir, copy_tags, transcode, Preset};
+    use crate::{
+        scan::Scan,
+        song::{MusicDir, Song},
+        test_util,
+    };
+
+    const PRESET: Preset = Preset::Opus128;
+
+    fn init(root: &Path) -> MusicDir {
+        MusicDir::init(root.to_path_buf(), false, Scan::default()).unwrap()
+    }
+
+    ///give the song at `path` a new title and a later modification time
+    fn retitle(path: &Path, title: &str) {
+        let mut file = lofty::read_from_path(path).unwrap();
+        file.primary_tag_mut().unwrap().set_title(title.to_owned());
+        file.save_to_path(path).unwrap();
+        //padding may keep the size, and this may all happen within a second
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+    }
+
+    ///pretend `source` was already transcoded, so ffmpeg isn't needed
+    fn cache(music_dir: &MusicDir, source: &Path) -> PathBuf {
+        let cached = music_dir.cached_transcode(source, PRESET).unwrap();
+        std::fs::create_dir_all(cached.parent().unwrap()).unwrap();
+        std::fs::write(&cached, "encoded").unwrap();
+        cached
+    }
+
+    #[test]
+    fn cached_transcodes_are_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = test_util::tagged_song(dir.path(), "a.flac", "a", "Artist");
+        let music_dir = init(dir.path());
+        let cached = cache(&music_dir, &a);
+        assert_eq!(music_dir.cached_transcode(&a, PRESET).unwrap(), cached);
+
+        let destination = dir.path().join("copy/a.opus");
+        std::fs::create_dir_all(destination.parent().unwrap()).unwrap();
+        transcode(&a, &destination, PRESET, &cached, false).unwrap();
+        assert_eq!(std::fs::read(&destination).unwrap(), b"encoded");
+    }
+
+    #[test]
+    fn retagging_invalidates_the_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = test_util::tagged_song(dir.path(), "a.flac", "a", "Artist");
+        let music_dir = init(dir.path());
+        let before = music_dir.cached_transcode(&a, PRESET).unwrap();
+        retitle(&a, "a longer title");
+
+        let after = music_dir.cached_transcode(&a, PRESET).unwrap();
+        assert_ne!(before, after);
+        //the db entry is out of date, so the audio is hashed again, with the same result
+        let audio_hash = |path: &Path| {
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            name.split('-').next().unwrap().to_owned()
+        };
+        assert_eq!(audio_hash(&before), audio_hash(&after));
+    }
+
+    #[test]
+    fn pruning_keeps_the_transcodes_of_current_songs() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = test_util::tagged_song(dir.path(), "a.flac", "a", "Artist");
+        let b = test_util::tagged_song(dir.path(), "b.flac", "b", "Artist");
+        let music_dir = init(dir.path());
+        let current = cache(&music_dir, &a);
+        let retagged = cache(&music_dir, &b);
+        let removed = cache_dir(dir.path()).join("0123-1-1.opus-128.opus");
+        std::fs::write(&removed, "encoded").unwrap();
+        retitle(&b, "a longer title");
+
+        music_dir.prune_transcodes();
+        assert!(current.exists());
+        assert!(!retagged.exists());
+        assert!(!removed.exists());
+    }
+
+    #[test]
+    fn copied_tags_keep_the_uuid() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = tempfile::tempdir().unwrap();
+        let a = test_util::tagged_song(dir.path(), "a.mp3", "a", "Artist");
+        let music_dir = init(dir.path());
+        let uuid = music_dir.songs[0].uuid.clone().unwrap();
+        let target = test_util::song(target.path(), "a.opus");
+
+        copy_tags(&a, &target).unwrap();
+        let copied = Song::parse(target).unwrap();
+        assert_eq!(copied.uuid.unwrap().0, uuid.0);
+        assert_eq!(copied.tagged.primary_tag().unwrap().title().unwrap(), "a");
+    }
+}
@@ -0,0 +1,340 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use crate::sort::Link;
+
+///`rename` fails with this when source and destination are on different file systems
+#[cfg(unix)]
+const CROSSES_DEVICES: i32 = 18;
+#[cfg(windows)]
+const CROSSES_DEVICES: i32 = 17;
+
+///move `source` to `destination`, renaming when both are on the same file system.
+///Across file systems the file is copied, verified and only then removed.
+///Fails with [`io::ErrorKind::AlreadyExists`] if `destination` exists
+pub(crate) fn move_file(source: &Path, destination: &Path) -> io::Result<()> {
+    move_with(source, destination, rename_new)
+}
+
+///[`move_file`] with the rename that is tried first, so tests can fail it like another file system would
+fn move_with(
+    source: &Path,
+    destination: &Path,
+    rename: impl Fn(&Path, &Path) -> io::Result<()>,
+) -> io::Result<()> {
+    match rename(source, destination) {
+        Ok(()) => {
+            sync_parent(destination)?;
+            sync_parent(source)
+        }
+        Err(e) if crosses_devices(&e) => {
+            tracing::debug!(
+                "'{}' is on another file system, copying instead",
+                destination.to_string_lossy()
+            );
+            copy_file(source, destination, false)?;
+            if !same_contents(source, destination)? {
+                std::fs::remove_file(destination)?;
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "copy of '{}' does not match the original",
+                        source.to_string_lossy()
+                    ),
+                ));
+            }
+            std::fs::remove_file(source)?;
+            sync_parent(source)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+///copy `source` to `destination` through a temporary file, so `destination` is never left half written.
+///Unless `replace`, fails with [`io::ErrorKind::AlreadyExists`] if `destination` exists
+pub(crate) fn copy_file(source: &Path, destination: &Path, replace: bool) -> io::Result<()> {
+    let temp = temp_path(destination);
+    let result = std::fs::copy(source, &temp)
+        .and_then(|_| File::open(&temp)?.sync_all())
+        .and_then(|()| publish(&temp, destination, replace));
+    if result.is_err() {
+        //best effort, the original error is more useful
+        let _ = std::fs::remove_file(&temp);
+    }
+    result?;
+    sync_parent(destination)
+}
+
+///link `destination` to `source`, copying if the file system can't link them.
+///Unless `replace`, fails with [`io::ErrorKind::AlreadyExists`] if `destination` exists
+pub(crate) fn link_file(
+    source: &Path,
+    destination: &Path,
+    link: Link,
+    replace: bool,
+) -> io::Result<()> {
+    let temp = temp_path(destination);
+    let linked = match link {
+        Link::Hard => std::fs::hard_link(source, &temp),
+        Link::Sym => source
+            .canonicalize()
+            .and_then(|source| symlink(&source, &temp)),
+        Link::Reflink => reflink_copy::reflink(source, &temp),
+    };
+    match linked {
+        Ok(()) => {
+            if let Err(e) = publish(&temp, destination, replace) {
+                //best effort, the original error is more useful
+                let _ = std::fs::remove_file(&temp);
+                return Err(e);
+            }
+            sync_parent(destination)
+        }
+        Err(e) => {
+            //best effort, the link most likely was never created
+            let _ = std::fs::remove_file(&temp);
+            //most file systems can't reflink, don't warn about every file
+            if link == Link::Reflink {
+                tracing::debug!(
+                    "unable to reflink '{}', copying. {e}",
+                    source.to_string_lossy()
+                );
+            } else {
+                tracing::warn!(
+                    "unable to link '{}', copying. {e}",
+                    source.to_string_lossy()
+                );
+            }
+            copy_file(source, destination, replace)
+        }
+    }
+}
+
+///rename the temporary file `temp` to `destination`, only replacing an existing file if `replace`
+fn publish(temp: &Path, destination: &Path, replace: bool) -> io::Result<()> {
+    if replace {
+        std::fs::rename(temp, destination)
+    } else {
+        rename_new(temp, destination)
+    }
+}
+
+///rename `from` to `to`, failing with [`io::ErrorKind::AlreadyExists`] instead of replacing `to`.
+///A hard link can't replace anything, so `to` is linked and `from` removed.
+///Where hard links aren't supported, e.g. on fat, `to` is claimed with an empty file that is then renamed over
+fn rename_new(from: &Path, to: &Path) -> io::Result<()> {
+    let already_exists = || {
+        io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("'{}' already exists", to.to_string_lossy()),
+        )
+    };
+    match std::fs::hard_link(from, to) {
+        Ok(()) => std::fs::remove_file(from),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Err(already_exists()),
+        Err(e) if crosses_devices(&e) => Err(e),
+        Err(_) => {
+            if let Err(e) = File::options().write(true).create_new(true).open(to) {
+                return Err(if e.kind() == io::ErrorKind::AlreadyExists {
+                    already_exists()
+                } else {
+                    e
+                });
+            }
+            std::fs::rename(from, to).inspect_err(|_| {
+                //best effort, the claim is empty
+                let _ = std::fs::remove_file(to);
+            })
+        }
+    }
+}
+
+#[cfg(unix)]
+fn symlink(original: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(original, link)
+}
+#[cfg(windows)]
+fn symlink(original: &Path, link: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(original, link)
+}
+#[cfg(not(any(unix, windows)))]
+fn symlink(_original: &Path, _link: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "symbolic links are not supported on this platform",
+    ))
+}
+
+///a hidden name next to `path`, ignored by bongo if left behind
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{name}.bongo-tmp"))
+}
+
+fn crosses_devices(error: &io::Error) -> bool {
+    #[cfg(any(unix, windows))]
+    {
+        error.raw_os_error() == Some(CROSSES_DEVICES)
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = error;
+        false
+    }
+}
+
+///compare sizes, then every byte
+pub(crate) fn same_contents(a: &Path, b: &Path) -> io::Result<bool> {
+    let (mut a, mut b) = (File::open(a)?, File::open(b)?);
+    if a.metadata()?.len() != b.metadata()?.len() {
+        return Ok(false);
+    }
+    let mut buf_a = vec![0; 64 * 1024];
+    let mut buf_b = vec![0; 64 * 1024];
+    loop {
+        let read = a.read(&mut buf_a)?;
+        if read == 0 {
+            return Ok(true);
+        }
+        b.read_exact(&mut buf_b[..read])?;
+        if buf_a[..read] != buf_b[..read] {
+            return Ok(false);
+        }
+    }
+}
+
+///flush the directory entry of `path` to disk.
+///Only possible on unix, elsewhere renames are left to the file system
+fn sync_parent(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        File::open(parent)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, path::Path};
+
+    use super::{copy_file, link_file, move_file, move_with, CROSSES_DEVICES};
+    use crate::sort::Link;
+
+    ///fails like `rename` across file systems
+    fn cross_device(_from: &Path, _to: &Path) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(CROSSES_DEVICES))
+    }
+
+    fn write(path: &Path, contents: &str) {
+        std::fs::write(path, contents).unwrap();
+    }
+    fn read(path: &Path) -> String {
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn move_refuses_to_replace() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, destination) = (dir.path().join("a"), dir.path().join("b"));
+        write(&source, "source");
+        write(&destination, "destination");
+        let error = move_file(&source, &destination).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(read(&source), "source");
+        assert_eq!(read(&destination), "destination");
+    }
+
+    #[test]
+    fn move_renames() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, destination) = (dir.path().join("a"), dir.path().join("b"));
+        write(&source, "source");
+        move_file(&source, &destination).unwrap();
+        assert!(!source.exists());
+        assert_eq!(read(&destination), "source");
+    }
+
+    #[test]
+    fn move_across_file_systems_copies_and_removes() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, destination) = (dir.path().join("a"), dir.path().join("b"));
+        write(&source, "source");
+        move_with(&source, &destination, cross_device).unwrap();
+        assert!(!source.exists());
+        assert_eq!(read(&destination), "source");
+        let names = std::fs::read_dir(dir.path()).unwrap().count();
+        assert_eq!(names, 1, "the temporary copy was left behind");
+    }
+
+    #[test]
+    fn move_across_file_systems_refuses_to_replace() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, destination) = (dir.path().join("a"), dir.path().join("b"));
+        write(&source, "source");
+        write(&destination, "destination");
+        let error = move_with(&source, &destination, cross_device).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(read(&source), "source");
+        assert_eq!(read(&destination), "destination");
+    }
+
+    #[test]
+    fn move_returns_other_rename_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, destination) = (dir.path().join("a"), dir.path().join("b"));
+        write(&source, "source");
+        let denied = |_: &Path, _: &Path| Err(io::ErrorKind::PermissionDenied.into());
+        let error = move_with(&source, &destination, denied).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(read(&source), "source");
+        assert!(!destination.exists());
+    }
+
+    #[test]
+    fn copy_only_replaces_when_asked() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, destination) = (dir.path().join("a"), dir.path().join("b"));
+        write(&source, "source");
+        write(&destination, "destination");
+        let error = copy_file(&source, &destination, false).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(read(&destination), "destination");
+        copy_file(&source, &destination, true).unwrap();
+        assert_eq!(read(&destination), "source");
+        assert_eq!(read(&source), "source");
+    }
+
+    #[test]
+    fn link_refuses_to_replace() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, destination) = (dir.path().join("a"), dir.path().join("b"));
+        write(&source, "source");
+        write(&destination, "destination");
+        for link in [Link::Hard, Link::Sym, Link::Reflink] {
+            let error = link_file(&source, &destination, link, false).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+            assert_eq!(read(&destination), "destination");
+        }
+    }
+
+    #[test]
+    fn no_temporary_files_are_left() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, destination) = (dir.path().join("a"), dir.path().join("b"));
+        write(&source, "source");
+        write(&destination, "destination");
+        let _ = copy_file(&source, &destination, false);
+        let _ = link_file(&source, &destination, Link::Hard, false);
+        let mut names = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["a", "b"]);
+    }
+}
@@ -0,0 +1,341 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use redb::ReadableTable;
+use relative_path::RelativePath;
+
+use crate::{
+    db::{Database, SongUuid, SONGTABLE},
+    hash,
+    scan::Scan,
+    song::{MusicDir, Song},
+};
+
+///something [`MusicDir::verify`] found wrong with the library
+#[derive(Debug, serde::Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Problem {
+    ///a song in the db that is no longer on disk
+    Missing { uuid: SongUuid, path: RelativePath },
+    ///a song whose audio no longer matches its stored hash.
+    ///Unless `modified`, the file changed without its modification time changing, most likely bit-rot
+    Corrupt { path: PathBuf, modified: bool },
+    ///files sharing a uuid, e.g. a song that was copied
+    DuplicateUuid { uuid: SongUuid, paths: Vec<PathBuf> },
+    ///a file whose tags lofty can't parse, or that can't be read to hash it
+    Unreadable { path: PathBuf, error: String },
+    ///a file whose audio fails to decode
+    Undecodable { path: PathBuf, error: String },
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Missing { uuid, path } => write!(f, "missing     {path} ({uuid})"),
+            Self::Corrupt {
+                path,
+                modified: false,
+            } => write!(
+                f,
+                "corrupt     {} (audio changed, modification time did not)",
+                path.to_string_lossy()
+            ),
+            Self::Corrupt {
+                path,
+                modified: true,
+            } => write!(f, "changed     {} (audio changed)", path.to_string_lossy()),
+            Self::DuplicateUuid { uuid, paths } => {
+                write!(f, "duplicate   {uuid}")?;
+                for path in paths {
+                    write!(f, "\n              {}", path.to_string_lossy())?;
+                }
+                Ok(())
+            }
+            Self::Unreadable { path, error } => {
+                write!(f, "unreadable  {} ({error})", path.to_string_lossy())
+            }
+            Self::Undecodable { path, error } => {
+                write!(f, "undecodable {} ({error})", path.to_string_lossy())
+            }
+        }
+    }
+}
+
+///the result of [`MusicDir::verify`]
+#[derive(Debug, serde::Serialize)]
+pub struct Report {
+    ///music files found on disk
+    pub checked: usize,
+    ///songs without a stored hash, they are hashed by the next `bongo update`
+    pub unhashed: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    #[must_use]
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for problem in &self.problems {
+            writeln!(f, "{problem}")?;
+        }
+        write!(
+            f,
+            "{} files checked, {} problems, {} not hashed yet",
+            self.checked,
+            self.problems.len(),
+            self.unhashed
+        )
+    }
+}
+
+impl MusicDir {
+    ///cross-check the db of the music dir containing `dir` against the files on disk.
+    ///Unlike [`MusicDir::open`] this doesn't fail on files whose tags can't be parsed, they are reported instead.
+    ///With `decode`, every file is decoded in full, which is slow
+    pub fn verify(dir: &Path, scan: &Scan, decode: bool) -> anyhow::Result<Report> {
+        if decode && !cfg!(feature = "decode") {
+            anyhow::bail!("bongo was built without the decode feature");
+        }
+        let db = Database::open(dir)?;
+        let root = db.1.parent().expect("db is both a file and a directory?");
+        let mut problems = Vec::new();
+        let mut songs = Vec::new();
+        let paths = Self::find_music_files(root, scan)?;
+        let checked = paths.len();
+        for path in paths {
+            if decode {
+                if let Err(e) = decode_all(&path) {
+                    problems.push(Problem::Undecodable {
+                        path: path.clone(),
+                        error: e.to_string(),
+                    });
+                }
+            }
+            match Song::parse(path.clone()) {
+                Ok(song) => songs.push(song),
+                Err(e) => problems.push(Problem::Unreadable {
+                    path,
+                    error: e.to_string(),
+                }),
+            }
+        }
+
+        let mut by_uuid = BTreeMap::<_, Vec<&Song>>::new();
+        for song in &songs {
+            if let Some(uuid) = &song.uuid {
+                by_uuid.entry(uuid.0).or_default().push(song);
+            }
+        }
+        for (uuid, songs) in &by_uuid {
+            if songs.len() > 1 {
+                problems.push(Problem::DuplicateUuid {
+                    uuid: SongUuid(*uuid),
+                    paths: songs.iter().map(|s| s.path.clone()).collect(),
+                });
+            }
+        }
+
+        let mut unhashed = 0;
+        let reader = db.0.begin_read()?;
+        //the table doesn't exist until the first update
+        if let Ok(table) = reader.open_table(SONGTABLE) {
+            let unreadable = problems
+                .iter()
+                .filter_map(|p| match p {
+                    Problem::Unreadable { path, .. } => Some(path.clone()),
+                    _ => None,
+                })
+                .collect::<HashSet<_>>();
+            for entry in table.iter()? {
+                let (uuid, entry) = entry?;
+                let (uuid, entry) = (uuid.value(), entry.value());
+                let Some(songs) = by_uuid.get(&uuid.0) else {
+                    //a song that can't be parsed is reported as unreadable, not missing
+                    let old_path = entry.old_path.rebase(root.to_path_buf());
+                    if !unreadable.contains(&old_path) {
+                        problems.push(Problem::Missing {
+                            uuid,
+                            path: entry.old_path,
+                        });
+                    }
+                    continue;
+                };
+                let Some(stored) = &entry.audio_hash else {
+                    unhashed += 1;
+                    continue;
+                };
+                for song in songs {
+                    tracing::debug!("hashing '{}'", song.path.to_string_lossy());
+                    //whether the song changed, and if so whether its modification time did too
+                    let changed = hash::audio_hash(&song.path).and_then(|hash| {
+                        if hash == *stored {
+                            return Ok(None);
+                        }
+                        Ok(Some(
+                            hash::stamp(&song.path)? != (entry.size, entry.modified),
+                        ))
+                    });
+                    //one unreadable file shouldn't stop the rest from being checked
+                    match changed {
+                        Ok(None) => {}
+                        Ok(Some(modified)) => problems.push(Problem::Corrupt {
+                            path: song.path.clone(),
+                            modified,
+                        }),
+                        Err(e) => problems.push(Problem::Unreadable {
+                            path: song.path.clone(),
+                            error: e.to_string(),
+                        }),
+                    }
+                }
+            }
+        }
+        Ok(Report {
+            checked,
+            unhashed,
+            problems,
+        })
+    }
+}
+
+///decode every packet of the default track of `path`
+#[cfg(feature = "decode")]
+fn decode_all(path: &Path) -> anyhow::Result<()> {
+    use symphonia::core::{
+        codecs::DecoderOptions, errors::Error, formats::FormatOptions, io::MediaSourceStream,
+        meta::MetadataOptions, probe::Hint,
+    };
+
+    let file = std::fs::File::open(path)?;
+    let stream = MediaSourceStream::new(Box::new(file), std::default::Default::default());
+    let mut hint = Hint::new();
+    if let Some(ext) = path.extension().and_then(std::ffi::OsStr::to_str) {
+        hint.with_extension(ext);
+    }
+    let mut format = symphonia::default::get_probe()
+        .format(
+            &hint,
+            stream,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )?
+        .format;
+    let track = format
+        .default_track()
+        .ok_or_else(|| anyhow::anyhow!("file contains no audio track"))?;
+    let track_id = track.id;
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Ok(())
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if packet.track_id() == track_id {
+            decoder.decode(&packet)?;
+        }
+    }
+}
+///never called, [`MusicDir::verify`] refuses to decode without the decode feature
+#[cfg(not(feature = "decode"))]
+fn decode_all(_path: &Path) -> anyhow::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::Path,
+        time::{Duration, SystemTime},
+    };
+
+    use super::{Problem, Report};
+    use crate::{scan::Scan, song::MusicDir, test_util};
+
+    ///a music dir with `a.mp3` and `b.mp3`, hashed into its db. Their last byte is audio
+    fn library(root: &Path) {
+        test_util::tagged_song(root, "a.mp3", "a", "Artist");
+        test_util::tagged_song(root, "b.mp3", "b", "Artist");
+        MusicDir::init(root.to_path_buf(), false, Scan::default()).unwrap();
+    }
+
+    fn verify(root: &Path) -> Report {
+        MusicDir::verify(root, &Scan::default(), false).unwrap()
+    }
+
+    ///change the last byte of the audio in `path`, then set its modification time to `modified`
+    fn damage(path: &Path, modified: SystemTime) {
+        let mut bytes = std::fs::read(path).unwrap();
+        *bytes.last_mut().unwrap() ^= 0xFF;
+        std::fs::write(path, bytes).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    #[test]
+    fn untouched_libraries_are_clean() {
+        let dir = tempfile::tempdir().unwrap();
+        library(dir.path());
+        let report = verify(dir.path());
+        assert!(report.is_clean(), "{report}");
+        assert_eq!((report.checked, report.unhashed), (2, 0));
+    }
+
+    #[test]
+    fn removed_songs_are_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        library(dir.path());
+        std::fs::remove_file(dir.path().join("b.mp3")).unwrap();
+        let report = verify(dir.path());
+        assert!(
+            matches!(&report.problems[..], [Problem::Missing { path, .. }] if path.to_string() == "b.mp3"),
+            "{report}"
+        );
+    }
+
+    #[test]
+    fn changed_audio_is_reported_with_whether_its_time_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        library(dir.path());
+        let a = dir.path().join("a.mp3");
+        let b = dir.path().join("b.mp3");
+        damage(&a, SystemTime::now() + Duration::from_secs(60));
+        damage(&b, b.metadata().unwrap().modified().unwrap());
+
+        let report = verify(dir.path());
+        assert_eq!(report.problems.len(), 2, "{report}");
+        for problem in &report.problems {
+            match problem {
+                Problem::Corrupt { path, modified } => assert_eq!(*modified, *path == a),
+                problem => panic!("unexpected {problem}"),
+            }
+        }
+    }
+
+    #[test]
+    fn unparsable_songs_are_unreadable_not_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        library(dir.path());
+        let a = dir.path().join("a.mp3");
+        std::fs::write(&a, b"not an mp3").unwrap();
+        let report = verify(dir.path());
+        assert!(
+            matches!(&report.problems[..], [Problem::Unreadable { path, .. }] if *path == a),
+            "{report}"
+        );
+    }
+}